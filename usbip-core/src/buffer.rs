@@ -0,0 +1,103 @@
+use std::fmt;
+
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+/// A fixed-size field of `N` elements, such as a C string.
+///
+/// It is sent as its `N` elements with no length prefix,
+/// the way C lays out an array inside a struct.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Buffer<const N: usize, T>([T; N]);
+
+impl<const N: usize, T> AsRef<[T]> for Buffer<N, T> {
+    fn as_ref(&self) -> &[T] {
+        &self.0
+    }
+}
+
+impl<const N: usize, T: fmt::Debug> fmt::Debug for Buffer<N, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(&self.0).finish()
+    }
+}
+
+/// The error returned when bytes do not fit in a
+/// [`Buffer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TryFromError {
+    capacity: usize,
+    len: usize,
+}
+
+impl fmt::Display for TryFromError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes do not fit in a buffer of {}",
+            self.len, self.capacity
+        )
+    }
+}
+
+impl std::error::Error for TryFromError {}
+
+impl<const N: usize> TryFrom<&[u8]> for Buffer<N, i8> {
+    type Error = TryFromError;
+
+    /// Copies `bytes` to the front of a zeroed buffer.
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() > N {
+            return Err(TryFromError {
+                capacity: N,
+                len: bytes.len(),
+            });
+        }
+        let mut buf = [0; N];
+        for (dst, &src) in buf.iter_mut().zip(bytes) {
+            *dst = src as i8;
+        }
+        Ok(Self(buf))
+    }
+}
+
+impl<const N: usize, T: Serialize> Serialize for Buffer<N, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(N)?;
+        for elem in &self.0 {
+            tuple.serialize_element(elem)?;
+        }
+        tuple.end()
+    }
+}
+
+impl<'de, const N: usize, T: Deserialize<'de> + Copy + Default> Deserialize<'de> for Buffer<N, T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BufferVisitor<const N: usize, T>(std::marker::PhantomData<T>);
+
+        impl<'de, const N: usize, T: Deserialize<'de> + Copy + Default> Visitor<'de>
+            for BufferVisitor<N, T>
+        {
+            type Value = Buffer<N, T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "an array of {N} elements")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut buf = [T::default(); N];
+                for (i, elem) in buf.iter_mut().enumerate() {
+                    *elem = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+                }
+                Ok(Buffer(buf))
+            }
+        }
+
+        deserializer.deserialize_tuple(N, BufferVisitor(std::marker::PhantomData))
+    }
+}
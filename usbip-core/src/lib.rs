@@ -0,0 +1,150 @@
+pub mod buffer;
+pub mod net;
+pub mod vhci;
+
+use buffer::Buffer;
+use serde::{Deserialize, Serialize};
+
+/// Size of the bus id field, including its NUL.
+pub const SYSFS_BUS_ID_SIZE: usize = 32;
+/// Size of the sysfs path field, including its NUL.
+pub const SYSFS_PATH_MAX: usize = 256;
+
+/// Status of a device or port, as the kernel's
+/// `usbip_device_status` numbers it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceStatus {
+    /// An exported device nobody imported.
+    DevAvailable = 1,
+    /// An exported device imported by a client.
+    DevInUse,
+    /// An exported device that failed.
+    DevError,
+    /// A vhci port with no device attached.
+    PortAvailable,
+    /// A vhci port with a device being attached.
+    PortInitializing,
+    /// A vhci port with a device attached.
+    DeviceUsed,
+    /// A vhci port whose device failed.
+    DeviceError,
+}
+
+impl DeviceStatus {
+    /// Returns the status numbered `value`, or `None` if
+    /// the kernel has no status by that number.
+    pub const fn from_raw(value: u32) -> Option<Self> {
+        Some(match value {
+            1 => Self::DevAvailable,
+            2 => Self::DevInUse,
+            3 => Self::DevError,
+            4 => Self::PortAvailable,
+            5 => Self::PortInitializing,
+            6 => Self::DeviceUsed,
+            7 => Self::DeviceError,
+            _ => return None,
+        })
+    }
+}
+
+/// A USB device as the protocol describes it, laid out
+/// like `struct usbip_usb_device`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsbDevice {
+    path: Buffer<SYSFS_PATH_MAX, i8>,
+    bus_id: Buffer<SYSFS_BUS_ID_SIZE, i8>,
+    busnum: u32,
+    devnum: u32,
+    speed: u32,
+    id_vendor: u16,
+    id_product: u16,
+    bcd_device: u16,
+    b_device_class: u8,
+    b_device_subclass: u8,
+    b_device_protocol: u8,
+    b_configuration_value: u8,
+    b_num_configurations: u8,
+    b_num_interfaces: u8,
+}
+
+impl UsbDevice {
+    pub fn path(&self) -> &Buffer<SYSFS_PATH_MAX, i8> {
+        &self.path
+    }
+
+    pub fn bus_id(&self) -> &Buffer<SYSFS_BUS_ID_SIZE, i8> {
+        &self.bus_id
+    }
+
+    pub const fn busnum(&self) -> u32 {
+        self.busnum
+    }
+
+    pub const fn devnum(&self) -> u32 {
+        self.devnum
+    }
+
+    pub const fn speed(&self) -> u32 {
+        self.speed
+    }
+
+    pub const fn id_vendor(&self) -> u16 {
+        self.id_vendor
+    }
+
+    pub const fn id_product(&self) -> u16 {
+        self.id_product
+    }
+
+    pub const fn bcd_device(&self) -> u16 {
+        self.bcd_device
+    }
+
+    pub const fn b_device_class(&self) -> u8 {
+        self.b_device_class
+    }
+
+    pub const fn b_device_subclass(&self) -> u8 {
+        self.b_device_subclass
+    }
+
+    pub const fn b_device_protocol(&self) -> u8 {
+        self.b_device_protocol
+    }
+
+    pub const fn b_configuration_value(&self) -> u8 {
+        self.b_configuration_value
+    }
+
+    pub const fn b_num_configurations(&self) -> u8 {
+        self.b_num_configurations
+    }
+
+    pub const fn b_num_interfaces(&self) -> u8 {
+        self.b_num_interfaces
+    }
+}
+
+/// An interface of a USB device, laid out like
+/// `struct usbip_usb_interface`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct UsbInterface {
+    b_interface_class: u8,
+    b_interface_subclass: u8,
+    b_interface_protocol: u8,
+    padding: u8,
+}
+
+impl UsbInterface {
+    pub const fn b_interface_class(&self) -> u8 {
+        self.b_interface_class
+    }
+
+    pub const fn b_interface_subclass(&self) -> u8 {
+        self.b_interface_subclass
+    }
+
+    pub const fn b_interface_protocol(&self) -> u8 {
+        self.b_interface_protocol
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The status word of an operation header.
+///
+/// It is sent as a `u32`. Values the protocol does not
+/// define are received as `Unexpected`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Status {
+    /// `ST_OK`
+    Success,
+    /// `ST_NA`: the request failed.
+    Failed,
+    /// `ST_DEV_BUSY`: the device is imported elsewhere.
+    DevBusy,
+    /// `ST_DEV_ERR`: the device is in an error state.
+    DevErr,
+    /// `ST_NODEV`: no such device is exported.
+    NoDev,
+    /// `ST_ERROR`, or a status the protocol does not
+    /// define.
+    Unexpected,
+}
+
+impl Status {
+    /// Returns the status as it is sent.
+    pub const fn to_raw(self) -> u32 {
+        match self {
+            Status::Success => 0,
+            Status::Failed => 1,
+            Status::DevBusy => 2,
+            Status::DevErr => 3,
+            Status::NoDev => 4,
+            Status::Unexpected => 5,
+        }
+    }
+
+    /// Returns the status sent as `value`.
+    pub const fn from_raw(value: u32) -> Self {
+        match value {
+            0 => Status::Success,
+            1 => Status::Failed,
+            2 => Status::DevBusy,
+            3 => Status::DevErr,
+            4 => Status::NoDev,
+            _ => Status::Unexpected,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Success => "request completed successfully",
+            Status::Failed => "request failed",
+            Status::DevBusy => "device busy (exported)",
+            Status::DevErr => "device in error state",
+            Status::NoDev => "device not found",
+            Status::Unexpected => "unexpected response",
+        })
+    }
+}
+
+impl Serialize for Status {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_raw().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Status {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u32::deserialize(deserializer).map(Status::from_raw)
+    }
+}
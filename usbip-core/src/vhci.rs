@@ -0,0 +1,150 @@
+use std::{fmt, fs, io};
+
+use crate::DeviceStatus;
+
+/// sysfs directory of the primary vhci-hcd controller,
+/// which carries the status of every controller.
+const VHCI_PATH: &str = "/sys/devices/platform/vhci_hcd.0";
+
+/// Directory holding a state file per attached port.
+pub const STATE_PATH: &str = "/var/run/vhci_hcd";
+
+/// The error returned when the vhci driver cannot be read.
+#[derive(Debug)]
+pub enum Error {
+    /// The vhci-hcd module is not loaded.
+    DriverNotLoaded,
+    /// A driver attribute could not be read or written.
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DriverNotLoaded => write!(f, "the vhci_hcd driver is not loaded"),
+            Error::Io(err) => write!(f, "failed to access the vhci_hcd driver: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::DriverNotLoaded => None,
+            Error::Io(err) => Some(err),
+        }
+    }
+}
+
+/// Which root hub of a controller a port belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HubSpeed {
+    /// The USB 2.0 hub (`hs`).
+    High,
+    /// The USB 3.0 hub (`ss`).
+    Super,
+}
+
+/// One vhci port, as its status line describes it.
+#[derive(Debug, Clone)]
+pub struct ImportedDevice {
+    port: u8,
+    status: DeviceStatus,
+    hub: HubSpeed,
+}
+
+impl ImportedDevice {
+    /// Builds a port by hand, as the driver would list it.
+    pub const fn new(port: u8, status: DeviceStatus, hub: HubSpeed) -> Self {
+        Self { port, status, hub }
+    }
+
+    pub const fn port(&self) -> u8 {
+        self.port
+    }
+
+    pub const fn status(&self) -> DeviceStatus {
+        self.status
+    }
+
+    pub const fn hub(&self) -> HubSpeed {
+        self.hub
+    }
+}
+
+/// A snapshot of the ports of every vhci controller.
+#[derive(Debug)]
+pub struct Driver {
+    idevs: Vec<ImportedDevice>,
+    num_ports: usize,
+}
+
+impl Driver {
+    /// Reads the status of every port.
+    ///
+    /// # Error
+    /// This function returns `Error::DriverNotLoaded` if
+    /// the vhci-hcd controller is missing from sysfs.
+    pub fn try_open() -> Result<Self, Error> {
+        let mut idevs = Vec::new();
+        for controller in 0.. {
+            let name = match controller {
+                0 => "status".to_owned(),
+                n => format!("status.{n}"),
+            };
+            let status = match fs::read_to_string(format!("{VHCI_PATH}/{name}")) {
+                Ok(status) => status,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    if controller == 0 {
+                        return Err(Error::DriverNotLoaded);
+                    }
+                    break;
+                }
+                Err(err) => return Err(Error::Io(err)),
+            };
+            idevs.extend(status.lines().skip(1).filter_map(parse_status_line));
+        }
+
+        let num_ports = fs::read_to_string(format!("{VHCI_PATH}/nports"))
+            .map_err(Error::Io)?
+            .trim()
+            .parse()
+            .map_err(|err| Error::Io(io::Error::new(io::ErrorKind::InvalidData, err)))?;
+        Ok(Self { idevs, num_ports })
+    }
+
+    /// Returns the total number of ports across every
+    /// controller, attached or not, as read when this
+    /// handle was opened.
+    pub const fn num_ports(&self) -> usize {
+        self.num_ports
+    }
+
+    /// Returns the ports, attached or not.
+    pub fn imported_devices(&self) -> impl ExactSizeIterator<Item = ImportedDevice> + '_ {
+        self.idevs.iter().cloned()
+    }
+
+    /// Asks the driver to detach the device on `port`.
+    ///
+    /// # Error
+    /// This function returns `Error::Io` if the driver
+    /// refused the request, such as for a free port.
+    pub fn try_detach_dev(&self, port: u8) -> Result<(), Error> {
+        fs::write(format!("{VHCI_PATH}/detach"), port.to_string()).map_err(Error::Io)
+    }
+}
+
+/// Parses one line of a status attribute:
+/// `hub port sta spd dev sockfd local_busid`.
+fn parse_status_line(line: &str) -> Option<ImportedDevice> {
+    let mut fields = line.split_whitespace();
+    let hub = match fields.next()? {
+        "hs" => HubSpeed::High,
+        "ss" => HubSpeed::Super,
+        _ => return None,
+    };
+    let port = fields.next()?.parse().ok()?;
+    let status = DeviceStatus::from_raw(fields.next()?.parse().ok()?)?;
+    Some(ImportedDevice { port, status, hub })
+}
@@ -0,0 +1,3430 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    error::Error as StdError,
+    fmt,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use usbip_core::{net::Status, vhci, DeviceStatus, UsbDevice};
+
+use crate::{
+    buffer_to_string,
+    device::{self, DeviceMatch, SortKey, Speed, UsbDeviceExt},
+    from_cstr_like, net, protocol, state, sysfs, Port,
+};
+
+pub use crate::state::StateFormat;
+#[cfg(feature = "tokio")]
+pub use asynchronous::{
+    attach_device_async, devlist_stream_async, devlist_stream_async_cancellable,
+};
+pub use error::Error;
+
+mod error {
+    use std::fmt;
+
+    use usbip_core::net::Status;
+
+    use crate::device::{DeviceMatch, Speed};
+
+    #[derive(Debug, Clone)]
+    pub enum Error {
+        SharedNotSupported,
+        ImportFailed(Status),
+        DeviceBusy(Box<str>),
+        DevlistFailed(Status),
+        DevidNotExported(u32),
+        NoMatchingDevice(DeviceMatch),
+        TimedOut,
+        DeadlineExceeded,
+        ConnectionClosed(u8),
+        UnsupportedSpeed(Speed),
+        NoFreePort(Speed),
+        PortUnavailable(u8),
+        NotRecorded(u8),
+        NotEnumerated(u8),
+        AttachRejected(u8),
+        PortBusy(u8),
+        NoHub(u8),
+        Cancelled,
+        NoController(usize),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::SharedNotSupported => {
+                    write!(f, "USB/IP does not support shared access to a device")
+                }
+                Error::ImportFailed(status) => {
+                    write!(f, "server rejected the import request: {status:?}")
+                }
+                Error::DeviceBusy(bus_id) => write!(
+                    f,
+                    "device {bus_id} is attached by another client: \
+                     retry once it is detached"
+                ),
+                Error::DevlistFailed(status) => {
+                    write!(f, "server rejected the devlist request: {status:?}")
+                }
+                Error::DevidNotExported(devid) => {
+                    write!(f, "no exported device has devid {devid:#010x}")
+                }
+                Error::NoMatchingDevice(DeviceMatch {
+                    vendor,
+                    product,
+                    index,
+                }) => write!(
+                    f,
+                    "no exported device matches {vendor:04x}:{product:04x}:{index}"
+                ),
+                Error::TimedOut => write!(f, "timed out"),
+                Error::DeadlineExceeded => write!(f, "attach deadline exceeded"),
+                Error::ConnectionClosed(port) => {
+                    write!(
+                        f,
+                        "connection for port {port} closed while it was being set up"
+                    )
+                }
+                Error::UnsupportedSpeed(speed) => {
+                    write!(f, "vhci cannot attach a device running at {speed}")
+                }
+                Error::NoFreePort(speed) => write!(f, "no free vhci port for a {speed} device"),
+                Error::PortUnavailable(port) => {
+                    write!(f, "port {port} is not free for this device")
+                }
+                Error::NotRecorded(port) => {
+                    write!(f, "no connection is recorded for port {port}")
+                }
+                Error::NotEnumerated(port) => {
+                    write!(f, "no local device has been set up on port {port}")
+                }
+                Error::AttachRejected(port) => write!(
+                    f,
+                    "vhci rejected the device on port {port}: \
+                     its devid or speed is invalid for that port"
+                ),
+                Error::PortBusy(port) => write!(
+                    f,
+                    "vhci port {port} is already in use: \
+                     detach it first or pick another port"
+                ),
+                Error::NoHub(port) => write!(
+                    f,
+                    "no vhci hub has port {port}: \
+                     check that vhci-hcd is loaded with enough ports"
+                ),
+                Error::Cancelled => write!(f, "attach cancelled"),
+                Error::NoController(controller) => {
+                    write!(f, "no vhci controller vhci_hcd.{controller}")
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+/// How the remote device is claimed when it is imported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Access {
+    /// The server binds the device to this client alone.
+    /// This is the only mode USB/IP supports.
+    #[default]
+    Exclusive,
+    /// The device stays available to other clients.
+    /// USB/IP has no such mode: once `usbip-host` hands
+    /// a device to a client, it is claimed until that
+    /// client detaches. Requesting it fails with
+    /// `Error::SharedNotSupported`.
+    Shared,
+}
+
+impl Access {
+    /// Returns `true` if the protocol can honour this
+    /// access mode.
+    pub const fn is_supported(self) -> bool {
+        matches!(self, Access::Exclusive)
+    }
+}
+
+/// Options controlling how `attach_device_with`
+/// imports a remote device.
+#[derive(Debug, Clone, Default)]
+pub struct AttachOptions {
+    pub access: Access,
+    /// The protocol version sent to, and expected back
+    /// from, the server.
+    pub version: net::ProtocolVersion,
+    /// Return as soon as the vhci driver accepts the
+    /// device, instead of waiting for it to be in use.
+    pub no_wait: bool,
+    /// Attach to this vhci port, failing if it is taken
+    /// or on the wrong hub for the device, instead of
+    /// picking any free port.
+    pub port: Option<Port>,
+    /// Give up with `Error::DeadlineExceeded` once this
+    /// passes. Every blocking step, from connecting to
+    /// waiting for the port to be used, is bounded by the
+    /// time left.
+    pub deadline: Option<Instant>,
+    /// Connect without enabling TCP keepalive, see
+    /// [`net::ConnectOptions::no_keepalive`].
+    pub no_keepalive: bool,
+    /// How the connection is recorded in the port's state
+    /// file.
+    pub state_format: StateFormat,
+    /// Only attach to a port of this vhci controller, as
+    /// in `vhci_hcd.N`, instead of any controller's.
+    ///
+    /// Every controller has a high-speed and a SuperSpeed
+    /// hub, and devices always go to the hub matching
+    /// their speed, so this only picks between
+    /// controllers. The kernel drives all of them through
+    /// the primary controller's attributes, numbering
+    /// their ports one after the other, so a controller is
+    /// selected by its range of ports. Fails with
+    /// `Error::NoController` if the controller does not
+    /// exist, or `Error::PortUnavailable` if `port` is not
+    /// one of its ports.
+    pub controller: Option<usize>,
+}
+
+/// Returns the time left until `deadline`, or `None` if
+/// there is no deadline.
+///
+/// # Error
+/// This function returns `Error::DeadlineExceeded` if the
+/// deadline has passed.
+fn time_left(deadline: Option<Instant>) -> Result<Option<Duration>, Error> {
+    match deadline {
+        None => Ok(None),
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+            Some(left) if !left.is_zero() => Ok(Some(left)),
+            _ => Err(Error::DeadlineExceeded),
+        },
+    }
+}
+
+/// Reports `err` as `Error::DeadlineExceeded` if it
+/// happened because `deadline` passed.
+fn past_deadline(err: Box<dyn StdError>, deadline: Option<Instant>) -> Box<dyn StdError> {
+    match time_left(deadline) {
+        Err(exceeded) => exceeded.into(),
+        Ok(_) => err,
+    }
+}
+
+/// A socket whose every read and write times out after
+/// whatever is left until `deadline`.
+struct Bounded<'a> {
+    socket: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Bounded<'_> {
+    fn arm(&self, set: fn(&TcpStream, Option<Duration>) -> io::Result<()>) -> io::Result<()> {
+        let left = time_left(Some(self.deadline))
+            .map_err(|err| io::Error::new(io::ErrorKind::TimedOut, err))?;
+        set(self.socket, left)
+    }
+}
+
+impl Read for Bounded<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.arm(TcpStream::set_read_timeout)?;
+        self.socket.read(buf)
+    }
+}
+
+impl Write for Bounded<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.arm(TcpStream::set_write_timeout)?;
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl AsRawFd for Bounded<'_> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl net::Send for Bounded<'_> {}
+impl net::Recv for Bounded<'_> {}
+
+/// How long attaching waits for the kernel to finish
+/// setting up the device, unless told not to wait.
+const USED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the vhci port status is polled while waiting.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Returns `true` if `idev` is an available port on the
+/// vhci hub matching the device's speed.
+fn is_free_for(idev: &vhci::ImportedDevice, speed: Speed) -> bool {
+    matches!(idev.status(), DeviceStatus::PortAvailable)
+        && matches!(idev.hub(), vhci::HubSpeed::Super) == speed.is_super_speed()
+}
+
+/// Lists the ports of the vhci controller `controller`.
+///
+/// # Error
+/// This function returns `Error::NoController` if no
+/// controller has that number, or an error if sysfs could
+/// not be read.
+fn controller_ports(controller: usize) -> Result<Vec<u8>, Box<dyn StdError>> {
+    let ports: Vec<u8> = sysfs::port_statuses()?
+        .into_iter()
+        .filter(|status| status.controller == controller)
+        .map(|status| status.port)
+        .collect();
+    if ports.is_empty() {
+        return Err(Error::NoController(controller).into());
+    }
+    Ok(ports)
+}
+
+/// Picks the port to attach a device of `speed` to: the
+/// `requested` one if it is free, or else any free port,
+/// among the `allowed` ones if given.
+fn free_port(
+    driver: &vhci::Driver,
+    speed: Speed,
+    requested: Option<u8>,
+    allowed: Option<&[u8]>,
+) -> Result<u8, Error> {
+    let mut free = driver
+        .imported_devices()
+        .filter(|idev| is_free_for(idev, speed))
+        .map(|idev| idev.port())
+        .filter(|port| allowed.is_none_or(|allowed| allowed.contains(port)));
+
+    match requested {
+        Some(port) => free
+            .find(|&free| free == port)
+            .ok_or(Error::PortUnavailable(port)),
+        None => free.next().ok_or(Error::NoFreePort(speed)),
+    }
+}
+
+/// A device attached to a local vhci port, with
+/// everything learned while importing it.
+#[derive(Debug, Clone)]
+pub struct AttachedDevice {
+    /// The device as described by the server in its
+    /// import reply.
+    pub udev: UsbDevice,
+    /// The local vhci port the device was attached to,
+    /// either the requested one or picked from the free
+    /// ports on the hub matching the device's speed.
+    pub port: Port,
+    /// The protocol version from the header of the
+    /// server's import reply.
+    pub server_version: u16,
+}
+
+impl AttachedDevice {
+    /// Reads the strings the device reports about itself.
+    ///
+    /// See [`device_strings`].
+    pub fn strings(&self) -> Result<DeviceStrings, Box<dyn StdError>> {
+        device_strings(self.port)
+    }
+
+    /// Resolves the sysfs directory of the local device
+    /// the kernel created for this attachment, such as
+    /// `/sys/devices/platform/vhci_hcd.0/usb3/3-1`.
+    ///
+    /// Its `busnum` and `devnum` attributes name the
+    /// device node under `/dev/bus/usb`. The kernel may
+    /// still be enumerating a device that was just
+    /// attached, so this waits briefly for it to appear.
+    ///
+    /// # Errors
+    /// This function fails with `Error::NotEnumerated` if
+    /// the device did not appear in time, or if sysfs could
+    /// not be read.
+    pub fn local_path(&self) -> Result<PathBuf, Box<dyn StdError>> {
+        let bus_id = wait_for_local_bus_id(self.port.get())?;
+        Ok(sysfs::usb_device_path(&bus_id)?)
+    }
+}
+
+/// Waits for the kernel to enumerate the device attached
+/// to `port`, returning its local bus id, or
+/// `Error::NotEnumerated` if it did not appear in time.
+fn wait_for_local_bus_id(port: u8) -> Result<String, Box<dyn StdError>> {
+    let deadline = Instant::now() + USED_TIMEOUT;
+    loop {
+        if let Some(bus_id) = sysfs::local_bus_id(port)? {
+            return Ok(bus_id);
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::NotEnumerated(port).into());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Forces the interfaces of the device attached to `port`
+/// onto `driver`, or with `None` returns them to the
+/// driver the kernel would pick on its own.
+///
+/// This writes the `driver_override` of every interface
+/// and has the kernel bind it again. To hand a device to
+/// a userspace program using libusb, pass a name no driver
+/// has, such as `"none"`, to leave every interface
+/// unbound.
+///
+/// The kernel may still be enumerating a device that was
+/// just attached, so this waits briefly for it to appear.
+///
+/// # Errors
+/// This function fails with `Error::NotEnumerated` if the
+/// device did not appear in time, or if a sysfs write
+/// failed, for example on kernels without
+/// `driver_override` for USB interfaces.
+pub fn set_driver_override(port: Port, driver: Option<&str>) -> Result<(), Box<dyn StdError>> {
+    let bus_id = wait_for_local_bus_id(port.get())?;
+    for interface in sysfs::usb_interfaces(&bus_id)? {
+        sysfs::rebind_interface(&interface, driver.unwrap_or("\n"))?;
+    }
+    Ok(())
+}
+
+/// The human readable strings a device reports about
+/// itself, such as "SanDisk" and "Ultra".
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceStrings {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+}
+
+/// Reads the string descriptors of the device attached
+/// to `port` from the local sysfs.
+///
+/// The USB/IP control protocol only carries the numeric
+/// descriptor fields, so these strings cannot be fetched
+/// from the server before attaching. Once attached, the
+/// local kernel reads them over the data plane while
+/// enumerating the device, and publishes them in sysfs.
+/// Strings the device does not provide are `None`.
+///
+/// # Errors
+/// This function fails with `Error::NotEnumerated` if the
+/// kernel has not set up a device on `port` yet (see
+/// [`wait_until_used`]), or if sysfs could not be read.
+pub fn device_strings(port: Port) -> Result<DeviceStrings, Box<dyn StdError>> {
+    let port = port.get();
+    let bus_id = sysfs::local_bus_id(port)?.ok_or(Error::NotEnumerated(port))?;
+    Ok(DeviceStrings {
+        manufacturer: sysfs::usb_device_attr(&bus_id, "manufacturer")?,
+        product: sysfs::usb_device_attr(&bus_id, "product")?,
+        serial: sysfs::usb_device_attr(&bus_id, "serial")?,
+    })
+}
+
+/// Turns a failed write to the vhci `attach` attribute
+/// into the error matching the kernel's errno, keeping
+/// any other I/O error as is.
+fn attach_write_error(err: io::Error, port: u8) -> Box<dyn StdError> {
+    match err.raw_os_error() {
+        Some(libc::EINVAL) => Error::AttachRejected(port).into(),
+        Some(libc::EBUSY) => Error::PortBusy(port).into(),
+        Some(libc::ENODEV) => Error::NoHub(port).into(),
+        _ => err.into(),
+    }
+}
+
+/// Held while a port is picked and claimed.
+static PORT_ALLOCATION: Mutex<()> = Mutex::new(());
+
+/// Ports attached by this process and not detached since.
+static OWNED: Mutex<BTreeSet<u8>> = Mutex::new(BTreeSet::new());
+
+fn owned() -> MutexGuard<'static, BTreeSet<u8>> {
+    OWNED.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Returns the ports this process attached that have not
+/// been detached through this crate since, in order.
+pub fn owned_ports() -> Vec<Port> {
+    owned().iter().copied().map(Port::from_driver).collect()
+}
+
+/// Forgets that this process attached `port`.
+pub(crate) fn disown(port: u8) {
+    owned().remove(&port);
+}
+
+/// Hands the connected socket to the vhci driver on a
+/// free port for the device `devid` at `speed`, returning
+/// the port the device was attached to.
+fn import_device<S>(
+    socket: &S,
+    devid: u32,
+    speed: Speed,
+    options: &AttachOptions,
+) -> Result<Port, Box<dyn StdError>>
+where
+    S: AsRawFd,
+{
+    let token = speed.attach_token().ok_or(Error::UnsupportedSpeed(speed))?;
+    let allowed = options.controller.map(controller_ports).transpose()?;
+
+    // Picking a port and claiming it is not atomic, so
+    // threads attaching at once could pick the same one.
+    let _allocating = PORT_ALLOCATION
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    let driver = vhci::Driver::try_open()?;
+    let requested = options.port.map(Port::get);
+    let port = free_port(&driver, speed, requested, allowed.as_deref())?;
+
+    sysfs::attach(port, socket.as_raw_fd(), devid, token)
+        .map_err(|err| attach_write_error(err, port))?;
+    Ok(Port::from_driver(port))
+}
+
+/// Reads and checks the header of a reply to `code`,
+/// returning the protocol version the server sent, or
+/// the error `failed` builds from a failure status.
+///
+/// Servers that reject a request may send the header with
+/// a failure status and close the connection right away,
+/// without any body. The failure is therefore reported
+/// from the header alone, and the body must only be read
+/// once this returns `Ok`; reading it anyway would turn
+/// the rejection into a confusing EOF.
+///
+/// The same goes for servers that follow a failure header
+/// with a zeroed body or a message of their own: the
+/// protocol has no field for an error message, so there
+/// is no format to parse it by, and whatever follows the
+/// header is left unread for the connection to drop.
+fn recv_reply_header<S>(
+    socket: &mut S,
+    code: u16,
+    version: net::ProtocolVersion,
+    failed: impl FnOnce(Status) -> Error,
+) -> Result<u16, Box<dyn StdError>>
+where
+    S: net::Recv,
+{
+    let reply = socket.recv_header()?;
+    match reply.validate(code, version)? {
+        Status::Success => Ok(reply.version),
+        status => Err(failed(status).into()),
+    }
+}
+
+/// Runs the import handshake for `bus_id` and attaches
+/// the device.
+fn query_import_device<S>(
+    socket: &mut S,
+    bus_id: &str,
+    options: &AttachOptions,
+) -> Result<AttachedDevice, Box<dyn StdError>>
+where
+    S: net::Send + net::Recv + AsRawFd,
+{
+    let (udev, server_version) = request_import(socket, bus_id, options)?;
+    log::trace!("importing {}", udev.redacted());
+    let port = import_device(socket, udev.devid(), udev.speed_kind(), options)?;
+    Ok(AttachedDevice {
+        udev,
+        port,
+        server_version,
+    })
+}
+
+/// Runs the import handshake for `bus_id` over `socket`,
+/// returning the device the server replied with and the
+/// protocol version of the reply.
+///
+/// This is only the control plane, and works over any
+/// transport with [`net::Send`] and [`net::Recv`] impls,
+/// such as a QUIC or TLS stream. Attaching the device
+/// then needs a stream socket for the kernel to carry the
+/// device's traffic over, see [`attach_over`].
+///
+/// # Errors
+/// This function fails if the request could not be sent
+/// or the reply could not be read, if the server replied
+/// with another protocol version than `options.version`,
+/// rejected the import (`Error::DeviceBusy` if another
+/// client has the device) or replied with a different
+/// device.
+pub fn request_import<S>(
+    socket: &mut S,
+    bus_id: &str,
+    options: &AttachOptions,
+) -> Result<(UsbDevice, u16), Box<dyn StdError>>
+where
+    S: net::Send + net::Recv,
+{
+    let header = net::OpCommon::request(protocol::OP_REQ_IMPORT).with_version(options.version);
+    let request = net::OpImportRequest {
+        bus_id: from_cstr_like(bus_id).ok_or(net::Error::FieldTooLong("bus id"))?,
+    };
+
+    socket.send(&header)?;
+    socket.send(&request)?;
+
+    let reply_version = recv_reply_header(
+        socket,
+        protocol::OP_REP_IMPORT,
+        options.version,
+        |status| match status {
+            Status::DevBusy => Error::DeviceBusy(bus_id.into()),
+            status => Error::ImportFailed(status),
+        },
+    )?;
+
+    let reply: net::OpImportReply = socket.recv()?;
+    net::check_device(&reply.udev)?;
+    let received = buffer_to_string(reply.udev.bus_id());
+    if received != bus_id {
+        return Err(net::Error::BusIdMismatch(received.into()).into());
+    }
+
+    Ok((reply.udev, reply_version))
+}
+
+/// Attaches the device exported as `bus_id` by `host`,
+/// using the default [`AttachOptions`].
+///
+/// See [`attach_device_with`].
+pub fn attach_device(host: &str, bus_id: &str) -> Result<AttachedDevice, Box<dyn StdError>> {
+    attach_device_with(host, bus_id, &AttachOptions::default())
+}
+
+/// Imports the device exported as `bus_id` by `host`
+/// and attaches it to a free vhci port.
+///
+/// `host` is either a bare host name or address, which
+/// connects to the default USB/IP port, or a `host:port`
+/// pair (`[addr]:port` for IPv6).
+///
+/// Attaching is always exclusive: the server claims the
+/// device for this client until it is detached.
+///
+/// Unless `options.no_wait` is set, this waits for the kernel
+/// to report the port as used before returning. If that
+/// times out, the device stays attached.
+///
+/// # Errors
+/// This function can fail for these reasons below:
+/// - `options` requested `Access::Shared`
+/// - `host` could not be parsed or reached
+/// - The server replied with a protocol version other
+///   than `options.version`
+/// - The server rejected the import, for example with
+///   `Error::DeviceBusy` if another client has the device,
+///   or replied with a different device
+/// - No vhci port was free for the device's speed
+/// - The vhci driver refused the device or the port
+/// - The port was not reported as used in time
+/// - `options.deadline` passed
+/// - There was an error with the Vhci driver
+///   (see `usbip_core::vhci::Driver::try_open`)
+pub fn attach_device_with(
+    host: &str,
+    bus_id: &str,
+    options: &AttachOptions,
+) -> Result<AttachedDevice, Box<dyn StdError>> {
+    if !options.access.is_supported() {
+        return Err(Error::SharedNotSupported.into());
+    }
+
+    let endpoint = net::parse_endpoint(host)?;
+    let connect = net::ConnectOptions {
+        timeout: time_left(options.deadline)?,
+        no_keepalive: options.no_keepalive,
+        ..net::ConnectOptions::default()
+    };
+    let socket = net::connect_with(&endpoint, &connect)
+        .map_err(|err| past_deadline(err.into(), options.deadline))?;
+    attach_connected(socket, &endpoint, bus_id, options)
+}
+
+/// Attaches `bus_id` like [`attach_device`], looking up how
+/// to reach `host` and which protocol version it speaks in
+/// `registry`.
+///
+/// # Errors
+/// See [`attach_device_with`] and
+/// [`net::ServerRegistry::connect`].
+pub fn attach_device_via(
+    registry: &mut net::ServerRegistry,
+    host: &str,
+    bus_id: &str,
+) -> Result<AttachedDevice, Box<dyn StdError>> {
+    let endpoint = net::parse_endpoint(host)?;
+    let (socket, version) = registry.connect(host)?;
+    let options = AttachOptions {
+        version,
+        ..AttachOptions::default()
+    };
+    attach_connected(socket, &endpoint, bus_id, &options)
+}
+
+fn attach_connected(
+    mut socket: TcpStream,
+    endpoint: &net::Endpoint,
+    bus_id: &str,
+    options: &AttachOptions,
+) -> Result<AttachedDevice, Box<dyn StdError>> {
+    let result = match options.deadline {
+        Some(deadline) => {
+            let (udev, server_version) = request_import_until(&socket, bus_id, options, deadline)?;
+            log::trace!("importing {}", udev.redacted());
+            let port = import_device(&socket, udev.devid(), udev.speed_kind(), options)
+                .map_err(|err| past_deadline(err, options.deadline))?;
+            AttachedDevice {
+                udev,
+                port,
+                server_version,
+            }
+        }
+        None => query_import_device(&mut socket, bus_id, options)?,
+    };
+    record_attached(result, endpoint, bus_id, options)
+}
+
+/// Runs the import handshake for `bus_id` over `socket`,
+/// giving up at `deadline`, and then clears the timeouts
+/// that leaves on the socket whatever the outcome.
+fn request_import_until(
+    socket: &TcpStream,
+    bus_id: &str,
+    options: &AttachOptions,
+    deadline: Instant,
+) -> Result<(UsbDevice, u16), Box<dyn StdError>> {
+    let reply = request_import(&mut Bounded { socket, deadline }, bus_id, options);
+    clear_timeouts(socket)?;
+    reply.map_err(|err| past_deadline(err, Some(deadline)))
+}
+
+/// Clears the read and write timeouts left on `socket`,
+/// such as by [`Bounded`], before it is handed to the vhci
+/// driver.
+///
+/// The kernel adopts the socket with its options as they
+/// are, and would time out its own reads and writes after
+/// the leftover `SO_RCVTIMEO` and `SO_SNDTIMEO`, dropping
+/// the device whenever it is idle for that long.
+fn clear_timeouts(socket: &impl AsRawFd) -> io::Result<()> {
+    net::clear_timeouts(socket.as_raw_fd())
+}
+
+/// Attaches `bus_id` over `stream`, a connection to
+/// `endpoint` the caller has already opened and set up,
+/// for example with a custom authentication handshake
+/// before the USB/IP protocol starts.
+///
+/// The import handshake runs over `stream` through its
+/// [`net::Send`] and [`net::Recv`] impls, then the file
+/// descriptor from its `AsRawFd` impl is handed to the
+/// vhci driver, which carries the device's traffic over
+/// it from then on. The driver takes its own reference
+/// to the socket, and `stream` is dropped once it has,
+/// closing this process's descriptor as
+/// [`attach_device`] does with its own connection.
+///
+/// That descriptor must therefore be a stream socket
+/// (`SOCK_STREAM`), which the driver reads and writes
+/// directly: usually the TCP socket to the server. After
+/// the handshake the bytes on it must be plain USB/IP. An
+/// authentication step may come before, but a transport
+/// that keeps encrypting or framing the traffic, or has
+/// no socket of its own (QUIC, TLS, SSH channels), cannot
+/// be handed to the kernel; its traffic has to be relayed
+/// in userspace through a socket the kernel can adopt
+/// instead, see [`attach_with_relay`]. Set `TCP_NODELAY`
+/// and keepalive on a TCP socket as [`net::connect`]
+/// documents.
+///
+/// `endpoint` is only recorded in the port's state file.
+/// `options.deadline` bounds waiting for the port to be
+/// used, but not the handshake; give the socket read and
+/// write timeouts for that. They are cleared before the
+/// handoff.
+///
+/// # Errors
+/// See [`attach_device_with`].
+pub fn attach_over<S>(
+    mut stream: S,
+    endpoint: &net::Endpoint,
+    bus_id: &str,
+    options: &AttachOptions,
+) -> Result<AttachedDevice, Box<dyn StdError>>
+where
+    S: net::Send + net::Recv + AsRawFd,
+{
+    if !options.access.is_supported() {
+        return Err(Error::SharedNotSupported.into());
+    }
+
+    let (udev, server_version) = request_import(&mut stream, bus_id, options)?;
+    clear_timeouts(&stream)?;
+    log::trace!("importing {}", udev.redacted());
+    let port = import_device(&stream, udev.devid(), udev.speed_kind(), options)?;
+    drop(stream);
+    let result = AttachedDevice {
+        udev,
+        port,
+        server_version,
+    };
+    record_attached(result, endpoint, bus_id, options)
+}
+
+/// Attaches `bus_id` over `stream` like [`attach_over`],
+/// for transports the vhci driver cannot adopt, such as
+/// TLS, QUIC or SSH streams, by relaying the device's
+/// traffic in userspace.
+///
+/// The import handshake runs over `stream`. The driver is
+/// then handed one end of a Unix socket pair, and two
+/// threads copy bytes between the other end and the two
+/// halves of `stream`, one per direction.
+///
+/// The relay stops by itself once either side is done:
+/// - When the device is detached, the driver closes its
+///   end of the pair, and the relay closes `stream` (see
+///   [`net::Split::close`]).
+/// - When `stream` ends or fails, the relay closes the
+///   pair, and the driver drops the device as it would on
+///   a broken TCP connection.
+///
+/// Every byte the device sends or receives crosses this
+/// process, so the device stops working if the process
+/// exits, and throughput is bounded by the relay threads.
+/// [`relay_stats`] reports how much traffic it carried.
+///
+/// # Errors
+/// See [`attach_over`]. The socket pair could also fail to
+/// be created, `stream` to be split, or the relay threads
+/// to be spawned. If the port was attached by then, it is
+/// detached again and its relay stopped.
+pub fn attach_with_relay<S>(
+    mut stream: S,
+    endpoint: &net::Endpoint,
+    bus_id: &str,
+    options: &AttachOptions,
+) -> Result<AttachedDevice, Box<dyn StdError>>
+where
+    S: net::Send + net::Recv + net::Split,
+{
+    if !options.access.is_supported() {
+        return Err(Error::SharedNotSupported.into());
+    }
+
+    let (udev, server_version) = request_import(&mut stream, bus_id, options)?;
+    let (kernel_end, relay_end) = UnixStream::pair()?;
+    let (reader, writer) = stream.split()?;
+    log::trace!("importing {} over a relay", udev.redacted());
+    let port = import_device(&kernel_end, udev.devid(), udev.speed_kind(), options)?;
+    drop(kernel_end);
+
+    // Nothing records the port until `record_attached`, so
+    // a relay that cannot start would leave it attached to
+    // nothing, out of the caller's sight.
+    if let Err(err) = spawn_relay::<S>(port.get(), reader, writer, relay_end) {
+        if let Err(detach_err) = crate::detach::detach_port(port) {
+            log::warn!("failed to detach port {port} after the relay failed: {detach_err}");
+        }
+        return Err(err.into());
+    }
+
+    let result = AttachedDevice {
+        udev,
+        port,
+        server_version,
+    };
+    let recorded = record_attached(result, endpoint, bus_id, options);
+    if recorded.is_err() {
+        // The relay runs until the kernel lets go of its end,
+        // so a port left attached would keep it going with
+        // nobody holding the result.
+        if owned().contains(&port.get()) {
+            if let Err(detach_err) = crate::detach::detach_port(port) {
+                log::warn!("failed to detach port {port} after recording it failed: {detach_err}");
+            }
+        }
+        relays().remove(&port.get());
+    }
+    recorded
+}
+
+/// Traffic relayed for one port since its relay started.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RelayStats {
+    /// Bytes from the server to the driver.
+    pub bytes_in: u64,
+    /// Bytes from the driver to the server.
+    pub bytes_out: u64,
+    /// Time since the relay started.
+    pub elapsed: Duration,
+    /// Bytes per second from the server to the driver
+    /// since the previous call to [`relay_stats`] for the
+    /// port, or `None` on the first call.
+    pub rate_in: Option<f64>,
+    /// Bytes per second from the driver to the server,
+    /// measured like `rate_in`.
+    pub rate_out: Option<f64>,
+}
+
+/// Counters shared between a port's relay threads and
+/// [`relay_stats`].
+#[derive(Debug)]
+struct RelayCounters {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    started: Instant,
+    /// When `relay_stats` last read the counters, and what
+    /// they were then.
+    last_sample: Mutex<Option<(Instant, u64, u64)>>,
+}
+
+/// Counters of the relays running in this process, by port.
+static RELAYS: Mutex<BTreeMap<u8, Arc<RelayCounters>>> = Mutex::new(BTreeMap::new());
+
+fn relays() -> MutexGuard<'static, BTreeMap<u8, Arc<RelayCounters>>> {
+    RELAYS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Forgets `counters` once their relay stopped, unless the
+/// port already has a newer relay.
+fn end_relay(port: u8, counters: &Arc<RelayCounters>) {
+    let mut relays = relays();
+    if relays
+        .get(&port)
+        .is_some_and(|current| Arc::ptr_eq(current, counters))
+    {
+        relays.remove(&port);
+    }
+}
+
+/// Returns the traffic relayed for `port`, or `None` if no
+/// relay started by [`attach_with_relay`] is running on it.
+///
+/// The rates are averaged over the time since the previous
+/// call for the same port, so polling at a fixed interval
+/// gives a moving average over that interval.
+pub fn relay_stats(port: Port) -> Option<RelayStats> {
+    let counters = relays().get(&port.get())?.clone();
+    let now = Instant::now();
+    let bytes_in = counters.bytes_in.load(Ordering::Relaxed);
+    let bytes_out = counters.bytes_out.load(Ordering::Relaxed);
+
+    let mut last_sample = counters
+        .last_sample
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    let rates =
+        last_sample
+            .replace((now, bytes_in, bytes_out))
+            .and_then(|(then, last_in, last_out)| {
+                let secs = now.duration_since(then).as_secs_f64();
+                (secs > 0.0).then(|| {
+                    (
+                        bytes_in.saturating_sub(last_in) as f64 / secs,
+                        bytes_out.saturating_sub(last_out) as f64 / secs,
+                    )
+                })
+            });
+
+    Some(RelayStats {
+        bytes_in,
+        bytes_out,
+        elapsed: now.duration_since(counters.started),
+        rate_in: rates.map(|(rate_in, _)| rate_in),
+        rate_out: rates.map(|(_, rate_out)| rate_out),
+    })
+}
+
+/// A writer adding the bytes it writes to `count`.
+struct Counted<'a, W> {
+    inner: W,
+    count: &'a AtomicU64,
+}
+
+impl<W: Write> Write for Counted<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count.fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Spawns the threads relaying the device on `port`
+/// between `relay_end`, the driver's peer, and the two
+/// halves of the transport, counting the bytes for
+/// [`relay_stats`].
+fn spawn_relay<S: net::Split>(
+    port: u8,
+    mut reader: S::Reader,
+    mut writer: S::Writer,
+    relay_end: UnixStream,
+) -> io::Result<()> {
+    let mut from_kernel = relay_end.try_clone()?;
+    let mut to_kernel = relay_end;
+
+    let counters = Arc::new(RelayCounters {
+        bytes_in: AtomicU64::new(0),
+        bytes_out: AtomicU64::new(0),
+        started: Instant::now(),
+        last_sample: Mutex::new(None),
+    });
+    relays().insert(port, counters.clone());
+    let up_counters = counters.clone();
+    let down_counters = counters.clone();
+
+    let spawned = thread::Builder::new()
+        .name(format!("usbip-relay-{port}-up"))
+        .spawn(move || {
+            let mut counted = Counted {
+                inner: &mut writer,
+                count: &up_counters.bytes_out,
+            };
+            if let Err(err) = io::copy(&mut from_kernel, &mut counted) {
+                log::debug!("relay for port {port} stopped sending: {err}");
+            }
+            let _ = S::close(&mut writer);
+            let _ = from_kernel.shutdown(Shutdown::Both);
+            end_relay(port, &up_counters);
+        })
+        .and_then(|_| {
+            thread::Builder::new()
+                .name(format!("usbip-relay-{port}-down"))
+                .spawn(move || {
+                    let mut counted = Counted {
+                        inner: &mut to_kernel,
+                        count: &down_counters.bytes_in,
+                    };
+                    if let Err(err) = io::copy(&mut reader, &mut counted) {
+                        log::debug!("relay for port {port} stopped receiving: {err}");
+                    }
+                    let _ = to_kernel.shutdown(Shutdown::Both);
+                    end_relay(port, &down_counters);
+                })
+        });
+    if spawned.is_err() {
+        end_relay(port, &counters);
+    }
+    spawned.map(|_| ())
+}
+
+/// Records the connection for the port `result` was
+/// attached to in its state file, takes ownership of the
+/// port and, unless `options.no_wait` is set, waits for it
+/// to be used.
+fn record_attached(
+    result: AttachedDevice,
+    endpoint: &net::Endpoint,
+    bus_id: &str,
+    options: &AttachOptions,
+) -> Result<AttachedDevice, Box<dyn StdError>> {
+    let record = state::Record {
+        host: endpoint.host().into(),
+        tcp_port: endpoint.port(),
+        bus_id: bus_id.into(),
+        version: Some(result.server_version),
+    };
+    take_port_with(
+        result.port,
+        || state::write(result.port.get(), &record, options.state_format),
+        crate::detach::detach_port,
+    )?;
+
+    if !options.no_wait {
+        let timeout = match time_left(options.deadline)? {
+            Some(left) => left.min(USED_TIMEOUT),
+            None => USED_TIMEOUT,
+        };
+        wait_until_used(result.port, timeout)
+            .map_err(|err| past_deadline(err, options.deadline))?;
+    }
+
+    log_attached(&result, bus_id);
+    Ok(result)
+}
+
+/// Runs `write_state` for the freshly attached `port`, and
+/// only takes ownership of the port once it succeeded.
+///
+/// A port without a state file cannot be told apart from
+/// one attached by another tool, so if the state cannot be
+/// written the port is detached with `detach` instead of
+/// being left attached to nothing.
+///
+/// # Error
+/// This function returns the error from `write_state`.
+fn take_port_with(
+    port: Port,
+    write_state: impl FnOnce() -> io::Result<()>,
+    detach: impl FnOnce(Port) -> Result<(), Box<dyn StdError>>,
+) -> io::Result<()> {
+    if let Err(err) = write_state() {
+        if let Err(detach_err) = detach(port) {
+            log::warn!("failed to detach port {port} after recording it failed: {detach_err}");
+        }
+        return Err(err);
+    }
+    owned().insert(port.get());
+    Ok(())
+}
+
+/// Logs an attachment at `info` level, as one line with
+/// each detail as its own key-value pair for structured
+/// backends.
+///
+/// The serial number is never logged: it identifies the
+/// physical device, and can be read with
+/// [`AttachedDevice::strings`] where it is needed.
+fn log_attached(result: &AttachedDevice, bus_id: &str) {
+    let udev = &result.udev;
+    let vid_pid = format!("{:04x}:{:04x}", udev.id_vendor(), udev.id_product());
+    let speed = udev.speed_kind().to_string();
+    log::info!(
+        port = result.port.get(),
+        bus_id = bus_id,
+        vid_pid = vid_pid.as_str(),
+        speed = speed.as_str(),
+        server_version = result.server_version;
+        "attached {bus_id} ({vid_pid}, {speed}) to port {}",
+        result.port
+    );
+}
+
+/// Attaches `bus_id` from `host` like [`attach_device`],
+/// but tells the vhci driver the device is `devid` at
+/// `speed`, as cached from an earlier attach, instead of
+/// taking both from the server's import reply.
+///
+/// The import handshake still runs and its reply is
+/// checked as usual, since the server only hands the
+/// device over in reply to it, but the reply's devid and
+/// speed are not used. This is an
+/// escape hatch for tightly controlled environments, and
+/// the caller is trusted: if `devid` or `speed` do not
+/// match the device the server actually exports, the
+/// kernel fails to enumerate it or drops the connection
+/// once URBs start flowing, rather than this returning an
+/// error.
+///
+/// # Errors
+/// See [`attach_device_with`].
+pub fn attach_raw(
+    host: &str,
+    bus_id: &str,
+    devid: u32,
+    speed: Speed,
+) -> Result<AttachedDevice, Box<dyn StdError>> {
+    let options = AttachOptions::default();
+    let endpoint = net::parse_endpoint(host)?;
+    let mut socket = net::connect(&endpoint)?;
+
+    let (udev, server_version) = request_import(&mut socket, bus_id, &options)?;
+    let port = import_device(&socket, devid, speed, &options)?;
+    let result = AttachedDevice {
+        udev,
+        port,
+        server_version,
+    };
+    record_attached(result, &endpoint, bus_id, &options)
+}
+
+/// Attaches `bus_id` from `host` like [`attach_device`],
+/// giving up with `Error::TimedOut` once `timeout` has
+/// passed, however the server behaves.
+///
+/// Connecting is bounded by `timeout` directly. During
+/// the import handshake, a watchdog thread holds a clone
+/// of the socket and shuts it down when time runs out,
+/// which makes the blocked read return at once. Shutting
+/// down the socket once it belongs to the kernel would
+/// break the attached device, so the watchdog is stopped
+/// and joined before the socket is handed to the vhci
+/// driver, on every path. Waiting for the port to be used
+/// is then bounded by the time left, so the attach is only
+/// logged once the device is in use.
+///
+/// # Errors
+/// See [`attach_device_with`].
+pub fn attach_device_timeout(
+    host: &str,
+    bus_id: &str,
+    timeout: Duration,
+) -> Result<AttachedDevice, Box<dyn StdError>> {
+    let deadline = Instant::now() + timeout;
+    let options = AttachOptions {
+        deadline: Some(deadline),
+        ..AttachOptions::default()
+    };
+    let endpoint = net::parse_endpoint(host)?;
+    let connect = net::ConnectOptions {
+        timeout: Some(timeout),
+        ..net::ConnectOptions::default()
+    };
+    let mut socket =
+        net::connect_with(&endpoint, &connect).map_err(|err| -> Box<dyn StdError> {
+            if err.kind() == io::ErrorKind::TimedOut {
+                Error::TimedOut.into()
+            } else {
+                err.into()
+            }
+        })?;
+
+    let watched = socket.try_clone()?;
+    let fired = Arc::new(AtomicBool::new(false));
+    let (stop, stopped) = mpsc::channel::<()>();
+    let watchdog = {
+        let fired = Arc::clone(&fired);
+        thread::spawn(move || {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(left) {
+                fired.store(true, Ordering::SeqCst);
+                let _ = watched.shutdown(Shutdown::Both);
+            }
+        })
+    };
+
+    let reply = request_import(&mut socket, bus_id, &options);
+    drop(stop);
+    let _ = watchdog.join();
+    if fired.load(Ordering::SeqCst) {
+        return Err(Error::TimedOut.into());
+    }
+
+    let (udev, server_version) = reply?;
+    let port = import_device(&socket, udev.devid(), udev.speed_kind(), &options)?;
+    record_attached(
+        AttachedDevice {
+            udev,
+            port,
+            server_version,
+        },
+        &endpoint,
+        bus_id,
+        &options,
+    )
+}
+
+/// Attaches `bus_id` like [`attach_device`], but returns as
+/// soon as the vhci driver accepts the device.
+///
+/// The kernel may still be enumerating the device when this
+/// returns, so it may not be usable yet. This is the low
+/// latency path for attaching many devices in a row; use
+/// [`wait_until_used`] to confirm a device later.
+///
+/// # Errors
+/// See [`attach_device_with`].
+pub fn attach_device_nowait(host: &str, bus_id: &str) -> Result<AttachedDevice, Box<dyn StdError>> {
+    let options = AttachOptions {
+        no_wait: true,
+        ..AttachOptions::default()
+    };
+    attach_device_with(host, bus_id, &options)
+}
+
+/// Resets the device attached to `port` by detaching it
+/// and attaching it again from the same server and bus id,
+/// as recorded in the port's state file.
+///
+/// The device is attached back to `port` if it is still
+/// free, or else to any free port on the matching hub. The
+/// recorded protocol version is reused when there is one.
+/// If attaching again fails, the device stays detached.
+///
+/// # Errors
+/// This function can fail for these reasons below:
+/// - No connection was recorded for `port`
+/// - Detaching failed (see [`crate::detach::detach_port`])
+/// - Attaching again failed (see [`attach_device_with`])
+pub fn reattach(port: Port) -> Result<AttachedDevice, Box<dyn StdError>> {
+    let record = state::read(port.get())?.ok_or(Error::NotRecorded(port.get()))?;
+    let version = match record.version {
+        Some(version) => net::ProtocolVersion::new(version)?,
+        None => net::ProtocolVersion::default(),
+    };
+
+    crate::detach::detach_port(port)?;
+
+    let endpoint = net::Endpoint::new(&record.host, record.tcp_port);
+    attach_preferring_port(&endpoint, &record.bus_id, port, version)
+}
+
+/// Attaches `bus_id` from `endpoint` to `port` if it is
+/// still free, or else to any free port on the matching
+/// hub.
+fn attach_preferring_port(
+    endpoint: &net::Endpoint,
+    bus_id: &str,
+    port: Port,
+    version: net::ProtocolVersion,
+) -> Result<AttachedDevice, Box<dyn StdError>> {
+    let socket = net::connect(endpoint)?;
+    let options = AttachOptions {
+        version,
+        port: Some(port),
+        ..AttachOptions::default()
+    };
+    match attach_connected(socket, endpoint, bus_id, &options) {
+        Err(err) if matches!(err.downcast_ref(), Some(Error::PortUnavailable(_))) => {
+            let socket = net::connect(endpoint)?;
+            let options = AttachOptions {
+                port: None,
+                ..options
+            };
+            attach_connected(socket, endpoint, bus_id, &options)
+        }
+        result => result,
+    }
+}
+
+/// A snapshot of the current attachments, to attach them
+/// again later with [`restore_manifest`], for example
+/// after rebooting or on another host.
+///
+/// Serialize it with any serde format to keep it around.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// One attachment in a [`Manifest`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub host: String,
+    pub tcp_port: u16,
+    pub bus_id: String,
+    /// The vhci port the device was attached to. It is
+    /// kept as a number, since a manifest may be restored
+    /// on another host, and is checked against the driver
+    /// by [`restore_manifest`].
+    pub port: u8,
+    /// The protocol version the server replied with, if
+    /// it was recorded.
+    pub version: Option<u16>,
+}
+
+/// Snapshots every attachment with connection details
+/// recorded for it, see [`attachments`].
+///
+/// # Errors
+/// See [`attachments`].
+pub fn export_manifest() -> Result<Manifest, Box<dyn StdError>> {
+    let entries = attachments()?
+        .into_iter()
+        .map(|info| ManifestEntry {
+            host: info.host().into(),
+            tcp_port: info.tcp_port(),
+            bus_id: info.bus_id().into(),
+            port: info.port().get(),
+            version: info.version(),
+        })
+        .collect();
+    Ok(Manifest { entries })
+}
+
+/// Attaches every device in `manifest` again, returning
+/// one result per entry, in order.
+///
+/// Each device goes back to the port it was on if that
+/// port is free, or else to any free port on the matching
+/// hub, like [`reattach`]. The recorded protocol version
+/// is reused when there is one. Entries are attached one
+/// after the other, and one failing never stops the rest.
+pub fn restore_manifest(manifest: &Manifest) -> Vec<crate::Result<AttachedDevice>> {
+    manifest
+        .entries
+        .iter()
+        .map(|entry| {
+            let version = match entry.version {
+                Some(version) => net::ProtocolVersion::new(version)?,
+                None => net::ProtocolVersion::default(),
+            };
+            let endpoint = net::Endpoint::new(&entry.host, entry.tcp_port);
+            let port = Port::try_from(entry.port)?;
+            Ok(attach_preferring_port(
+                &endpoint,
+                &entry.bus_id,
+                port,
+                version,
+            )?)
+        })
+        .collect()
+}
+
+/// Attaches every `(host, bus_id)` pair in `requests`,
+/// running up to `concurrency` attaches at once.
+///
+/// Each attach runs on its own connection like
+/// [`attach_device`], and the results are returned in the
+/// same order as `requests`. Attaching one device never
+/// stops the others from being attached.
+///
+/// Connecting and the import handshake run in parallel,
+/// but picking and claiming a vhci port is serialized
+/// across threads, so workers never race for the same
+/// port. The kernel also serializes writes to the vhci
+/// driver, so a large `concurrency` mostly helps when
+/// the servers are slow to reply.
+pub fn attach_batch(
+    requests: &[(String, String)],
+    concurrency: usize,
+) -> Vec<crate::Result<AttachedDevice>> {
+    attach_batch_cancellable(requests, concurrency, &AtomicBool::new(false))
+}
+
+/// Attaches every `(host, bus_id)` pair in `requests`
+/// like [`attach_batch`], until `cancel` is set.
+///
+/// Once `cancel` is set, no further attach is started.
+/// An attach already under way is not interrupted: it
+/// runs to completion and is then rolled back with the
+/// rest. Every device the batch attached is detached
+/// again, including those that completed before the
+/// cancellation, so the system is left as it was before
+/// the batch. Requests that were never started or were
+/// rolled back fail with `Error::Cancelled`.
+///
+/// A device that could not be detached during the
+/// rollback is logged and kept as `Ok` in the results, so
+/// the caller can tell which ports are still attached.
+pub fn attach_batch_cancellable(
+    requests: &[(String, String)],
+    concurrency: usize,
+    cancel: &AtomicBool,
+) -> Vec<crate::Result<AttachedDevice>> {
+    attach_batch_with(
+        requests,
+        concurrency,
+        cancel,
+        |host, bus_id| attach_device(host, bus_id).map_err(crate::Error::from),
+        crate::detach::detach_port,
+    )
+}
+
+/// Runs the batch of [`attach_batch_cancellable`], with
+/// `attach` and `detach` standing in for attaching and
+/// detaching a device.
+fn attach_batch_with(
+    requests: &[(String, String)],
+    concurrency: usize,
+    cancel: &AtomicBool,
+    attach: impl Fn(&str, &str) -> crate::Result<AttachedDevice> + Sync,
+    detach: impl Fn(Port) -> Result<(), Box<dyn StdError>>,
+) -> Vec<crate::Result<AttachedDevice>> {
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<crate::Result<AttachedDevice>>> =
+        requests.iter().map(|_| None).collect();
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..concurrency.clamp(1, requests.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        if cancel.load(Ordering::Relaxed) {
+                            break done;
+                        }
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some((host, bus_id)) = requests.get(i) else {
+                            break done;
+                        };
+                        done.push((i, attach(host, bus_id)));
+                    }
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            let done = worker
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            for (i, result) in done {
+                results[i] = Some(result);
+            }
+        }
+    });
+
+    let cancelled = cancel.load(Ordering::Relaxed);
+    results
+        .into_iter()
+        .map(|result| match result {
+            Some(Ok(attached)) if cancelled => match detach(attached.port) {
+                Ok(()) => Err(Error::Cancelled.into()),
+                Err(err) => {
+                    log::warn!(
+                        "failed to detach port {} while cancelling the batch: {err}",
+                        attached.port
+                    );
+                    Ok(attached)
+                }
+            },
+            Some(result) => result,
+            // Requests are only left unstarted once `cancel`
+            // was set, even if it has been cleared since.
+            None => Err(Error::Cancelled.into()),
+        })
+        .collect()
+}
+
+/// Waits until the device attached to `port` is in use,
+/// meaning the kernel has finished setting it up.
+///
+/// Once attached, the connection belongs to the kernel,
+/// so it cannot be read here to notice a dead server.
+/// Instead, the kernel's own view is polled: when the
+/// connection breaks, the kernel marks the port as failed
+/// or frees it, which ends the wait right away.
+///
+/// # Errors
+/// This function fails with `Error::TimedOut` if the port
+/// did not become used within `timeout`, with
+/// `Error::ConnectionClosed` if the connection broke
+/// first, or if there was an error with the Vhci driver.
+pub fn wait_until_used(port: Port, timeout: Duration) -> Result<(), Box<dyn StdError>> {
+    let port = port.get();
+    let deadline = Instant::now() + timeout;
+    loop {
+        let status = vhci::Driver::try_open()?
+            .imported_devices()
+            .find(|idev| idev.port() == port)
+            .map(|idev| idev.status());
+        if is_used(port, status)? {
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            return Err(Error::TimedOut.into());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Tells from the status the driver lists for `port`
+/// whether the device being set up on it is in use yet.
+///
+/// # Error
+/// This function returns `Error::ConnectionClosed` if
+/// the kernel gave up on the port instead.
+fn is_used(port: u8, status: Option<DeviceStatus>) -> Result<bool, Error> {
+    match status {
+        Some(DeviceStatus::DeviceUsed) => Ok(true),
+        Some(DeviceStatus::PortAvailable | DeviceStatus::DeviceError) => {
+            Err(Error::ConnectionClosed(port))
+        }
+        _ => Ok(false),
+    }
+}
+
+/// A vhci port in use, joined with the connection
+/// details recorded when it was attached.
+#[derive(Debug, Clone)]
+pub struct AttachmentInfo {
+    port: u8,
+    host: Box<str>,
+    tcp_port: u16,
+    bus_id: Box<str>,
+    version: Option<u16>,
+}
+
+impl AttachmentInfo {
+    pub(crate) fn new(port: u8, record: state::Record) -> Self {
+        Self {
+            port,
+            host: record.host,
+            tcp_port: record.tcp_port,
+            bus_id: record.bus_id,
+            version: record.version,
+        }
+    }
+
+    pub const fn port(&self) -> Port {
+        Port::from_driver(self.port)
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub const fn tcp_port(&self) -> u16 {
+        self.tcp_port
+    }
+
+    pub fn bus_id(&self) -> &str {
+        &self.bus_id
+    }
+
+    /// The protocol version the server replied with at
+    /// attach time, if it was recorded.
+    pub const fn version(&self) -> Option<u16> {
+        self.version
+    }
+
+    /// Returns `true` if this is `bus_id` from the server
+    /// at `endpoint`. NUL padding on either bus id is
+    /// ignored.
+    pub(crate) fn is_to(&self, endpoint: &net::Endpoint, bus_id: &str) -> bool {
+        self.host() == endpoint.host()
+            && self.tcp_port() == endpoint.port()
+            && self.bus_id().trim_end_matches('\0') == bus_id.trim_end_matches('\0')
+    }
+}
+
+/// Returns `true` if `port` is in use and recorded as
+/// serving `bus_id` from `host`.
+///
+/// Only `port` is looked at, so this is cheaper than
+/// filtering [`attachments`]. Like there, the host must
+/// match as written (see [`attach_device_idempotent`]). A
+/// port in use without a state file is not attached to
+/// anything as far as this is concerned.
+///
+/// # Errors
+/// This function fails if `host` could not be parsed, if
+/// there was an error with the Vhci driver, or if the
+/// port's state file could not be read.
+pub fn is_port_attached_to(
+    port: Port,
+    host: &str,
+    bus_id: &str,
+) -> Result<bool, Box<dyn StdError>> {
+    let port = port.get();
+    let endpoint = net::parse_endpoint(host)?;
+    let in_use = vhci::Driver::try_open()?
+        .imported_devices()
+        .in_use()
+        .any(|idev| idev.port() == port);
+    if !in_use {
+        return Ok(false);
+    }
+
+    Ok(state::read(port)?
+        .is_some_and(|record| AttachmentInfo::new(port, record).is_to(&endpoint, bus_id)))
+}
+
+/// Filters for iterators over vhci ports, such as
+/// `usbip_core::vhci::Driver::imported_devices`, which
+/// lists every port whether it is in use or not.
+pub trait ImportedDeviceFilter: Iterator<Item = vhci::ImportedDevice> + Sized {
+    /// Keeps only the ports whose status is `status`,
+    /// such as `DeviceStatus::DeviceUsed`.
+    fn with_status(self, status: DeviceStatus) -> WithStatus<Self> {
+        WithStatus {
+            inner: self,
+            status,
+            matching: true,
+        }
+    }
+
+    /// Keeps only the ports with a device on them, in any
+    /// status other than `DeviceStatus::PortAvailable`.
+    fn in_use(self) -> WithStatus<Self> {
+        WithStatus {
+            inner: self,
+            status: DeviceStatus::PortAvailable,
+            matching: false,
+        }
+    }
+}
+
+impl<I: Iterator<Item = vhci::ImportedDevice>> ImportedDeviceFilter for I {}
+
+/// An iterator over the ports of another that do or do
+/// not have a given status, see [`ImportedDeviceFilter`].
+#[derive(Debug, Clone)]
+pub struct WithStatus<I> {
+    inner: I,
+    status: DeviceStatus,
+    matching: bool,
+}
+
+impl<I: Iterator<Item = vhci::ImportedDevice>> Iterator for WithStatus<I> {
+    type Item = vhci::ImportedDevice;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (status, matching) = (self.status, self.matching);
+        self.inner
+            .find(|idev| (idev.status() == status) == matching)
+    }
+}
+
+/// Lists every port currently in use that has
+/// connection details recorded for it.
+///
+/// Ports attached without a state file (for example by
+/// a tool that does not write one) are skipped.
+///
+/// # Errors
+/// This function can fail if there was an error with the
+/// Vhci driver or a state file could not be read.
+pub fn attachments() -> Result<Vec<AttachmentInfo>, Box<dyn StdError>> {
+    let driver = vhci::Driver::try_open()?;
+
+    let mut attachments = Vec::new();
+    for idev in driver.imported_devices().in_use() {
+        if let Some(record) = state::read(idev.port())? {
+            attachments.push(AttachmentInfo::new(idev.port(), record));
+        }
+    }
+
+    Ok(attachments)
+}
+
+/// Lists the attachments whose server replied with
+/// protocol version `v`.
+///
+/// Attachments with no recorded version never match.
+///
+/// # Errors
+/// See [`attachments`].
+pub fn attachments_by_version(v: u16) -> Result<Vec<AttachmentInfo>, Box<dyn StdError>> {
+    let mut attachments = attachments()?;
+    attachments.retain(|info| info.version == Some(v));
+    Ok(attachments)
+}
+
+/// One vhci port as `usbip port` reports it, joining
+/// the driver's view of the port with the connection
+/// recorded for it.
+///
+/// `Display` prints the same block as the C tool does for
+/// each port. Free ports, which the C tool leaves out, are
+/// printed as a single `<Port Available>` line.
+#[derive(Debug, Clone)]
+pub struct PortRecord {
+    pub port: Port,
+    pub status: DeviceStatus,
+    /// The speed the device was attached with.
+    pub speed: Speed,
+    /// The remote devid the device was attached with,
+    /// `busnum << 16 | devnum` on the server.
+    pub devid: u32,
+    /// The server and bus id recorded in the port's
+    /// state file, if there is one.
+    pub remote: Option<AttachmentInfo>,
+    /// The local bus id the kernel gave the device, once
+    /// it has been enumerated.
+    pub local_bus_id: Option<String>,
+    /// The local device's vendor and product ids, once it
+    /// has been enumerated.
+    pub vid_pid: Option<(u16, u16)>,
+}
+
+impl PortRecord {
+    /// Returns `true` if no device is attached to the port.
+    pub const fn is_free(&self) -> bool {
+        matches!(self.status, DeviceStatus::PortAvailable)
+    }
+
+    /// Returns the port status as the C tool prints it,
+    /// such as "Port in Use".
+    pub const fn status_str(&self) -> &'static str {
+        match self.status {
+            DeviceStatus::PortAvailable => "Port Available",
+            DeviceStatus::PortInitializing => "Port Initializing",
+            DeviceStatus::DeviceUsed => "Port in Use",
+            DeviceStatus::DeviceError => "Port Error",
+            _ => "Unknown Status",
+        }
+    }
+}
+
+impl fmt::Display for PortRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Port {:02}: <{}>", self.port, self.status_str())?;
+        if self.is_free() {
+            return Ok(());
+        }
+        writeln!(f, " at {}", self.speed)?;
+
+        match self.vid_pid {
+            Some((vendor, product)) => writeln!(
+                f,
+                "       unknown vendor : unknown product ({vendor:04x}:{product:04x})"
+            )?,
+            None => writeln!(f, "       unknown vendor : unknown product")?,
+        }
+        let local = self.local_bus_id.as_deref().unwrap_or("0-0");
+        match &self.remote {
+            Some(info) => writeln!(
+                f,
+                "       {local} -> usbip://{}:{}/{}",
+                info.host(),
+                info.tcp_port(),
+                info.bus_id()
+            )?,
+            None => writeln!(
+                f,
+                "       {local} -> unknown host, remote port and remote busid"
+            )?,
+        }
+        write!(
+            f,
+            "           -> remote bus/dev {:03}/{:03}",
+            self.devid >> 16,
+            self.devid & 0xffff
+        )
+    }
+}
+
+/// Lists every vhci port, free or not, like `usbip port`.
+///
+/// See [`crate::format::ports`] to print them the way the
+/// C tool does.
+///
+/// # Errors
+/// This function can fail if there was an error with the
+/// Vhci driver, or if sysfs or a state file could not be
+/// read.
+pub fn ports() -> Result<Vec<PortRecord>, Box<dyn StdError>> {
+    let driver = vhci::Driver::try_open()?;
+    let statuses = sysfs::port_statuses()?;
+
+    let mut records = Vec::new();
+    for idev in driver.imported_devices() {
+        let port = idev.port();
+        let status = statuses.iter().find(|status| status.port == port);
+        let local_bus_id = status.and_then(|status| status.local_bus_id.clone());
+        let vid_pid = match &local_bus_id {
+            Some(bus_id) => {
+                let id = |name| -> io::Result<Option<u16>> {
+                    Ok(sysfs::usb_device_attr(bus_id, name)?
+                        .and_then(|value| u16::from_str_radix(&value, 16).ok()))
+                };
+                id("idVendor")?.zip(id("idProduct")?)
+            }
+            None => None,
+        };
+        let remote = match idev.status() {
+            DeviceStatus::PortAvailable => None,
+            _ => state::read(port)?.map(|record| AttachmentInfo::new(port, record)),
+        };
+
+        records.push(PortRecord {
+            port: Port::from_driver(port),
+            status: idev.status(),
+            speed: Speed::from(status.map_or(0, |status| status.speed)),
+            devid: status.map_or(0, |status| status.devid),
+            remote,
+            local_bus_id,
+            vid_pid,
+        });
+    }
+    Ok(records)
+}
+
+/// The free ports of one vhci controller, by the speed of
+/// the hub they are on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ControllerPorts {
+    /// The controller, as in `vhci_hcd.N` and
+    /// [`AttachOptions::controller`].
+    pub controller: usize,
+    /// Free ports for low, full and high speed devices.
+    pub high_speed: Vec<Port>,
+    /// Free ports for SuperSpeed devices.
+    pub super_speed: Vec<Port>,
+}
+
+impl ControllerPorts {
+    /// Returns the free ports a device of `speed` can be
+    /// attached to.
+    pub fn for_speed(&self, speed: Speed) -> &[Port] {
+        if speed.is_super_speed() {
+            &self.super_speed
+        } else {
+            &self.high_speed
+        }
+    }
+}
+
+/// Lists the free ports of every vhci controller, so a
+/// device can be placed on a controller that still has
+/// room for its speed.
+///
+/// Every controller is listed, in order, even if it has no
+/// free ports left.
+///
+/// # Errors
+/// This function can fail if there was an error with the
+/// Vhci driver, or if sysfs could not be read.
+pub fn free_ports_by_controller() -> Result<Vec<ControllerPorts>, Box<dyn StdError>> {
+    let driver = vhci::Driver::try_open()?;
+    let idevs: Vec<_> = driver.imported_devices().collect();
+    let num_ports = driver.num_ports();
+
+    let mut controllers: Vec<ControllerPorts> = Vec::new();
+    for status in sysfs::port_statuses()? {
+        while controllers.len() <= status.controller {
+            controllers.push(ControllerPorts {
+                controller: controllers.len(),
+                ..ControllerPorts::default()
+            });
+        }
+        let Some(idev) = idevs.iter().find(|idev| idev.port() == status.port) else {
+            continue;
+        };
+        if !matches!(idev.status(), DeviceStatus::PortAvailable) {
+            continue;
+        }
+
+        let port = Port::new(status.port, num_ports)?;
+        let ports = &mut controllers[status.controller];
+        match idev.hub() {
+            vhci::HubSpeed::Super => ports.super_speed.push(port),
+            _ => ports.high_speed.push(port),
+        }
+    }
+    Ok(controllers)
+}
+
+/// Iterates over the devices of a devlist reply,
+/// reading each one from the socket as it is requested.
+///
+/// Iteration stops after the first error, since the
+/// rest of the reply can no longer be framed.
+///
+/// Devices that fail [`UsbDeviceExt::validate`] are
+/// skipped with a warning, unless [`Devlist::strict`]
+/// is used to yield them as errors instead.
+pub struct Devlist<S> {
+    socket: S,
+    remaining: u32,
+    strict: bool,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl<S> Devlist<S> {
+    /// Yields devices that fail validation as errors
+    /// instead of skipping them. The rest of the reply
+    /// is still read after such an error.
+    pub fn strict(self) -> Self {
+        Self {
+            strict: true,
+            ..self
+        }
+    }
+
+    /// Stops iterating as soon as `cancel` is set, for
+    /// example by a UI the user navigated away from.
+    ///
+    /// The flag is checked between devices, so a device
+    /// being read is finished first. The rest of the reply
+    /// is left unread, so drop the `Devlist`, and with it
+    /// the connection, rather than reusing the socket.
+    pub fn cancel_on(self, cancel: Arc<AtomicBool>) -> Self {
+        Self {
+            cancel: Some(cancel),
+            ..self
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+}
+
+impl<S> Devlist<S>
+where
+    S: net::Recv,
+{
+    fn read_device(&mut self) -> Result<UsbDevice, Box<dyn StdError>> {
+        let udev: UsbDevice = self.socket.recv()?;
+        // Each device is followed by one 4 byte record per
+        // interface, padding included, which must all be
+        // consumed to reach the next device. This happens
+        // before validation so that a skipped device does
+        // not leave its records in front of the next one.
+        let mut interfaces = vec![0; net::INTERFACE_SIZE * usize::from(udev.b_num_interfaces())];
+        self.socket.read_exact(&mut interfaces)?;
+        net::check_device(&udev)?;
+        log::trace!("devlist entry: {}", udev.redacted());
+        Ok(udev)
+    }
+}
+
+impl<S> Iterator for Devlist<S>
+where
+    S: net::Recv,
+{
+    type Item = Result<UsbDevice, Box<dyn StdError>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.remaining == 0 {
+                return None;
+            }
+            if self.is_cancelled() {
+                self.remaining = 0;
+                return None;
+            }
+            self.remaining -= 1;
+
+            let udev = match self.read_device() {
+                Ok(udev) => udev,
+                Err(err) => {
+                    self.remaining = 0;
+                    return Some(Err(err));
+                }
+            };
+            match udev.validate() {
+                Ok(()) => return Some(Ok(udev)),
+                Err(err) if self.strict => return Some(Err(err.into())),
+                Err(err) => log::warn!("skipping devlist entry {}: {err}", udev.redacted()),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, usize::try_from(self.remaining).ok())
+    }
+}
+
+/// Requests the list of exported devices over `socket`,
+/// returning an iterator over the devices in the reply.
+///
+/// # Errors
+/// This function fails if the request could not be sent,
+/// or the server replied with a different protocol version
+/// or a failure status.
+pub fn devlist<S>(
+    mut socket: S,
+    version: net::ProtocolVersion,
+) -> Result<Devlist<S>, Box<dyn StdError>>
+where
+    S: net::Send + net::Recv,
+{
+    let request = net::OpCommon::request(protocol::OP_REQ_DEVLIST).with_version(version);
+
+    socket.send(&request)?;
+
+    recv_reply_header(
+        &mut socket,
+        protocol::OP_REP_DEVLIST,
+        version,
+        Error::DevlistFailed,
+    )?;
+
+    let reply: net::OpDevlistReply = socket.recv()?;
+    Ok(Devlist {
+        socket,
+        remaining: reply.ndev,
+        strict: false,
+        cancel: None,
+    })
+}
+
+/// Logs a warning for each bus id `host` listed more
+/// than once among `devices`.
+fn warn_duplicates(host: &str, devices: &[UsbDevice]) {
+    for bus_id in device::find_duplicates(devices) {
+        log::warn!("{host} exports bus id {bus_id} more than once; attaching it is ambiguous");
+    }
+}
+
+/// Lists the devices exported by `host`.
+///
+/// See [`devlist`] for a streaming variant over an
+/// already connected socket.
+///
+/// Bus ids listed more than once are logged as a warning,
+/// since attaching them by bus id is ambiguous; use
+/// [`device::find_duplicates`] to act on them.
+///
+/// The listing cannot tell which devices are free: the
+/// devlist reply carries no status per device, and
+/// servers list devices another client has imported just
+/// like free ones. The only way to find out is to attach;
+/// a device in use is refused with `Error::DeviceBusy`
+/// before anything is set up locally.
+///
+/// # Errors
+/// This function can fail if `host` could not be parsed
+/// or reached, or for any reason listed in [`devlist`].
+pub fn list_exported_devices(host: &str) -> Result<Vec<UsbDevice>, Box<dyn StdError>> {
+    let endpoint = net::parse_endpoint(host)?;
+    let socket = net::connect(&endpoint)?;
+    let devices: Vec<_> = devlist(
+        net::BufferedRecv::new(socket),
+        net::ProtocolVersion::default(),
+    )?
+    .collect::<Result<_, _>>()?;
+    warn_duplicates(host, &devices);
+    Ok(devices)
+}
+
+struct CachedDevlist {
+    devices: Vec<UsbDevice>,
+    refreshed: Instant,
+}
+
+/// Caches the devices each server exports, for clients
+/// that list the same servers over and over, such as a
+/// dashboard.
+///
+/// A host is listed again with [`list_exported_devices`]
+/// once its entry is older than the cache's TTL. Failed
+/// listings are not cached: the entry is dropped, so the
+/// next call tries the server again instead of serving a
+/// list from before it went away.
+pub struct DevlistCache {
+    ttl: Duration,
+    hosts: HashMap<Box<str>, CachedDevlist>,
+}
+
+impl DevlistCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Returns the devices `host` exports, listing them
+    /// if there is no fresh entry for it.
+    ///
+    /// # Errors
+    /// See [`list_exported_devices`].
+    pub fn get(&mut self, host: &str) -> Result<&[UsbDevice], Box<dyn StdError>> {
+        self.get_with(host, Instant::now(), list_exported_devices)
+    }
+
+    /// Looks `host` up like [`DevlistCache::get`] at `now`,
+    /// with `list` standing in for listing the server.
+    fn get_with(
+        &mut self,
+        host: &str,
+        now: Instant,
+        list: impl FnOnce(&str) -> Result<Vec<UsbDevice>, Box<dyn StdError>>,
+    ) -> Result<&[UsbDevice], Box<dyn StdError>> {
+        let fresh = self
+            .hosts
+            .get(host)
+            .is_some_and(|cached| now.saturating_duration_since(cached.refreshed) < self.ttl);
+
+        if !fresh {
+            self.hosts.remove(host);
+            let devices = list(host)?;
+            self.hosts.insert(
+                host.into(),
+                CachedDevlist {
+                    devices,
+                    refreshed: now,
+                },
+            );
+        }
+
+        Ok(&self.hosts[host].devices)
+    }
+
+    /// Drops the entry for `host`, so the next
+    /// [`DevlistCache::get`] lists it again. Call this
+    /// after attaching or detaching a device from `host`.
+    pub fn invalidate(&mut self, host: &str) {
+        self.hosts.remove(host);
+    }
+
+    /// Drops every entry.
+    pub fn clear(&mut self) {
+        self.hosts.clear();
+    }
+}
+
+/// Lists the devices exported by `host` like
+/// [`list_exported_devices`], sorted by `key`.
+///
+/// # Errors
+/// See [`list_exported_devices`].
+pub fn list_exported_devices_sorted(
+    host: &str,
+    key: SortKey,
+) -> Result<Vec<UsbDevice>, Box<dyn StdError>> {
+    let mut devices = list_exported_devices(host)?;
+    key.sort(&mut devices);
+    Ok(devices)
+}
+
+/// Lists the devices exported by each of `hosts`, one
+/// thread per host, returning each host with its result
+/// in the same order as `hosts`.
+///
+/// An unreachable host only fails its own entry, so the
+/// rest of the inventory is still returned.
+pub fn list_exported_devices_multi(
+    hosts: &[String],
+) -> Vec<(String, crate::Result<Vec<UsbDevice>>)> {
+    thread::scope(|scope| {
+        let workers: Vec<_> = hosts
+            .iter()
+            .map(|host| {
+                scope.spawn(move || list_exported_devices(host).map_err(crate::Error::from))
+            })
+            .collect();
+
+        hosts
+            .iter()
+            .zip(workers)
+            .map(|(host, worker)| {
+                let result = worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+                (host.clone(), result)
+            })
+            .collect()
+    })
+}
+
+/// Lists the devices exported by `host` like
+/// [`list_exported_devices`], looking up how to reach it
+/// and which protocol version it speaks in `registry`.
+///
+/// # Errors
+/// See [`list_exported_devices`] and
+/// [`net::ServerRegistry::connect`].
+pub fn list_exported_devices_via(
+    registry: &mut net::ServerRegistry,
+    host: &str,
+) -> Result<Vec<UsbDevice>, Box<dyn StdError>> {
+    let (socket, version) = registry.connect(host)?;
+    let devices: Vec<_> =
+        devlist(net::BufferedRecv::new(socket), version)?.collect::<Result<_, _>>()?;
+    warn_duplicates(host, &devices);
+    Ok(devices)
+}
+
+/// Looks up the bus id of the device `host` exports
+/// with the given `devid`.
+///
+/// The import request only identifies devices by bus
+/// id, so this runs a devlist first to translate the
+/// devid, which stays the same across reconnects.
+///
+/// # Errors
+/// This function fails if no exported device has this
+/// `devid`, or for any reason listed in
+/// [`list_exported_devices`].
+pub fn bus_id_for_devid(host: &str, devid: u32) -> Result<String, Box<dyn StdError>> {
+    list_exported_devices(host)?
+        .iter()
+        .find(|udev| udev.devid() == devid)
+        .map(|udev| buffer_to_string(udev.bus_id()).into_owned())
+        .ok_or_else(|| Error::DevidNotExported(devid).into())
+}
+
+/// Rebuilds the [`AttachedDevice`] for `bus_id`, attached
+/// to `port` earlier with protocol `version`.
+///
+/// The import reply is not kept after attaching, so the
+/// descriptor fields are read back from the kernel's copy
+/// of the device. The bus and device numbers are the
+/// remote ones, from the devid the port was attached
+/// with. The remote sysfs path is not known, so it is
+/// left empty.
+fn recorded_device(
+    port: Port,
+    bus_id: &str,
+    version: u16,
+) -> Result<AttachedDevice, Box<dyn StdError>> {
+    let status = sysfs::port_status(port.get())?.ok_or(Error::NotEnumerated(port.get()))?;
+    let local = status
+        .local_bus_id
+        .ok_or(Error::NotEnumerated(port.get()))?;
+    let number = |name: &str, radix: u32| -> Result<u16, Box<dyn StdError>> {
+        let value = sysfs::usb_device_attr(&local, name)?;
+        let value = value.ok_or(Error::NotEnumerated(port.get()))?;
+        u16::from_str_radix(value.trim(), radix).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{name}: {value}")).into()
+        })
+    };
+
+    // Lay the device out as an import reply would, so it
+    // decodes into the same `UsbDevice`.
+    if bus_id.len() >= usbip_core::SYSFS_BUS_ID_SIZE {
+        return Err(net::Error::FieldTooLong("bus id").into());
+    }
+    let mut bus_id_field = [0; usbip_core::SYSFS_BUS_ID_SIZE];
+    bus_id_field[..bus_id.len()].copy_from_slice(bus_id.as_bytes());
+    let mut bytes = vec![0; usbip_core::SYSFS_PATH_MAX];
+    bytes.extend(bus_id_field);
+    bytes.extend((status.devid >> 16).to_be_bytes());
+    bytes.extend((status.devid & 0xffff).to_be_bytes());
+    bytes.extend(status.speed.to_be_bytes());
+    for name in ["idVendor", "idProduct", "bcdDevice"] {
+        bytes.extend(number(name, 16)?.to_be_bytes());
+    }
+    for (name, radix) in [
+        ("bDeviceClass", 16),
+        ("bDeviceSubClass", 16),
+        ("bDeviceProtocol", 16),
+        ("bConfigurationValue", 10),
+        ("bNumConfigurations", 10),
+        ("bNumInterfaces", 10),
+    ] {
+        bytes.push(number(name, radix)? as u8);
+    }
+
+    Ok(AttachedDevice {
+        udev: net::parse_device(&bytes)?,
+        port,
+        server_version: version,
+    })
+}
+
+/// Attaches `bus_id` from `host` like [`attach_device`],
+/// unless it is already attached from that same server,
+/// in which case the existing attachment is returned.
+///
+/// This suits "ensure attached" workflows that may run
+/// again while the device is still attached. An existing
+/// attachment is found from the state files (see
+/// [`attachments`]), and must match the server's host and
+/// TCP port as well as the bus id, so a device with the
+/// same bus id from another server is never returned.
+/// Hosts are compared as written, not resolved, so pass
+/// `host` the same way each time.
+///
+/// The import reply is not kept after attaching, so for
+/// an existing attachment the returned `udev` is rebuilt
+/// from the kernel's copy of the device. Its remote sysfs
+/// path is not known and is left empty.
+///
+/// # Errors
+/// See [`attachments`] and [`attach_device`].
+pub fn attach_device_idempotent(
+    host: &str,
+    bus_id: &str,
+) -> Result<AttachedDevice, Box<dyn StdError>> {
+    let endpoint = net::parse_endpoint(host)?;
+    let existing = attachments()?
+        .into_iter()
+        .find(|info| info.is_to(&endpoint, bus_id));
+    match existing {
+        Some(info) => {
+            let version = info
+                .version()
+                .unwrap_or(net::ProtocolVersion::default().get());
+            recorded_device(info.port(), bus_id, version)
+        }
+        None => attach_device(host, bus_id),
+    }
+}
+
+/// Attaches `udev`, as returned by [`list_exported_devices`]
+/// for `host`, using the default [`AttachOptions`].
+///
+/// See [`attach_device_from_with`].
+pub fn attach_device_from(
+    host: &str,
+    udev: &UsbDevice,
+) -> Result<AttachedDevice, Box<dyn StdError>> {
+    attach_device_from_with(host, udev, &AttachOptions::default())
+}
+
+/// Attaches `udev`, as returned by listing the devices of
+/// `host`, like [`attach_device_with`].
+///
+/// The bus id is taken from `udev` as is, so the device
+/// is imported under the same name the server listed it
+/// with. Pass the `options` the list was made with, for
+/// example the same `version`, to attach the same way.
+///
+/// # Errors
+/// See [`attach_device_with`].
+pub fn attach_device_from_with(
+    host: &str,
+    udev: &UsbDevice,
+    options: &AttachOptions,
+) -> Result<AttachedDevice, Box<dyn StdError>> {
+    attach_device_with(host, &buffer_to_string(udev.bus_id()), options)
+}
+
+/// Attaches the device `host` exports with the given
+/// `devid`.
+///
+/// See [`bus_id_for_devid`] and [`attach_device`].
+pub fn attach_by_devid(host: &str, devid: u32) -> Result<AttachedDevice, Box<dyn StdError>> {
+    let bus_id = bus_id_for_devid(host, devid)?;
+    attach_device(host, &bus_id)
+}
+
+/// Attaches the device `host` exports that is selected
+/// by `matcher`.
+///
+/// Serial numbers are not part of the devlist reply, so
+/// devices cannot be selected by serial before they are
+/// attached; see [`DeviceMatch`] for how identical models
+/// are told apart instead.
+///
+/// # Errors
+/// This function fails if no exported device matches,
+/// or for any reason listed in [`list_exported_devices`]
+/// and [`attach_device`].
+pub fn attach_matching(
+    host: &str,
+    matcher: &DeviceMatch,
+) -> Result<AttachedDevice, Box<dyn StdError>> {
+    let devices = list_exported_devices(host)?;
+    let udev = matcher
+        .select(&devices)
+        .ok_or(Error::NoMatchingDevice(*matcher))?;
+    attach_device_from(host, udev)
+}
+
+/// Returns the total number of vhci ports on this
+/// system, whether they are in use or not.
+///
+/// The count is read once per driver handle, so a module
+/// reloaded with another number of ports is picked up by
+/// the next call. Code already holding a `vhci::Driver`
+/// should ask it with `Driver::num_ports` instead.
+///
+/// # Errors
+/// This function fails if the vhci driver is not loaded,
+/// or its `nports` attribute could not be parsed.
+pub fn max_ports() -> Result<usize, vhci::Error> {
+    Ok(vhci::Driver::try_open()?.num_ports())
+}
+
+/// Measures the round-trip time to `host`, to pick the
+/// closest of several servers before attaching.
+///
+/// Only the exchange of a devlist request header and the
+/// reply header is timed, not name resolution or setting
+/// up the connection. This opens one connection to `host`
+/// and closes it afterwards; listing devices does not
+/// change any state on the server.
+///
+/// # Errors
+/// This function can fail if `host` could not be parsed
+/// or reached, or the exchange failed.
+pub fn measure_rtt(host: &str) -> Result<Duration, Box<dyn StdError>> {
+    let endpoint = net::parse_endpoint(host)?;
+    let mut socket = net::connect(&endpoint)?;
+
+    let start = Instant::now();
+    net::negotiate_version(&mut socket)?;
+    Ok(start.elapsed())
+}
+
+/// Returns `true` if the local kernel can attach USB/IP
+/// devices at all, meaning the vhci-hcd driver is built
+/// and loaded.
+///
+/// This only checks that the driver's sysfs tree exists,
+/// so it needs no privileges and is cheap enough to call
+/// before showing any USB/IP UI. The driver may still fail
+/// to open, for example without permission to its sysfs
+/// attributes.
+pub fn is_supported() -> bool {
+    sysfs::vhci_present()
+}
+
+/// Probes what the server at `host` supports.
+///
+/// The protocol cannot advertise features, so this only
+/// asks the server for its version (see
+/// [`net::negotiate_version`]) and infers the rest, as
+/// described on [`net::Capabilities`].
+///
+/// # Errors
+/// This function fails if `host` could not be parsed or
+/// reached, or did not reply with a USB/IP header.
+pub fn server_capabilities(host: &str) -> Result<net::Capabilities, Box<dyn StdError>> {
+    let endpoint = net::parse_endpoint(host)?;
+    let mut socket = net::connect(&endpoint)?;
+    let version = net::negotiate_version(&mut socket)?;
+    Ok(net::Capabilities::from_version(version))
+}
+
+/// Waits until `host` exports `bus_id`, then attaches it.
+///
+/// Only the device list is polled, every `poll`, so the
+/// server never sees import requests for a device that
+/// is not plugged in yet. No connection is held open
+/// between polls, so a server that goes away fails the
+/// next poll instead of leaving this waiting.
+///
+/// # Errors
+/// This function fails with `Error::TimedOut` if the
+/// device was not listed within `timeout`, or for any
+/// reason listed in [`list_exported_devices`] and
+/// [`attach_device`].
+pub fn attach_when_available(
+    host: &str,
+    bus_id: &str,
+    poll: Duration,
+    timeout: Duration,
+) -> Result<AttachedDevice, Box<dyn StdError>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let listed = list_exported_devices(host)?
+            .iter()
+            .any(|udev| buffer_to_string(udev.bus_id()) == bus_id);
+        if listed {
+            return attach_device(host, bus_id);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::TimedOut.into());
+        }
+        thread::sleep(poll.min(deadline - now));
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod asynchronous {
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    use futures_util::stream::{self, Stream};
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::TcpStream,
+        task, time,
+    };
+    use usbip_core::{net::Status, UsbDevice};
+
+    use super::{AttachOptions, AttachedDevice, Error};
+    use crate::{buffer_to_string, device::UsbDeviceExt, from_cstr_like, net, protocol};
+
+    enum State<S> {
+        Request(S, net::ProtocolVersion),
+        Reading(S, u32),
+        Done,
+    }
+
+    fn is_cancelled(cancel: &Option<Arc<AtomicBool>>) -> bool {
+        cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    /// Sends a devlist request and reads the reply up to
+    /// its device count.
+    async fn request<S>(socket: &mut S, version: net::ProtocolVersion) -> crate::Result<u32>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let request = net::OpCommon::request(protocol::OP_REQ_DEVLIST).with_version(version);
+        socket.write_all(&net::encode(&request)?).await?;
+
+        let mut header = [0; net::HEADER_SIZE];
+        socket.read_exact(&mut header).await?;
+        match net::parse_header(&header)?.validate(protocol::OP_REP_DEVLIST, version)? {
+            Status::Success => {}
+            status => return Err(Error::DevlistFailed(status).into()),
+        }
+
+        let mut reply = [0; net::DEVLIST_REPLY_SIZE];
+        socket.read_exact(&mut reply).await?;
+        Ok(net::parse_devlist_reply(&reply)?.ndev)
+    }
+
+    /// Reads the next device and skips its interfaces.
+    async fn read_device<S>(socket: &mut S) -> crate::Result<UsbDevice>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut device = [0; net::DEVICE_SIZE];
+        socket.read_exact(&mut device).await?;
+        let udev = net::parse_device(&device)?;
+        net::check_device(&udev)?;
+
+        let mut interfaces = vec![0; net::INTERFACE_SIZE * usize::from(udev.b_num_interfaces())];
+        socket.read_exact(&mut interfaces).await?;
+        Ok(udev)
+    }
+
+    /// Reads devices until one passes validation, skipping
+    /// the others with a warning like [`super::Devlist`].
+    async fn next<S>(
+        mut socket: S,
+        mut remaining: u32,
+        cancel: &Option<Arc<AtomicBool>>,
+    ) -> Option<(crate::Result<UsbDevice>, State<S>)>
+    where
+        S: AsyncRead + Unpin,
+    {
+        loop {
+            if remaining == 0 || is_cancelled(cancel) {
+                return None;
+            }
+            remaining -= 1;
+
+            let udev = match read_device(&mut socket).await {
+                Ok(udev) => udev,
+                Err(err) => return Some((Err(err), State::Done)),
+            };
+            match udev.validate() {
+                Ok(()) => return Some((Ok(udev), State::Reading(socket, remaining))),
+                Err(err) => log::warn!("skipping devlist entry {}: {err}", udev.redacted()),
+            }
+        }
+    }
+
+    fn stream<S>(
+        socket: S,
+        version: net::ProtocolVersion,
+        cancel: Option<Arc<AtomicBool>>,
+    ) -> impl Stream<Item = crate::Result<UsbDevice>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let state = State::Request(socket, version);
+        stream::unfold((state, cancel), |(state, cancel)| async move {
+            let next = match state {
+                State::Request(mut socket, version) => match request(&mut socket, version).await {
+                    Ok(ndev) => next(socket, ndev, &cancel).await,
+                    Err(err) => Some((Err(err), State::Done)),
+                },
+                State::Reading(socket, remaining) => next(socket, remaining, &cancel).await,
+                State::Done => None,
+            };
+            next.map(|(item, state)| (item, (state, cancel)))
+        })
+    }
+
+    /// Requests the list of exported devices over `socket`,
+    /// yielding each device as soon as it arrives.
+    ///
+    /// This is the async counterpart of [`super::devlist`].
+    /// Exactly as many devices as the reply announces are
+    /// read. A failed request or a malformed device is
+    /// yielded as an error, which ends the stream. Devices
+    /// that fail [`UsbDeviceExt::validate`] are skipped with
+    /// a warning, as [`super::Devlist`] does.
+    ///
+    /// Dropping the stream cancels it and closes the
+    /// connection; see [`devlist_stream_async_cancellable`]
+    /// to cancel it from elsewhere.
+    pub fn devlist_stream_async<S>(
+        socket: S,
+        version: net::ProtocolVersion,
+    ) -> impl Stream<Item = crate::Result<UsbDevice>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream(socket, version, None)
+    }
+
+    /// Streams the devices exported over `socket` like
+    /// [`devlist_stream_async`], ending early once `cancel`
+    /// is set.
+    ///
+    /// The flag is checked between devices. The socket is
+    /// dropped as soon as the stream ends, with the rest
+    /// of the reply unread.
+    pub fn devlist_stream_async_cancellable<S>(
+        socket: S,
+        version: net::ProtocolVersion,
+        cancel: Arc<AtomicBool>,
+    ) -> impl Stream<Item = crate::Result<UsbDevice>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream(socket, version, Some(cancel))
+    }
+
+    /// Runs the import handshake for `bus_id`, returning
+    /// the device the server replied with and the protocol
+    /// version of the reply.
+    async fn import<S>(
+        socket: &mut S,
+        bus_id: &str,
+        version: net::ProtocolVersion,
+    ) -> crate::Result<(UsbDevice, u16)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let header = net::OpCommon::request(protocol::OP_REQ_IMPORT).with_version(version);
+        let request = net::OpImportRequest {
+            bus_id: from_cstr_like(bus_id).ok_or(net::Error::FieldTooLong("bus id"))?,
+        };
+        let mut bytes = net::encode(&header)?;
+        bytes.extend(net::encode(&request)?);
+        socket.write_all(&bytes).await?;
+
+        let mut header = [0; net::HEADER_SIZE];
+        socket.read_exact(&mut header).await?;
+        let header = net::parse_header(&header)?;
+        match header.validate(protocol::OP_REP_IMPORT, version)? {
+            Status::Success => {}
+            Status::DevBusy => return Err(Error::DeviceBusy(bus_id.into()).into()),
+            status => return Err(Error::ImportFailed(status).into()),
+        }
+
+        let mut reply = [0; net::DEVICE_SIZE];
+        socket.read_exact(&mut reply).await?;
+        let udev = net::parse_import_reply(&reply)?.udev;
+        net::check_device(&udev)?;
+        let received = buffer_to_string(udev.bus_id());
+        if received != bus_id {
+            return Err(net::Error::BusIdMismatch(received.into()).into());
+        }
+        Ok((udev, header.version))
+    }
+
+    /// Imports the device exported as `bus_id` by `host`
+    /// and attaches it to a free vhci port, like
+    /// [`super::attach_device_with`], without blocking the
+    /// executor.
+    ///
+    /// Connecting and the import handshake run on the
+    /// runtime as ordinary async I/O, bounded by
+    /// `options.deadline` if one is set. Handing the socket
+    /// to the vhci driver, recording the state file and
+    /// waiting for the port to be used are sysfs and
+    /// filesystem operations with no async interface, so
+    /// they are offloaded to the blocking pool with
+    /// `tokio::task::spawn_blocking`.
+    ///
+    /// # Errors
+    /// See [`super::attach_device_with`].
+    pub async fn attach_device_async(
+        host: &str,
+        bus_id: &str,
+        options: &AttachOptions,
+    ) -> crate::Result<AttachedDevice> {
+        if !options.access.is_supported() {
+            return Err(Error::SharedNotSupported.into());
+        }
+
+        let endpoint = net::parse_endpoint(host)?;
+        let handshake = async {
+            let mut socket = match endpoint.scoped_addr()? {
+                Some(addr) => TcpStream::connect(addr).await?,
+                None => TcpStream::connect((endpoint.host(), endpoint.port())).await?,
+            };
+            let (udev, server_version) = import(&mut socket, bus_id, options.version).await?;
+            crate::Result::Ok((socket, udev, server_version))
+        };
+        let (socket, udev, server_version) = match super::time_left(options.deadline)? {
+            Some(left) => time::timeout(left, handshake)
+                .await
+                .map_err(|_| Error::DeadlineExceeded)??,
+            None => handshake.await?,
+        };
+
+        // `into_std` leaves the socket non-blocking, which
+        // the driver's threads do not expect.
+        let socket = socket.into_std()?;
+        socket.set_nonblocking(false)?;
+        socket.set_nodelay(true)?;
+        if !options.no_keepalive {
+            net::enable_keepalive(&socket)?;
+        }
+        let bus_id = bus_id.to_owned();
+        let options = options.clone();
+        task::spawn_blocking(move || -> crate::Result<_> {
+            let port = super::import_device(&socket, udev.devid(), udev.speed_kind(), &options)?;
+            let result = AttachedDevice {
+                udev,
+                port,
+                server_version,
+            };
+            Ok(super::record_attached(
+                result, &endpoint, &bus_id, &options,
+            )?)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, DeviceBytes, Scripted};
+
+    #[test]
+    fn devlist_names_an_overlong_path() {
+        let path = "a".repeat(usbip_core::SYSFS_PATH_MAX);
+        let reply = test_support::devlist_reply(&[DeviceBytes {
+            path: &path,
+            ..DeviceBytes::default()
+        }]);
+        let socket = Scripted::new(reply);
+
+        let err = devlist(socket, net::ProtocolVersion::CURRENT)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(net::Error::FieldTooLong("path"))
+        ));
+    }
+
+    #[test]
+    fn devlist_failure_header_is_not_read_past() {
+        // The server sends only a header and closes, so
+        // reading a body would fail with an EOF instead.
+        let reply = test_support::header(protocol::OP_REP_DEVLIST, Status::NoDev.to_raw());
+        let socket = Scripted::new(reply);
+
+        let err = devlist(socket, net::ProtocolVersion::CURRENT)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::DevlistFailed(Status::NoDev))
+        ));
+    }
+
+    #[test]
+    fn broken_connection_ends_the_wait_for_a_port() {
+        assert!(!is_used(3, Some(DeviceStatus::PortInitializing)).unwrap());
+        assert!(is_used(3, Some(DeviceStatus::DeviceUsed)).unwrap());
+        for status in [DeviceStatus::PortAvailable, DeviceStatus::DeviceError] {
+            assert!(matches!(
+                is_used(3, Some(status)),
+                Err(Error::ConnectionClosed(3))
+            ));
+        }
+    }
+
+    #[test]
+    fn server_going_away_ends_the_wait_for_a_device() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        // Lists no devices once, then closes the next
+        // connection without replying, like a server that
+        // died between polls.
+        thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut request = [0; 8];
+            conn.read_exact(&mut request).unwrap();
+            let mut reply = test_support::header(protocol::OP_REP_DEVLIST, 0);
+            reply.extend(0u32.to_be_bytes());
+            conn.write_all(&reply).unwrap();
+            drop(conn);
+            drop(listener.accept());
+        });
+
+        let err = attach_when_available(
+            &host,
+            "1-1",
+            Duration::from_millis(10),
+            Duration::from_secs(30),
+        )
+        .unwrap_err();
+        assert!(!matches!(err.downcast_ref(), Some(Error::TimedOut)));
+    }
+
+    /// An `attach` attribute on which every write fails
+    /// like the driver's with `errno`.
+    struct FailingAttr(i32);
+
+    impl io::Write for FailingAttr {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::from_raw_os_error(self.0))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn attach_with_errno(errno: i32) -> Box<dyn StdError> {
+        sysfs::write_attach(FailingAttr(errno), 2, 5, 0x0001_0002, "3")
+            .map_err(|err| attach_write_error(err, 2))
+            .unwrap_err()
+    }
+
+    #[test]
+    fn attach_errnos_map_to_their_variants() {
+        let err = attach_with_errno(libc::EINVAL);
+        assert!(matches!(err.downcast_ref(), Some(Error::AttachRejected(2))));
+        assert!(err.to_string().contains("devid or speed is invalid"));
+
+        let err = attach_with_errno(libc::EBUSY);
+        assert!(matches!(err.downcast_ref(), Some(Error::PortBusy(2))));
+        assert!(err.to_string().contains("already in use"));
+
+        let err = attach_with_errno(libc::ENODEV);
+        assert!(matches!(err.downcast_ref(), Some(Error::NoHub(2))));
+        assert!(err.to_string().contains("check that vhci-hcd is loaded"));
+    }
+
+    #[test]
+    fn other_attach_errnos_stay_io_errors() {
+        let err = attach_with_errno(libc::EACCES);
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+    }
+
+    #[test]
+    fn devlist_reads_past_every_interface_record() {
+        let reply = test_support::devlist_reply(&[
+            DeviceBytes {
+                bus_id: "1-1",
+                num_interfaces: 3,
+                ..DeviceBytes::default()
+            },
+            DeviceBytes {
+                bus_id: "1-2",
+                ..DeviceBytes::default()
+            },
+        ]);
+
+        let bus_ids: Vec<_> = devlist(Scripted::new(reply), net::ProtocolVersion::CURRENT)
+            .unwrap()
+            .map(|udev| buffer_to_string(udev.unwrap().bus_id()).into_owned())
+            .collect();
+        assert_eq!(bus_ids, ["1-1", "1-2"]);
+    }
+
+    #[test]
+    fn import_names_an_overlong_path() {
+        let path = "a".repeat(usbip_core::SYSFS_PATH_MAX);
+        let mut reply = test_support::header(protocol::OP_REP_IMPORT, 0);
+        reply.extend(
+            DeviceBytes {
+                path: &path,
+                ..DeviceBytes::default()
+            }
+            .encode(),
+        );
+        let mut socket = Scripted::new(reply);
+
+        let err = request_import(&mut socket, "1-1", &AttachOptions::default()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(net::Error::FieldTooLong("path"))
+        ));
+    }
+
+    #[test]
+    fn import_failure_header_is_not_read_past() {
+        // The server sends only a header and closes, so
+        // reading a body would fail with an EOF instead.
+        let reply = test_support::header(protocol::OP_REP_IMPORT, Status::NoDev.to_raw());
+        let mut socket = Scripted::new(reply);
+
+        let err = request_import(&mut socket, "1-1", &AttachOptions::default()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::ImportFailed(Status::NoDev))
+        ));
+    }
+
+    #[test]
+    fn import_of_a_taken_device_is_busy() {
+        let reply = test_support::header(protocol::OP_REP_IMPORT, Status::DevBusy.to_raw());
+        let mut socket = Scripted::new(reply);
+
+        let err = request_import(&mut socket, "1-1", &AttachOptions::default()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::DeviceBusy(bus_id)) if &**bus_id == "1-1"
+        ));
+    }
+
+    #[test]
+    fn import_of_an_overlong_bus_id_sends_nothing() {
+        let bus_id = "1".repeat(usbip_core::SYSFS_BUS_ID_SIZE);
+        let mut socket = Scripted::new(Vec::new());
+
+        let err = request_import(&mut socket, &bus_id, &AttachOptions::default()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(net::Error::FieldTooLong("bus id"))
+        ));
+        assert!(socket.sent.is_empty());
+    }
+
+    #[test]
+    fn devlist_names_an_overlong_bus_id() {
+        let bus_id = "1".repeat(usbip_core::SYSFS_BUS_ID_SIZE);
+        let reply = test_support::devlist_reply(&[DeviceBytes {
+            bus_id: &bus_id,
+            ..DeviceBytes::default()
+        }]);
+
+        let err = devlist(Scripted::new(reply), net::ProtocolVersion::CURRENT)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(net::Error::FieldTooLong("bus id"))
+        ));
+    }
+
+    #[test]
+    fn bytes_after_a_failure_header_are_left_unread() {
+        let trailing = b"device is not exported\0";
+        let mut reply = test_support::header(protocol::OP_REP_IMPORT, Status::Failed.to_raw());
+        reply.extend(trailing);
+        let mut socket = Scripted::new(reply);
+
+        let err = request_import(&mut socket, "1-1", &AttachOptions::default()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::ImportFailed(Status::Failed))
+        ));
+        assert_eq!(socket.unread(), trailing.len());
+    }
+
+    #[test]
+    fn devlist_failure_ignores_a_trailing_body() {
+        let mut reply = test_support::header(protocol::OP_REP_DEVLIST, Status::Failed.to_raw());
+        reply.extend([0; net::DEVLIST_REPLY_SIZE]);
+
+        let Err(err) = devlist(Scripted::new(reply), net::ProtocolVersion::CURRENT) else {
+            panic!("a failed devlist was read as a success");
+        };
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::DevlistFailed(Status::Failed))
+        ));
+    }
+
+    /// Returns a connected loopback socket and its server
+    /// end, which has already sent `reply`.
+    fn loopback_import(reply: &[u8]) -> (TcpStream, TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server.write_all(reply).unwrap();
+        (socket, server)
+    }
+
+    #[test]
+    fn timeouts_are_cleared_after_a_bounded_handshake() {
+        let mut reply = test_support::header(protocol::OP_REP_IMPORT, 0);
+        reply.extend(DeviceBytes::default().encode());
+        let (socket, _server) = loopback_import(&reply);
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let (udev, _) =
+            request_import_until(&socket, "1-1", &AttachOptions::default(), deadline).unwrap();
+        assert_eq!(buffer_to_string(udev.bus_id()), "1-1");
+        assert_eq!(socket.read_timeout().unwrap(), None);
+        assert_eq!(socket.write_timeout().unwrap(), None);
+    }
+
+    #[test]
+    fn timeouts_are_cleared_after_a_failed_handshake() {
+        let reply = test_support::header(protocol::OP_REP_IMPORT, Status::NoDev.to_raw());
+        let (socket, _server) = loopback_import(&reply);
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let err =
+            request_import_until(&socket, "1-1", &AttachOptions::default(), deadline).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::ImportFailed(Status::NoDev))
+        ));
+        assert_eq!(socket.read_timeout().unwrap(), None);
+        assert_eq!(socket.write_timeout().unwrap(), None);
+    }
+
+    #[test]
+    fn port_is_owned_once_its_state_is_written() {
+        let port = Port::from_driver(250);
+        take_port_with(port, || Ok(()), |_| panic!("port detached")).unwrap();
+        assert!(owned_ports().contains(&port));
+        disown(port.get());
+    }
+
+    #[test]
+    fn port_is_detached_if_its_state_cannot_be_written() {
+        let port = Port::from_driver(251);
+        let mut detached = None;
+        let err = take_port_with(
+            port,
+            || Err(io::Error::from_raw_os_error(libc::ENOSPC)),
+            |port| {
+                detached = Some(port);
+                Ok(())
+            },
+        )
+        .unwrap_err();
+
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(detached, Some(port));
+        assert!(!owned_ports().contains(&port));
+    }
+
+    /// Starts a relay for `port` over a socket pair standing
+    /// in for the transport, returning the driver's end and
+    /// the server's end.
+    fn relay_pair(port: u8) -> (UnixStream, UnixStream) {
+        let (kernel_end, relay_end) = UnixStream::pair().unwrap();
+        let (transport, server) = UnixStream::pair().unwrap();
+        for socket in [&kernel_end, &server] {
+            socket
+                .set_read_timeout(Some(Duration::from_secs(30)))
+                .unwrap();
+        }
+        let (reader, writer) = net::Split::split(transport).unwrap();
+        spawn_relay::<UnixStream>(port, reader, writer, relay_end).unwrap();
+        (kernel_end, server)
+    }
+
+    /// Waits for the relay of `port` to be forgotten.
+    fn wait_for_relay_end(port: u8) {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while relays().contains_key(&port) {
+            assert!(
+                Instant::now() < deadline,
+                "relay for port {port} still running"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn read_to_end(mut socket: &UnixStream) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        socket.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn relay_copies_both_ways_until_the_driver_closes() {
+        let (mut kernel_end, mut server) = relay_pair(240);
+
+        kernel_end.write_all(b"submit").unwrap();
+        let mut buf = [0; 6];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"submit");
+
+        server.write_all(b"return").unwrap();
+        kernel_end.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"return");
+
+        kernel_end.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(read_to_end(&server), b"");
+        wait_for_relay_end(240);
+    }
+
+    #[test]
+    fn relay_closes_the_driver_end_when_the_server_closes() {
+        let (kernel_end, server) = relay_pair(241);
+
+        drop(server);
+        assert_eq!(read_to_end(&kernel_end), b"");
+        wait_for_relay_end(241);
+    }
+
+    #[test]
+    fn first_relay_stats_count_the_bytes_without_rates() {
+        const N: usize = 4096;
+        let (mut kernel_end, mut server) = relay_pair(242);
+        let mut buf = [0; N];
+        kernel_end.write_all(&[1; N]).unwrap();
+        server.read_exact(&mut buf).unwrap();
+        server.write_all(&[2; N]).unwrap();
+        kernel_end.read_exact(&mut buf).unwrap();
+
+        // A byte is counted just after it was written, so
+        // wait for the counters to catch up without taking
+        // the first sample.
+        let counters = relays().get(&242).unwrap().clone();
+        while counters.bytes_in.load(Ordering::Relaxed) < N as u64
+            || counters.bytes_out.load(Ordering::Relaxed) < N as u64
+        {
+            thread::yield_now();
+        }
+
+        let stats = relay_stats(Port::from_driver(242)).unwrap();
+        assert_eq!(stats.bytes_in, N as u64);
+        assert_eq!(stats.bytes_out, N as u64);
+        assert_eq!(stats.rate_in, None);
+        assert_eq!(stats.rate_out, None);
+
+        drop(kernel_end);
+        wait_for_relay_end(242);
+        assert_eq!(relay_stats(Port::from_driver(242)), None);
+    }
+
+    fn attached_to(port: u8) -> AttachedDevice {
+        AttachedDevice {
+            udev: DeviceBytes::default().parse(),
+            port: Port::from_driver(port),
+            server_version: net::VERSION,
+        }
+    }
+
+    fn batch_requests(count: u8) -> Vec<(String, String)> {
+        (0..count)
+            .map(|i| ("10.0.0.2".to_owned(), format!("1-{i}")))
+            .collect()
+    }
+
+    /// Returns the port a request's bus id, `1-<port>`,
+    /// asks for.
+    fn requested_port(bus_id: &str) -> u8 {
+        bus_id.strip_prefix("1-").unwrap().parse().unwrap()
+    }
+
+    fn is_cancelled(result: &crate::Result<AttachedDevice>) -> bool {
+        matches!(result, Err(crate::Error::Attach(Error::Cancelled)))
+    }
+
+    #[test]
+    fn attach_batch_keeps_results_in_request_order() {
+        let requests = batch_requests(8);
+        let results = attach_batch_with(
+            &requests,
+            3,
+            &AtomicBool::new(false),
+            |_, bus_id| {
+                let port = requested_port(bus_id);
+                // Finish out of order.
+                thread::sleep(Duration::from_millis(u64::from(8 - port) * 5));
+                if port.is_multiple_of(3) {
+                    Err(Error::NoFreePort(Speed::High).into())
+                } else {
+                    Ok(attached_to(port))
+                }
+            },
+            |port| panic!("port {port} detached without a cancellation"),
+        );
+
+        assert_eq!(results.len(), requests.len());
+        for (i, result) in (0..).zip(&results) {
+            match result {
+                Ok(attached) => assert_eq!(attached.port.get(), i),
+                Err(err) => {
+                    assert!(i.is_multiple_of(3), "request {i}: {err}");
+                    assert!(matches!(err, crate::Error::Attach(Error::NoFreePort(_))));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cancelling_a_batch_rolls_back_the_attach_in_flight() {
+        let requests = batch_requests(4);
+        let cancel = AtomicBool::new(false);
+        let detached = std::sync::Mutex::new(Vec::new());
+        let results = attach_batch_with(
+            &requests,
+            1,
+            &cancel,
+            |_, bus_id| {
+                let port = requested_port(bus_id);
+                if port == 1 {
+                    // Cancelled while this attach is under way.
+                    cancel.store(true, Ordering::Relaxed);
+                }
+                Ok(attached_to(port))
+            },
+            |port| {
+                detached.lock().unwrap().push(port.get());
+                Ok(())
+            },
+        );
+
+        assert_eq!(*detached.lock().unwrap(), [0, 1]);
+        assert!(results.iter().all(is_cancelled), "{results:?}");
+    }
+
+    #[test]
+    fn failed_rollback_keeps_the_device_attached() {
+        let requests = batch_requests(3);
+        let cancel = AtomicBool::new(false);
+        let results = attach_batch_with(
+            &requests,
+            1,
+            &cancel,
+            |_, bus_id| {
+                let port = requested_port(bus_id);
+                if port == 1 {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+                Ok(attached_to(port))
+            },
+            |port| {
+                if port.get() == 0 {
+                    Err(crate::detach::Error::TimedOut(port.get()).into())
+                } else {
+                    Ok(())
+                }
+            },
+        );
+
+        assert!(matches!(&results[0], Ok(attached) if attached.port.get() == 0));
+        assert!(is_cancelled(&results[1]));
+        assert!(is_cancelled(&results[2]));
+    }
+
+    #[test]
+    fn attach_batch_of_no_requests_is_empty() {
+        let results = attach_batch_with(
+            &[],
+            4,
+            &AtomicBool::new(false),
+            |_, _| unreachable!(),
+            |_| unreachable!(),
+        );
+        assert!(results.is_empty());
+    }
+
+    fn vhci_ports() -> Vec<vhci::ImportedDevice> {
+        use vhci::HubSpeed::{High, Super};
+        [
+            (0, DeviceStatus::DeviceUsed, High),
+            (1, DeviceStatus::PortAvailable, High),
+            (2, DeviceStatus::PortInitializing, High),
+            (3, DeviceStatus::DeviceError, High),
+            (4, DeviceStatus::DeviceUsed, Super),
+            (5, DeviceStatus::PortAvailable, Super),
+        ]
+        .into_iter()
+        .map(|(port, status, hub)| vhci::ImportedDevice::new(port, status, hub))
+        .collect()
+    }
+
+    fn port_numbers(idevs: impl Iterator<Item = vhci::ImportedDevice>) -> Vec<u8> {
+        idevs.map(|idev| idev.port()).collect()
+    }
+
+    #[test]
+    fn with_status_keeps_only_that_status() {
+        let used = vhci_ports()
+            .into_iter()
+            .with_status(DeviceStatus::DeviceUsed);
+        assert_eq!(port_numbers(used), [0, 4]);
+        let free = vhci_ports()
+            .into_iter()
+            .with_status(DeviceStatus::PortAvailable);
+        assert_eq!(port_numbers(free), [1, 5]);
+        let exported = vhci_ports().into_iter().with_status(DeviceStatus::DevInUse);
+        assert!(port_numbers(exported).is_empty());
+    }
+
+    #[test]
+    fn in_use_keeps_every_port_with_a_device() {
+        assert_eq!(
+            port_numbers(vhci_ports().into_iter().in_use()),
+            [0, 2, 3, 4]
+        );
+    }
+
+    /// Lists one device per call to `host`, counting the
+    /// calls in `listed`.
+    fn counting_list<'a>(
+        listed: &'a std::cell::Cell<u32>,
+    ) -> impl FnOnce(&str) -> Result<Vec<UsbDevice>, Box<dyn StdError>> + 'a {
+        move |_| {
+            listed.set(listed.get() + 1);
+            Ok(vec![DeviceBytes::default().parse()])
+        }
+    }
+
+    #[test]
+    fn devlist_cache_lists_again_only_after_the_ttl() {
+        let ttl = Duration::from_secs(10);
+        let mut cache = DevlistCache::new(ttl);
+        let listed = std::cell::Cell::new(0);
+        let start = Instant::now();
+
+        assert_eq!(
+            cache
+                .get_with("a", start, counting_list(&listed))
+                .unwrap()
+                .len(),
+            1
+        );
+        cache
+            .get_with(
+                "a",
+                start + ttl - Duration::from_millis(1),
+                counting_list(&listed),
+            )
+            .unwrap();
+        assert_eq!(listed.get(), 1, "listed again within the ttl");
+
+        cache
+            .get_with("a", start + ttl, counting_list(&listed))
+            .unwrap();
+        assert_eq!(listed.get(), 2);
+    }
+
+    #[test]
+    fn devlist_cache_keeps_hosts_apart() {
+        let mut cache = DevlistCache::new(Duration::from_secs(10));
+        let listed = std::cell::Cell::new(0);
+        let now = Instant::now();
+        cache.get_with("a", now, counting_list(&listed)).unwrap();
+        cache.get_with("b", now, counting_list(&listed)).unwrap();
+        cache.get_with("a", now, counting_list(&listed)).unwrap();
+        assert_eq!(listed.get(), 2);
+    }
+
+    #[test]
+    fn invalidated_host_is_listed_again() {
+        let mut cache = DevlistCache::new(Duration::from_secs(10));
+        let listed = std::cell::Cell::new(0);
+        let now = Instant::now();
+        cache.get_with("a", now, counting_list(&listed)).unwrap();
+        cache.get_with("b", now, counting_list(&listed)).unwrap();
+
+        cache.invalidate("a");
+        cache.get_with("a", now, counting_list(&listed)).unwrap();
+        cache.get_with("b", now, counting_list(&listed)).unwrap();
+        assert_eq!(listed.get(), 3, "only the invalidated host is listed again");
+    }
+
+    #[test]
+    fn failed_listing_drops_the_stale_entry() {
+        let ttl = Duration::from_secs(10);
+        let mut cache = DevlistCache::new(ttl);
+        let listed = std::cell::Cell::new(0);
+        let start = Instant::now();
+        cache.get_with("a", start, counting_list(&listed)).unwrap();
+
+        let err = cache
+            .get_with("a", start + ttl, |_| Err(Error::TimedOut.into()))
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::TimedOut)));
+        cache
+            .get_with("a", start + ttl, counting_list(&listed))
+            .unwrap();
+        assert_eq!(listed.get(), 2, "not listed again after the failure");
+    }
+}
@@ -0,0 +1,104 @@
+use std::borrow::Cow;
+
+use usbip_core::buffer::Buffer;
+
+/// Decodes a fixed-size, NUL-padded C string buffer,
+/// such as a bus id or sysfs path, into text.
+///
+/// Everything from the first NUL on is ignored, and
+/// invalid UTF-8 is replaced rather than rejected, so
+/// this never fails on what a server sends.
+pub fn buffer_to_string<const N: usize>(buf: &Buffer<N, i8>) -> Cow<'_, str> {
+    let bytes: &[i8] = buf.as_ref();
+    let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+
+    // SAFETY: `i8` and `u8` have the same size and alignment,
+    // and every bit pattern is valid for both.
+    let bytes = unsafe { std::slice::from_raw_parts(bytes.as_ptr().cast::<u8>(), len) };
+    String::from_utf8_lossy(bytes)
+}
+
+/// Returns `true` if `buf` holds a NUL within its
+/// capacity, as every C string field on the wire must.
+///
+/// A buffer filled to the brim means the sender had more
+/// to say than the field can hold.
+pub(crate) fn is_nul_terminated<const N: usize>(buf: &Buffer<N, i8>) -> bool {
+    let bytes: &[i8] = buf.as_ref();
+    bytes.contains(&0)
+}
+
+/// Encodes `s` into a fixed-size C string buffer, the
+/// way the C tools fill bus id and path fields.
+///
+/// The bytes of `s` are copied to the front of the buffer
+/// and every byte after them is zeroed, so the string is
+/// always NUL-terminated and nothing past it is left
+/// undefined. Returns `None` if `s` plus its NUL does not
+/// fit in `N` bytes, or if `s` itself contains a NUL.
+pub fn from_cstr_like<const N: usize>(s: &str) -> Option<Buffer<N, i8>> {
+    if s.len() >= N || s.as_bytes().contains(&0) {
+        return None;
+    }
+
+    let mut bytes = [0u8; N];
+    bytes[..s.len()].copy_from_slice(s.as_bytes());
+    Buffer::try_from(&bytes[..]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(bytes: &[u8]) -> Buffer<8, i8> {
+        Buffer::try_from(bytes).unwrap()
+    }
+
+    #[test]
+    fn trailing_nuls_are_trimmed() {
+        assert_eq!(buffer_to_string(&buffer(b"1-1")), "1-1");
+        assert_eq!(buffer_to_string(&buffer(b"1-1\0\0x")), "1-1");
+        assert_eq!(buffer_to_string(&buffer(b"")), "");
+    }
+
+    #[test]
+    fn full_buffer_is_read_to_the_end() {
+        assert_eq!(buffer_to_string(&buffer(b"12345678")), "12345678");
+    }
+
+    #[test]
+    fn negative_bytes_are_read_as_utf8() {
+        // "é" is 0xc3 0xa9, both negative as `i8`.
+        assert_eq!(buffer_to_string(&buffer("1-é".as_bytes())), "1-é");
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        assert_eq!(buffer_to_string(&buffer(b"1-\xff")), "1-\u{fffd}");
+    }
+
+    #[test]
+    fn from_cstr_like_zeroes_the_rest() {
+        let buf: Buffer<8, i8> = from_cstr_like("1-1").unwrap();
+        assert_eq!(
+            buf.as_ref(),
+            [b'1' as i8, b'-' as i8, b'1' as i8, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn from_cstr_like_keeps_room_for_the_nul() {
+        // 7 bytes plus the NUL fill the buffer exactly.
+        let buf: Buffer<8, i8> = from_cstr_like("1234567").unwrap();
+        assert_eq!(buffer_to_string(&buf), "1234567");
+        assert!(is_nul_terminated(&buf));
+
+        assert!(from_cstr_like::<8>("12345678").is_none());
+        assert!(from_cstr_like::<8>("123456789").is_none());
+    }
+
+    #[test]
+    fn from_cstr_like_rejects_inner_nuls() {
+        assert!(from_cstr_like::<8>("1\0-1").is_none());
+    }
+}
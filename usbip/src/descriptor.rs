@@ -0,0 +1,382 @@
+//! Device and configuration descriptors of attached
+//! devices, read back from the local sysfs.
+//!
+//! The import reply only carries part of the device
+//! descriptor: the C `usbip_usb_device` struct has
+//! `idVendor`, `idProduct`, `bcdDevice`, the class triple,
+//! `bConfigurationValue` and `bNumConfigurations` (and
+//! `bNumInterfaces`, which is not a device descriptor
+//! field), all available on `UsbDevice`. Fields such as
+//! `bcdUSB` and `bMaxPacketSize0` never cross the wire,
+//! so they can only be read here once the device is
+//! attached.
+
+use std::error::Error as StdError;
+
+use crate::{attach, sysfs, Port};
+
+pub use error::Error;
+
+mod error {
+    use std::fmt;
+
+    #[derive(Debug, Clone, Copy)]
+    pub enum Error {
+        Malformed { offset: usize },
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::Malformed { offset } => {
+                    write!(f, "malformed descriptor at byte {offset}")
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+const DEVICE: u8 = 0x01;
+const CONFIG: u8 = 0x02;
+const INTERFACE: u8 = 0x04;
+const ENDPOINT: u8 = 0x05;
+
+/// The device descriptor, with every field, unlike the
+/// subset in `UsbDevice`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    /// `bcdUSB`, the USB specification release the device
+    /// complies with, such as `0x0200`.
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// `bMaxPacketSize0`, the maximum packet size of
+    /// endpoint zero. This is an exponent of two for
+    /// SuperSpeed devices.
+    pub max_packet_size0: u8,
+    pub vendor: u16,
+    pub product: u16,
+    /// `bcdDevice`, the device release number.
+    pub device_version: u16,
+    /// Indices of the string descriptors, 0 if none.
+    pub manufacturer_index: u8,
+    pub product_index: u8,
+    pub serial_index: u8,
+    pub num_configurations: u8,
+}
+
+/// Parses the device descriptor at the start of raw
+/// descriptor bytes, in the layout of the sysfs
+/// `descriptors` attribute.
+///
+/// # Error
+/// This function returns `Error::Malformed` if `bytes`
+/// does not start with a whole device descriptor.
+pub fn parse_device(bytes: &[u8]) -> Result<DeviceDescriptor, Error> {
+    let desc = bytes
+        .get(..18)
+        .filter(|desc| usize::from(desc[0]) >= 18 && desc[1] == DEVICE)
+        .ok_or(Error::Malformed { offset: 0 })?;
+    let u16_at = |i: usize| u16::from_le_bytes([desc[i], desc[i + 1]]);
+
+    Ok(DeviceDescriptor {
+        usb_version: u16_at(2),
+        class: desc[4],
+        subclass: desc[5],
+        protocol: desc[6],
+        max_packet_size0: desc[7],
+        vendor: u16_at(8),
+        product: u16_at(10),
+        device_version: u16_at(12),
+        manufacturer_index: desc[14],
+        product_index: desc[15],
+        serial_index: desc[16],
+        num_configurations: desc[17],
+    })
+}
+
+/// A configuration descriptor, with the interfaces and
+/// endpoints that follow it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigDescriptor {
+    pub configuration_value: u8,
+    pub num_interfaces: u8,
+    pub attributes: u8,
+    /// Maximum power draw, in units of 2 mA (8 mA for
+    /// SuperSpeed devices).
+    pub max_power: u8,
+    /// Every interface descriptor, including alternate
+    /// settings.
+    pub interfaces: Vec<InterfaceDescriptor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alternate_setting: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+/// Parses the configuration descriptors out of raw
+/// descriptor bytes, in the layout of the sysfs
+/// `descriptors` attribute: the device descriptor, then
+/// each configuration with everything that follows it.
+///
+/// Descriptors other than configurations, interfaces and
+/// endpoints (the device descriptor, class-specific ones)
+/// are skipped.
+///
+/// # Error
+/// This function returns `Error::Malformed` if a
+/// descriptor's length runs past the end of `bytes` or is
+/// too short for its type, or an interface or endpoint
+/// comes before any configuration or interface.
+pub fn parse(bytes: &[u8]) -> Result<Vec<ConfigDescriptor>, Error> {
+    let mut configs: Vec<ConfigDescriptor> = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let malformed = Error::Malformed { offset };
+        let len = usize::from(bytes[offset]);
+        let desc = bytes
+            .get(offset..offset + len)
+            .filter(|desc| desc.len() >= 2)
+            .ok_or(malformed)?;
+
+        match desc[1] {
+            CONFIG if len >= 9 => configs.push(ConfigDescriptor {
+                num_interfaces: desc[4],
+                configuration_value: desc[5],
+                attributes: desc[7],
+                max_power: desc[8],
+                interfaces: Vec::new(),
+            }),
+            INTERFACE if len >= 9 => {
+                configs
+                    .last_mut()
+                    .ok_or(malformed)?
+                    .interfaces
+                    .push(InterfaceDescriptor {
+                        number: desc[2],
+                        alternate_setting: desc[3],
+                        class: desc[5],
+                        subclass: desc[6],
+                        protocol: desc[7],
+                        endpoints: Vec::new(),
+                    })
+            }
+            ENDPOINT if len >= 7 => configs
+                .last_mut()
+                .and_then(|config| config.interfaces.last_mut())
+                .ok_or(malformed)?
+                .endpoints
+                .push(EndpointDescriptor {
+                    address: desc[2],
+                    attributes: desc[3],
+                    max_packet_size: u16::from_le_bytes([desc[4], desc[5]]),
+                    interval: desc[6],
+                }),
+            CONFIG | INTERFACE | ENDPOINT => return Err(malformed),
+            _ => {}
+        }
+        offset += len;
+    }
+
+    Ok(configs)
+}
+
+/// Looks up the local bus id of the device on `port`.
+///
+/// # Error
+/// This function returns `attach::Error::NotEnumerated` if
+/// no device is set up on `port` yet.
+fn local_bus_id(port: Port) -> Result<String, Box<dyn StdError>> {
+    Ok(sysfs::local_bus_id(port.get())?.ok_or(attach::Error::NotEnumerated(port.get()))?)
+}
+
+/// Reads the device descriptor of the device attached to
+/// `port`, once the local kernel has set it up (see
+/// [`attach::wait_until_used`]).
+///
+/// Returns `None` on kernels that do not publish the
+/// `descriptors` attribute.
+///
+/// # Errors
+/// This function fails with `attach::Error::NotEnumerated`
+/// if no device is set up on `port` yet, if sysfs could not
+/// be read, or if the descriptor could not be parsed.
+pub fn device_descriptor(port: Port) -> Result<Option<DeviceDescriptor>, Box<dyn StdError>> {
+    let bus_id = local_bus_id(port)?;
+    match sysfs::usb_device_bytes(&bus_id, "descriptors")? {
+        Some(bytes) => Ok(Some(parse_device(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Reads the configuration descriptors of the device
+/// attached to `port`, once the local kernel has set it
+/// up (see [`attach::wait_until_used`]).
+///
+/// Returns `None` on kernels that do not publish the
+/// `descriptors` attribute.
+///
+/// # Errors
+/// This function fails with `attach::Error::NotEnumerated`
+/// if no device is set up on `port` yet, if sysfs could not
+/// be read, or if the descriptors could not be parsed.
+pub fn config_descriptors(port: Port) -> Result<Option<Vec<ConfigDescriptor>>, Box<dyn StdError>> {
+    let bus_id = local_bus_id(port)?;
+    match sysfs::usb_device_bytes(&bus_id, "descriptors")? {
+        Some(bytes) => Ok(Some(parse(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use super::*;
+    use crate::{net, test_support::DeviceBytes};
+
+    /// The `descriptors` attribute of a USB 2.0 root hub
+    /// (1d6b:0002): the device descriptor, then one
+    /// configuration with one interface and its interrupt
+    /// endpoint.
+    fn root_hub() -> Vec<u8> {
+        let device = [
+            0x12, 0x01, 0x00, 0x02, 0x09, 0x00, 0x01, 0x40, 0x6b, 0x1d, 0x02, 0x00, 0x15, 0x05,
+            0x03, 0x02, 0x01, 0x01,
+        ];
+        let config = [0x09, 0x02, 0x19, 0x00, 0x01, 0x01, 0x00, 0xe0, 0x00];
+        let interface = [0x09, 0x04, 0x00, 0x00, 0x01, 0x09, 0x00, 0x00, 0x00];
+        let endpoint = [0x07, 0x05, 0x81, 0x03, 0x04, 0x00, 0x0c];
+        [&device[..], &config, &interface, &endpoint].concat()
+    }
+
+    fn malformed_at(result: Result<impl fmt::Debug, Error>) -> usize {
+        match result {
+            Err(Error::Malformed { offset }) => offset,
+            Ok(parsed) => panic!("parsed as {parsed:?}"),
+        }
+    }
+
+    #[test]
+    fn device_descriptor_fields() {
+        let desc = parse_device(&root_hub()).unwrap();
+        assert_eq!(
+            desc,
+            DeviceDescriptor {
+                usb_version: 0x0200,
+                class: 0x09,
+                subclass: 0,
+                protocol: 1,
+                max_packet_size0: 64,
+                vendor: 0x1d6b,
+                product: 0x0002,
+                device_version: 0x0515,
+                manufacturer_index: 3,
+                product_index: 2,
+                serial_index: 1,
+                num_configurations: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn config_interface_and_endpoint() {
+        let configs = parse(&root_hub()).unwrap();
+        assert_eq!(
+            configs,
+            [ConfigDescriptor {
+                configuration_value: 1,
+                num_interfaces: 1,
+                attributes: 0xe0,
+                max_power: 0,
+                interfaces: vec![InterfaceDescriptor {
+                    number: 0,
+                    alternate_setting: 0,
+                    class: 0x09,
+                    subclass: 0,
+                    protocol: 0,
+                    endpoints: vec![EndpointDescriptor {
+                        address: 0x81,
+                        attributes: 0x03,
+                        max_packet_size: 4,
+                        interval: 12,
+                    }],
+                }],
+            }]
+        );
+    }
+
+    #[test]
+    fn truncated_device_descriptor_is_malformed() {
+        let bytes = root_hub();
+        for len in [0, 1, 17] {
+            assert_eq!(malformed_at(parse_device(&bytes[..len])), 0, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn truncated_descriptors_name_their_offset() {
+        // Cut inside the device, configuration, interface
+        // and endpoint descriptors in turn.
+        let bytes = root_hub();
+        for (len, offset) in [(10, 0), (20, 18), (30, 27), (40, 36)] {
+            assert_eq!(malformed_at(parse(&bytes[..len])), offset, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn zero_length_descriptor_is_malformed() {
+        let mut bytes = root_hub();
+        let len = bytes.len();
+        bytes.extend([0x00, 0x05]);
+        assert_eq!(malformed_at(parse(&bytes)), len);
+        assert_eq!(malformed_at(parse_device(&[0; 18])), 0);
+    }
+
+    #[test]
+    fn device_fields_sit_at_their_c_offsets() {
+        // Offsets into `struct usbip_usb_device`, with a
+        // value no other field has.
+        let mut bytes = DeviceBytes::default().encode();
+        bytes[304..306].copy_from_slice(&0x0515u16.to_be_bytes()); // bcdDevice
+        bytes[306..309].copy_from_slice(&[0xef, 0x02, 0x01]); // class triple
+        bytes[309] = 2; // bConfigurationValue
+        bytes[310] = 3; // bNumConfigurations
+        bytes[311] = 4; // bNumInterfaces
+
+        let udev = net::parse_device(&bytes).unwrap();
+        assert_eq!(udev.bcd_device(), 0x0515);
+        assert_eq!(
+            (
+                udev.b_device_class(),
+                udev.b_device_subclass(),
+                udev.b_device_protocol()
+            ),
+            (0xef, 0x02, 0x01)
+        );
+        assert_eq!(udev.b_configuration_value(), 2);
+        assert_eq!(udev.b_num_configurations(), 3);
+        assert_eq!(udev.b_num_interfaces(), 4);
+
+        let encoded = net::encode(&udev).unwrap();
+        assert_eq!(encoded.len(), 312);
+        assert_eq!(encoded, bytes);
+    }
+}
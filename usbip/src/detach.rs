@@ -1,8 +1,17 @@
-use std::{error::Error as StdError, fs, path::PathBuf};
+use std::{
+    collections::BTreeSet,
+    error::Error as StdError,
+    io,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use usbip_core::{vhci, DeviceStatus};
 
-use usbip_core::{
-    vhci,
-    DeviceStatus,
+use crate::{
+    attach::{self, AttachmentInfo, ImportedDeviceFilter, Manifest},
+    state, sysfs, Port,
 };
 
 pub use error::Error;
@@ -14,6 +23,9 @@ mod error {
     pub enum Error {
         PortAlreadyDetached(u8),
         InvalidPort { requested: u8, num_ports: usize },
+        NotAUsbipDevice,
+        DetachNotConfirmed(u8),
+        TimedOut(u8),
     }
 
     impl fmt::Display for Error {
@@ -26,6 +38,11 @@ mod error {
                     requested,
                     num_ports,
                 } => write!(f, "Invalid port {requested} > maxports ({num_ports})"),
+                Error::NotAUsbipDevice => write!(f, "Device is not attached through a vhci port"),
+                Error::DetachNotConfirmed(port) => {
+                    write!(f, "Port {port} is still in use after detaching")
+                }
+                Error::TimedOut(port) => write!(f, "timed out waiting for port {port} to be free"),
             }
         }
     }
@@ -35,7 +52,11 @@ mod error {
 
 /// Iterates through `idevs` to verify that
 /// `port` is a valid port number.
-/// 
+///
+/// `num_ports` is the total number of vhci ports, as
+/// read from the driver's `nports` attribute. It cannot
+/// be taken from `idevs`, which may only list some ports.
+///
 /// # Error
 /// This function returns an error if the requested
 /// port was already detached or if the port number
@@ -43,41 +64,651 @@ mod error {
 /// this system.
 fn validate(
     port: u8,
-    mut idevs: impl ExactSizeIterator<Item = vhci::ImportedDevice>,
+    num_ports: usize,
+    mut idevs: impl Iterator<Item = vhci::ImportedDevice>,
 ) -> Result<(), Error> {
-    let num_ports = idevs.len();
-    let idev = idevs
-        .find(|idev| idev.port() == port)
-        .ok_or(Error::InvalidPort {
+    if usize::from(port) >= num_ports {
+        return Err(Error::InvalidPort {
             requested: port,
             num_ports,
-        })?;
-    if matches!(idev.status(), DeviceStatus::PortAvailable) {
-        Err(Error::PortAlreadyDetached(port))
+        });
+    }
+    match idevs.find(|idev| idev.port() == port) {
+        Some(idev) if !matches!(idev.status(), DeviceStatus::PortAvailable) => Ok(()),
+        _ => Err(Error::PortAlreadyDetached(port)),
+    }
+}
+
+/// Options for [`detach_port_with`].
+#[derive(Debug, Clone, Default)]
+pub struct DetachOptions {
+    /// Return as soon as the vhci driver accepts the
+    /// detach request, instead of checking that the port
+    /// was actually freed.
+    pub no_verify: bool,
+}
+
+/// How long detaching waits for the port to show up as
+/// available before giving up on it.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Polls `port` every `poll` until it is listed as
+/// available, returning `false` if it still was not once
+/// `timeout` passed.
+fn poll_free(port: u8, timeout: Duration, poll: Duration) -> Result<bool, Box<dyn StdError>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let available = vhci::Driver::try_open()?
+            .imported_devices()
+            .find(|idev| idev.port() == port)
+            .is_none_or(|idev| matches!(idev.status(), DeviceStatus::PortAvailable));
+        if available {
+            return Ok(true);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        thread::sleep(poll.min(deadline - now));
+    }
+}
+
+/// Waits for `port` to be listed as available again.
+///
+/// # Error
+/// This function returns `Error::DetachNotConfirmed` if
+/// the port is still not available after
+/// `CONFIRM_TIMEOUT`.
+fn confirm_detached(port: u8) -> Result<(), Box<dyn StdError>> {
+    if poll_free(port, CONFIRM_TIMEOUT, attach::POLL_INTERVAL)? {
+        Ok(())
     } else {
+        Err(Error::DetachNotConfirmed(port).into())
+    }
+}
+
+/// Waits until `port` is free, meaning the kernel has
+/// finished tearing down the device that was attached to
+/// it. This is the counterpart of
+/// [`attach::wait_until_used`].
+///
+/// The port is polled every 50ms, see
+/// [`wait_until_free_every`] to change that. A port that
+/// is already free returns right away.
+///
+/// # Errors
+/// This function fails with `Error::TimedOut` if the port
+/// was still in use after `timeout`, or if there was an
+/// error with the Vhci driver.
+pub fn wait_until_free(port: Port, timeout: Duration) -> Result<(), Box<dyn StdError>> {
+    wait_until_free_every(port, timeout, attach::POLL_INTERVAL)
+}
+
+/// Waits until `port` is free like [`wait_until_free`],
+/// polling it every `poll`.
+///
+/// # Errors
+/// See [`wait_until_free`].
+pub fn wait_until_free_every(
+    port: Port,
+    timeout: Duration,
+    poll: Duration,
+) -> Result<(), Box<dyn StdError>> {
+    if poll_free(port.get(), timeout, poll)? {
         Ok(())
+    } else {
+        Err(Error::TimedOut(port.get()).into())
     }
 }
 
 /// Detaches a remote USB device from the system.
 ///
+/// Only the state file for `port` is removed. The state
+/// directory is shared with other tools and the other
+/// attached ports, so it is left in place.
+///
+/// The port status is read back afterwards to confirm the
+/// kernel freed the port, see [`detach_port_with`] to skip
+/// this.
+///
 /// # Errors
 /// This function can fail for these reasons below:
-/// - `port` was already detached
+/// - `port` was already detached, or someone else
+///   detached it while this ran (`Error::PortAlreadyDetached`)
 /// - `port` was not a valid port number
+/// - The number of vhci ports could not be read
 /// - There was an error with the Vhci driver
-/// (see `usbip_core::vhci::Driver::try_open`)
-pub fn detach_port(port: u8) -> Result<(), Box<dyn StdError>> {
+///   (see `usbip_core::vhci::Driver::try_open`)
+/// - Writing the detach request failed, or was still
+///   interrupted by signals after a few retries
+/// - The driver accepted the request, but the port was
+///   still not available shortly after
+///   (`Error::DetachNotConfirmed`)
+pub fn detach_port(port: Port) -> Result<(), Box<dyn StdError>> {
+    detach(port.get(), &DetachOptions::default())
+}
+
+/// Detaches a remote USB device from the system, like
+/// [`detach_port`], with `options`.
+///
+/// # Errors
+/// See [`detach_port`]. With `options.no_verify`, this
+/// function never fails with `Error::DetachNotConfirmed`.
+pub fn detach_port_with(port: Port, options: &DetachOptions) -> Result<(), Box<dyn StdError>> {
+    detach(port.get(), options)
+}
+
+/// Detaches the device on `port` given as a bare number,
+/// like [`detach_port`], for callers that have not moved
+/// to [`Port`] yet.
+///
+/// # Errors
+/// See [`detach_port`].
+pub fn detach_port_number(port: u8) -> Result<(), Box<dyn StdError>> {
+    detach(port, &DetachOptions::default())
+}
+
+/// Turns a failed write to the vhci `detach` attribute
+/// into `Error::PortAlreadyDetached` if the port turns out
+/// to be free, keeping any other I/O error as is.
+///
+/// The driver refuses to detach a free port with
+/// `EINVAL`. The port was in use when it was validated,
+/// so this means someone else detached it in between,
+/// such as another thread detaching the same port.
+fn detach_write_error(err: io::Error, port: u8) -> Box<dyn StdError> {
+    detach_write_error_with(err, port, || {
+        poll_free(port, Duration::ZERO, Duration::ZERO).unwrap_or(false)
+    })
+}
+
+/// Does the work of [`detach_write_error`], asking
+/// `is_free` whether the port is free by now.
+fn detach_write_error_with(
+    err: io::Error,
+    port: u8,
+    is_free: impl FnOnce() -> bool,
+) -> Box<dyn StdError> {
+    let raced = err.raw_os_error() == Some(libc::EINVAL) && is_free();
+    if raced {
+        Error::PortAlreadyDetached(port).into()
+    } else {
+        err.into()
+    }
+}
+
+fn detach(port: u8, options: &DetachOptions) -> Result<(), Box<dyn StdError>> {
     let driver = vhci::Driver::try_open()?;
 
     let imported_devices = driver.imported_devices();
-    validate(port, imported_devices)?;
+    validate(port, driver.num_ports(), imported_devices)?;
+
+    let _ = state::remove(port);
+
+    if let Err(err) = sysfs::detach(port) {
+        let err = detach_write_error(err, port);
+        if matches!(err.downcast_ref(), Some(Error::PortAlreadyDetached(_))) {
+            attach::disown(port);
+        }
+        return Err(err);
+    }
+    attach::disown(port);
+    if !options.no_verify {
+        confirm_detached(port)?;
+    }
+    Ok(())
+}
+
+/// Detaches the device `idev` describes, as listed by
+/// `usbip_core::vhci::Driver::imported_devices`.
+///
+/// `idev` may be stale by the time this is called, so the
+/// port is validated against the driver's current state
+/// again before detaching, exactly as [`detach_port`]
+/// does.
+///
+/// # Errors
+/// See [`detach_port`]. An `idev` that was already
+/// available when listed fails with
+/// `Error::PortAlreadyDetached` without touching the
+/// driver.
+pub fn detach_imported(idev: &vhci::ImportedDevice) -> Result<(), Box<dyn StdError>> {
+    // The port range is checked when detaching, against
+    // the driver rather than this listing.
+    validate(idev.port(), usize::MAX, std::iter::once(idev.clone()))?;
+    detach_port_number(idev.port())
+}
+
+/// Detaches the USB/IP device at the local sysfs `path`,
+/// such as `/sys/bus/usb/devices/3-1` or the `DEVPATH` of
+/// a udev event under `/sys`.
+///
+/// `path` may also point inside the device, for example
+/// at one of its interfaces. Symlinks are resolved before
+/// `path` is matched against the devices on vhci ports.
+///
+/// # Errors
+/// This function fails with `Error::NotAUsbipDevice` if
+/// no vhci port has a device at `path`, if `path` could
+/// not be resolved, or for any reason listed in
+/// [`detach_port`].
+pub fn detach_by_local_path(path: &Path) -> Result<(), Box<dyn StdError>> {
+    let port = sysfs::port_for_path(path)?.ok_or(Error::NotAUsbipDevice)?;
+    detach_port_number(port)
+}
+
+/// The outcome of detaching several ports at once.
+#[derive(Debug, Default)]
+pub struct DetachAllReport {
+    /// Ports that were detached.
+    pub succeeded: Vec<Port>,
+    /// Ports that could not be detached, with the reason.
+    pub failed: Vec<(Port, Box<dyn StdError>)>,
+}
+
+impl DetachAllReport {
+    /// Returns `true` if every port was detached.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Detaches every remote USB device attached to the
+/// system.
+///
+/// Every port in use is attempted, even if detaching
+/// an earlier one failed, so the report lists exactly
+/// which ports need to be retried.
+///
+/// # Errors
+/// This function fails only if the Vhci driver could not
+/// be opened (see `usbip_core::vhci::Driver::try_open`).
+/// Errors for individual ports are collected in the
+/// returned report instead.
+pub fn detach_all() -> Result<DetachAllReport, Box<dyn StdError>> {
+    let ports: Vec<u8> = vhci::Driver::try_open()?
+        .imported_devices()
+        .in_use()
+        .map(|idev| idev.port())
+        .collect();
 
-    let path = PathBuf::from(format!("{}/port{}", vhci::STATE_PATH, port));
-    let _ = fs::remove_file(path);
-    let _ = fs::remove_dir(vhci::STATE_PATH);
+    Ok(detach_each(ports, detach_port_number))
+}
 
-    driver
-        .try_detach_dev(port)
-        .map_err(std::convert::Into::into)
+/// Runs `detach` on every port in `ports`, carrying on
+/// past failures, and reports how each one went.
+fn detach_each(
+    ports: impl IntoIterator<Item = u8>,
+    mut detach: impl FnMut(u8) -> Result<(), Box<dyn StdError>>,
+) -> DetachAllReport {
+    let mut report = DetachAllReport::default();
+    for port in ports {
+        match detach(port) {
+            Ok(()) => report.succeeded.push(Port::from_driver(port)),
+            Err(err) => report.failed.push((Port::from_driver(port), err)),
+        }
+    }
+    report
+}
+
+/// Detaches every attached device for which `pred`
+/// returns `true`, such as all devices from one host:
+///
+/// ```no_run
+/// let report = usbip::detach::detach_where(|info| info.host() == "10.0.0.2")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// Only ports with connection details recorded are
+/// considered (see [`attach::attachments`]). Like
+/// [`detach_all`], every matching port is attempted.
+///
+/// # Errors
+/// This function fails only if the attachments could not
+/// be listed. Errors for individual ports are collected
+/// in the returned report instead.
+pub fn detach_where(
+    pred: impl Fn(&AttachmentInfo) -> bool,
+) -> Result<DetachAllReport, Box<dyn StdError>> {
+    let mut report = DetachAllReport::default();
+    for info in attach::attachments()?.iter().filter(|info| pred(info)) {
+        match detach_port(info.port()) {
+            Ok(()) => report.succeeded.push(info.port()),
+            Err(err) => report.failed.push((info.port(), err)),
+        }
+    }
+
+    Ok(report)
+}
+
+/// What [`reconcile_state`] found and fixed.
+#[derive(Debug, Default)]
+pub struct ReconcileReport {
+    /// Ports whose state file was removed because nothing
+    /// is attached to them anymore.
+    pub removed: Vec<Port>,
+    /// Ports in use whose state file was written again
+    /// from the manifest given to [`reconcile_state_from`].
+    pub restored: Vec<Port>,
+    /// Ports in use with no state file, which could not be
+    /// restored: the kernel does not know which server a
+    /// port's device came from.
+    pub unrecorded: Vec<Port>,
+    /// Ports whose state file could not be removed or
+    /// written, with the reason.
+    pub failed: Vec<(Port, Box<dyn StdError>)>,
+}
+
+impl ReconcileReport {
+    /// Returns `true` if the state files already matched
+    /// the driver.
+    pub fn is_clean(&self) -> bool {
+        self.removed.is_empty()
+            && self.restored.is_empty()
+            && self.unrecorded.is_empty()
+            && self.failed.is_empty()
+    }
+}
+
+/// Brings the state files in line with the ports the vhci
+/// driver has in use, as after an unclean shutdown.
+///
+/// State files for ports that are no longer in use are
+/// removed. Ports in use without a state file are only
+/// reported, see [`reconcile_state_from`] to restore them.
+///
+/// # Errors
+/// This function fails only if the Vhci driver could not
+/// be opened or the state directory could not be listed.
+/// Errors for individual ports are collected in the
+/// returned report instead.
+pub fn reconcile_state() -> Result<ReconcileReport, Box<dyn StdError>> {
+    reconcile_state_from(None)
+}
+
+/// Brings the state files in line with the ports in use
+/// like [`reconcile_state`], also writing the state file
+/// of a port in use that has none from its entry in
+/// `manifest`, such as one saved with
+/// [`attach::export_manifest`] before the shutdown.
+///
+/// The manifest is trusted to still describe the device
+/// on each port; nothing on the port tells which server
+/// it came from, so this cannot be checked.
+///
+/// # Errors
+/// See [`reconcile_state`].
+pub fn reconcile_state_from(
+    manifest: Option<&Manifest>,
+) -> Result<ReconcileReport, Box<dyn StdError>> {
+    let in_use: BTreeSet<u8> = vhci::Driver::try_open()?
+        .imported_devices()
+        .in_use()
+        .map(|idev| idev.port())
+        .collect();
+    let recorded: BTreeSet<u8> = state::recorded_ports()?.into_iter().collect();
+
+    Ok(reconcile_with(
+        &in_use,
+        &recorded,
+        manifest,
+        state::remove,
+        |port, record| state::write(port, record, state::StateFormat::default()),
+    ))
+}
+
+/// Reconciles the `recorded` state files with the ports
+/// `in_use` like [`reconcile_state_from`], with `remove`
+/// and `write` standing in for the state directory.
+fn reconcile_with(
+    in_use: &BTreeSet<u8>,
+    recorded: &BTreeSet<u8>,
+    manifest: Option<&Manifest>,
+    mut remove: impl FnMut(u8) -> io::Result<()>,
+    mut write: impl FnMut(u8, &state::Record) -> io::Result<()>,
+) -> ReconcileReport {
+    let mut report = ReconcileReport::default();
+    for &port in recorded.difference(in_use) {
+        match remove(port) {
+            Ok(()) => report.removed.push(Port::from_driver(port)),
+            Err(err) => report.failed.push((Port::from_driver(port), err.into())),
+        }
+    }
+
+    for &port in in_use.difference(recorded) {
+        let entry =
+            manifest.and_then(|manifest| manifest.entries.iter().find(|entry| entry.port == port));
+        let Some(entry) = entry else {
+            report.unrecorded.push(Port::from_driver(port));
+            continue;
+        };
+        let record = state::Record {
+            host: entry.host.as_str().into(),
+            tcp_port: entry.tcp_port,
+            bus_id: entry.bus_id.as_str().into(),
+            version: entry.version,
+        };
+        match write(port, &record) {
+            Ok(()) => report.restored.push(Port::from_driver(port)),
+            Err(err) => report.failed.push((Port::from_driver(port), err.into())),
+        }
+    }
+
+    report
+}
+
+/// Detaches every device this process attached, leaving
+/// devices attached by anyone else alone, unlike
+/// [`detach_all`].
+///
+/// This takes a lock, so it must not be called from
+/// inside a signal handler. Have the handler set a flag
+/// instead, and call this (or drop an [`OwnedGuard`]) on
+/// the way out.
+///
+/// # Errors
+/// Errors for individual ports are collected in the
+/// returned report, like [`detach_all`]. A port detached
+/// by someone else in the meantime is reported as failed
+/// with `Error::PortAlreadyDetached`.
+pub fn detach_all_owned() -> DetachAllReport {
+    let mut report = DetachAllReport::default();
+    for port in attach::owned_ports() {
+        match detach_port(port) {
+            Ok(()) => report.succeeded.push(port),
+            Err(err) => {
+                if matches!(err.downcast_ref(), Some(Error::PortAlreadyDetached(_))) {
+                    attach::disown(port.get());
+                }
+                report.failed.push((port, err));
+            }
+        }
+    }
+    report
+}
+
+/// Detaches every device this process attached when
+/// dropped, see [`detach_all_owned`].
+///
+/// Keep one alive at the top of a daemon's `main` so the
+/// cleanup runs however `main` returns. Failures are
+/// logged, since `Drop` cannot report them.
+#[derive(Debug, Default)]
+pub struct OwnedGuard;
+
+impl Drop for OwnedGuard {
+    fn drop(&mut self) {
+        let report = detach_all_owned();
+        for (port, err) in &report.failed {
+            log::warn!("failed to detach port {port} on shutdown: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_rejects_ports_past_the_driver() {
+        let err = validate(8, 8, std::iter::empty()).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidPort {
+                requested: 8,
+                num_ports: 8
+            }
+        ));
+    }
+
+    #[test]
+    fn detach_each_attempts_every_port() {
+        // Port 9 is out of range on a 4 port driver, the
+        // others detach fine.
+        let report = detach_each([0, 9, 2], |port| Ok(Port::new(port, 4).map(drop)?));
+
+        assert_eq!(
+            report.succeeded,
+            [Port::from_driver(0), Port::from_driver(2)]
+        );
+        assert_eq!(report.failed.len(), 1);
+        let (port, err) = &report.failed[0];
+        assert_eq!(*port, Port::from_driver(9));
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::InvalidPort { requested: 9, .. })
+        ));
+        assert!(!report.is_complete());
+    }
+
+    #[test]
+    fn detach_losing_a_race_is_already_detached() {
+        // Another thread detached the port between this
+        // thread validating it and writing to `detach`.
+        let err = io::Error::from_raw_os_error(libc::EINVAL);
+        let err = detach_write_error_with(err, 3, || true);
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::PortAlreadyDetached(3))
+        ));
+    }
+
+    #[test]
+    fn detach_rejected_on_a_used_port_stays_an_io_error() {
+        let err = io::Error::from_raw_os_error(libc::EINVAL);
+        let err = detach_write_error_with(err, 3, || false);
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn other_detach_errors_do_not_check_the_port() {
+        let err = io::Error::from_raw_os_error(libc::EACCES);
+        let err = detach_write_error_with(err, 3, || panic!("port checked"));
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+    }
+
+    /// A state directory held in memory, recording what
+    /// reconciling did to it.
+    #[derive(Default)]
+    struct StateDir {
+        removed: Vec<u8>,
+        written: Vec<(u8, state::Record)>,
+    }
+
+    fn reconcile_in(
+        dir: &mut StateDir,
+        in_use: &[u8],
+        recorded: &[u8],
+        manifest: Option<&Manifest>,
+    ) -> ReconcileReport {
+        let StateDir { removed, written } = dir;
+        reconcile_with(
+            &in_use.iter().copied().collect(),
+            &recorded.iter().copied().collect(),
+            manifest,
+            |port| {
+                removed.push(port);
+                Ok(())
+            },
+            |port, record| {
+                written.push((port, record.clone()));
+                Ok(())
+            },
+        )
+    }
+
+    fn ports(ports: &[Port]) -> Vec<u8> {
+        ports.iter().map(|port| port.get()).collect()
+    }
+
+    fn manifest_for(port: u8) -> Manifest {
+        Manifest {
+            entries: vec![attach::ManifestEntry {
+                host: "10.0.0.2".to_owned(),
+                tcp_port: 3240,
+                bus_id: "1-1".to_owned(),
+                port,
+                version: Some(0x0111),
+            }],
+        }
+    }
+
+    #[test]
+    fn reconcile_removes_the_state_file_of_a_free_port() {
+        let mut dir = StateDir::default();
+        let report = reconcile_in(&mut dir, &[0], &[0, 3], None);
+        assert_eq!(dir.removed, [3]);
+        assert!(dir.written.is_empty());
+        assert_eq!(ports(&report.removed), [3]);
+        assert!(report.restored.is_empty() && report.unrecorded.is_empty());
+    }
+
+    #[test]
+    fn reconcile_reports_a_used_port_without_a_state_file() {
+        let mut dir = StateDir::default();
+        let report = reconcile_in(&mut dir, &[0, 2], &[0], None);
+        assert!(dir.removed.is_empty() && dir.written.is_empty());
+        assert_eq!(ports(&report.unrecorded), [2]);
+        assert!(!report.is_clean());
+    }
+
+    #[test]
+    fn reconcile_restores_a_missing_state_file_from_the_manifest() {
+        let mut dir = StateDir::default();
+        let report = reconcile_in(&mut dir, &[2], &[], Some(&manifest_for(2)));
+        let record = state::Record {
+            host: "10.0.0.2".into(),
+            tcp_port: 3240,
+            bus_id: "1-1".into(),
+            version: Some(0x0111),
+        };
+        assert_eq!(dir.written, [(2, record)]);
+        assert_eq!(ports(&report.restored), [2]);
+        assert!(report.unrecorded.is_empty());
+    }
+
+    #[test]
+    fn reconcile_of_a_consistent_state_does_nothing() {
+        let mut dir = StateDir::default();
+        let report = reconcile_in(&mut dir, &[0, 1], &[0, 1], Some(&manifest_for(1)));
+        assert!(dir.removed.is_empty() && dir.written.is_empty());
+        assert!(report.is_clean());
+    }
+
+    #[test]
+    fn reconcile_collects_failures_per_port() {
+        let report = reconcile_with(
+            &[1].into(),
+            &[0].into(),
+            Some(&manifest_for(1)),
+            |_| Err(io::ErrorKind::PermissionDenied.into()),
+            |_, _| Err(io::ErrorKind::PermissionDenied.into()),
+        );
+        let failed: Vec<_> = report.failed.iter().map(|(port, _)| port.get()).collect();
+        assert_eq!(failed, [0, 1]);
+        assert!(report.removed.is_empty() && report.restored.is_empty());
+    }
 }
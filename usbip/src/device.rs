@@ -0,0 +1,658 @@
+use std::{cmp::Ordering, collections::BTreeMap, fmt, io, str::FromStr};
+
+use usbip_core::UsbDevice;
+
+use crate::{buffer_to_string, sysfs};
+
+pub use error::{Error, ValidationError};
+
+mod error {
+    use std::fmt;
+
+    #[derive(Debug, Clone)]
+    pub enum Error {
+        InvalidMatch(Box<str>),
+        InvalidLsusbLine(Box<str>),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::InvalidMatch(s) => {
+                    write!(f, "expected VID:PID or VID:PID:INDEX in hex, got {s}")
+                }
+                Error::InvalidLsusbLine(s) => {
+                    write!(f, "expected a line of `lsusb` output, got {s}")
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    /// A field of a device that no real device would have.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ValidationError {
+        UnknownSpeed(u32),
+        ZeroVendor,
+        NoConfigurations,
+        TooManyInterfaces(u8),
+    }
+
+    impl fmt::Display for ValidationError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                ValidationError::UnknownSpeed(speed) => write!(f, "unknown speed {speed}"),
+                ValidationError::ZeroVendor => write!(f, "vendor id is 0"),
+                ValidationError::NoConfigurations => write!(f, "device has no configurations"),
+                ValidationError::TooManyInterfaces(n) => {
+                    write!(f, "{n} interfaces is more than a configuration can have")
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for ValidationError {}
+}
+
+/// The most interfaces one configuration can have, as
+/// the kernel's `USB_MAXINTERFACES`.
+const MAX_INTERFACES: u8 = 32;
+
+/// Helpers on top of `usbip_core::UsbDevice` that
+/// the attach path needs but the core crate does
+/// not provide.
+pub trait UsbDeviceExt {
+    /// Returns the `devid` the vhci driver expects
+    /// when attaching this device, computed as
+    /// `(busnum << 16) | devnum`.
+    fn devid(&self) -> u32;
+
+    /// Returns the speed the remote device reported,
+    /// decoded into a [`Speed`].
+    fn speed_kind(&self) -> Speed;
+
+    /// Returns a view of this device that is safe to
+    /// write to shared logs. Use `Debug` for full detail.
+    fn redacted(&self) -> Redacted<'_>;
+
+    /// Sanity-checks the fields a server sent for this
+    /// device, to flag malformed or adversarial entries.
+    ///
+    /// # Error
+    /// This function returns the first field found to be
+    /// out of range for any real device.
+    fn validate(&self) -> Result<(), ValidationError>;
+}
+
+impl UsbDeviceExt for UsbDevice {
+    fn devid(&self) -> u32 {
+        (self.busnum() << 16) | self.devnum()
+    }
+
+    fn speed_kind(&self) -> Speed {
+        Speed::from(self.speed())
+    }
+
+    fn redacted(&self) -> Redacted<'_> {
+        Redacted(self)
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if matches!(self.speed_kind(), Speed::Unknown) {
+            Err(ValidationError::UnknownSpeed(self.speed()))
+        } else if self.id_vendor() == 0 {
+            Err(ValidationError::ZeroVendor)
+        } else if self.b_num_configurations() == 0 {
+            Err(ValidationError::NoConfigurations)
+        } else if self.b_num_interfaces() > MAX_INTERFACES {
+            Err(ValidationError::TooManyInterfaces(self.b_num_interfaces()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Displays a device without the identifiers that can
+/// leak across tenants in shared logs.
+///
+/// The sysfs path is cut down to its last component,
+/// which is the part that tells devices apart on one
+/// server, and nothing else that identifies the server's
+/// topology is shown.
+#[derive(Debug, Clone, Copy)]
+pub struct Redacted<'a>(&'a UsbDevice);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let udev = self.0;
+        let path = buffer_to_string(udev.path());
+        let path = path.rsplit('/').next().unwrap_or_default();
+        write!(
+            f,
+            "{} ({:04x}:{:04x}, {}, path .../{})",
+            buffer_to_string(udev.bus_id()),
+            udev.id_vendor(),
+            udev.id_product(),
+            udev.speed_kind(),
+            path
+        )
+    }
+}
+
+/// The link speed of a USB device, mirroring the
+/// kernel's `enum usb_device_speed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Speed {
+    Unknown,
+    Low,
+    Full,
+    High,
+    Wireless,
+    Super,
+    SuperPlus,
+}
+
+impl Speed {
+    /// Returns the token written for this speed in the
+    /// vhci `attach` sysfs attribute.
+    ///
+    /// The vhci-hcd driver parses the speed as the raw
+    /// `usb_device_speed` number, and only accepts low,
+    /// full, high, wireless and super speed devices.
+    /// Any other speed is rejected by the kernel with
+    /// `EINVAL`, so `None` is returned for those instead.
+    pub const fn attach_token(self) -> Option<&'static str> {
+        match self {
+            Speed::Low => Some("1"),
+            Speed::Full => Some("2"),
+            Speed::High => Some("3"),
+            Speed::Wireless => Some("4"),
+            Speed::Super => Some("5"),
+            Speed::Unknown | Speed::SuperPlus => None,
+        }
+    }
+
+    /// Returns `true` if this device belongs on the
+    /// SuperSpeed hub of a vhci controller.
+    pub const fn is_super_speed(self) -> bool {
+        matches!(self, Speed::Super | Speed::SuperPlus)
+    }
+}
+
+impl From<u32> for Speed {
+    fn from(value: u32) -> Self {
+        match value {
+            1 => Speed::Low,
+            2 => Speed::Full,
+            3 => Speed::High,
+            4 => Speed::Wireless,
+            5 => Speed::Super,
+            6 => Speed::SuperPlus,
+            _ => Speed::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let speed = match self {
+            Speed::Unknown => "unknown speed",
+            Speed::Low => "Low Speed(1.5Mbps)",
+            Speed::Full => "Full Speed(12Mbps)",
+            Speed::High => "High Speed(480Mbps)",
+            Speed::Wireless => "Wireless",
+            Speed::Super => "Super Speed(5000Mbps)",
+            Speed::SuperPlus => "Super Speed Plus(10000Mbps)",
+        };
+        f.write_str(speed)
+    }
+}
+
+/// Picks one device out of a listing by vendor and
+/// product id.
+///
+/// USB/IP listings do not carry the serial number (it is
+/// a string descriptor, only readable over the data plane
+/// once attached), so identical models are told apart by
+/// `index`: their position among the matching devices,
+/// ordered by bus id like [`SortKey::BusId`], so `1-2`
+/// comes before `1-10`. As long as the devices stay plugged
+/// into the same physical ports, this picks the same one.
+///
+/// Parses from `VID:PID` or `VID:PID:INDEX`, with the ids
+/// in hex as `lsusb` prints them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceMatch {
+    pub vendor: u16,
+    pub product: u16,
+    pub index: usize,
+}
+
+impl DeviceMatch {
+    pub const fn new(vendor: u16, product: u16) -> Self {
+        Self {
+            vendor,
+            product,
+            index: 0,
+        }
+    }
+
+    /// Returns `true` if `udev` has this vendor and
+    /// product id, regardless of `index`.
+    pub fn matches(&self, udev: &UsbDevice) -> bool {
+        udev.id_vendor() == self.vendor && udev.id_product() == self.product
+    }
+
+    /// Selects the matching device from `devices`.
+    pub fn select<'a>(&self, devices: &'a [UsbDevice]) -> Option<&'a UsbDevice> {
+        let mut matching: Vec<&UsbDevice> =
+            devices.iter().filter(|udev| self.matches(udev)).collect();
+        matching.sort_by(|a, b| {
+            natural_cmp(&buffer_to_string(a.bus_id()), &buffer_to_string(b.bus_id()))
+        });
+        matching.get(self.index).copied()
+    }
+}
+
+impl FromStr for DeviceMatch {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidMatch(s.into());
+
+        let mut fields = s.split(':');
+        let vendor = fields.next().ok_or_else(invalid)?;
+        let product = fields.next().ok_or_else(invalid)?;
+        let index = fields.next();
+        if fields.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            vendor: u16::from_str_radix(vendor, 16).map_err(|_| invalid())?,
+            product: u16::from_str_radix(product, 16).map_err(|_| invalid())?,
+            index: match index {
+                Some(index) => index.parse().map_err(|_| invalid())?,
+                None => 0,
+            },
+        })
+    }
+}
+
+/// A local device as listed by one line of `lsusb`:
+///
+/// ```text
+/// Bus 001 Device 003: ID 0781:5581 SanDisk Corp. Ultra
+/// ```
+///
+/// `lsusb` only shows a few fields of each device, so
+/// this is used to find the device in sysfs, which has
+/// the rest of its descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsusbLine {
+    pub busnum: u32,
+    pub devnum: u32,
+    pub vendor: u16,
+    pub product: u16,
+    /// The vendor and product names, if `lsusb` knew
+    /// them.
+    pub description: Box<str>,
+}
+
+impl LsusbLine {
+    /// Looks up the sysfs bus id of this device, which is
+    /// what exporting a device needs. Returns `None` if
+    /// the device is no longer plugged in.
+    ///
+    /// # Error
+    /// This function returns an error if the local USB
+    /// devices could not be listed from sysfs.
+    pub fn bus_id(&self) -> io::Result<Option<String>> {
+        sysfs::find_usb_device(self.busnum, self.devnum)
+    }
+}
+
+impl FromStr for LsusbLine {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidLsusbLine(s.into());
+
+        let rest = s.trim().strip_prefix("Bus ").ok_or_else(invalid)?;
+        let (busnum, rest) = rest.split_once(" Device ").ok_or_else(invalid)?;
+        let (devnum, rest) = rest.split_once(": ID ").ok_or_else(invalid)?;
+        let (id, description) = rest.split_once(' ').unwrap_or((rest, ""));
+        let (vendor, product) = id.split_once(':').ok_or_else(invalid)?;
+
+        Ok(Self {
+            busnum: busnum.parse().map_err(|_| invalid())?,
+            devnum: devnum.parse().map_err(|_| invalid())?,
+            vendor: u16::from_str_radix(vendor, 16).map_err(|_| invalid())?,
+            product: u16::from_str_radix(product, 16).map_err(|_| invalid())?,
+            description: description.trim().into(),
+        })
+    }
+}
+
+/// The order to sort a device listing in.
+///
+/// Servers list devices in whatever order they find
+/// them, so listings are sorted before being shown to
+/// make them stable across runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SortKey {
+    /// By bus id, in natural order.
+    #[default]
+    BusId,
+    /// By vendor id, then product id.
+    VidPid,
+    /// By sysfs path on the server, in natural order.
+    Path,
+}
+
+impl SortKey {
+    /// Compares two devices by this key, breaking ties
+    /// by bus id.
+    pub fn compare(self, a: &UsbDevice, b: &UsbDevice) -> Ordering {
+        let by_bus_id =
+            || natural_cmp(&buffer_to_string(a.bus_id()), &buffer_to_string(b.bus_id()));
+        match self {
+            SortKey::BusId => by_bus_id(),
+            SortKey::VidPid => (a.id_vendor(), a.id_product())
+                .cmp(&(b.id_vendor(), b.id_product()))
+                .then_with(by_bus_id),
+            SortKey::Path => natural_cmp(&buffer_to_string(a.path()), &buffer_to_string(b.path()))
+                .then_with(by_bus_id),
+        }
+    }
+
+    /// Sorts `devices` by this key.
+    pub fn sort(self, devices: &mut [UsbDevice]) {
+        devices.sort_by(|a, b| self.compare(a, b));
+    }
+}
+
+/// Returns the bus ids that appear more than once in
+/// `devices`, in natural order (see [`natural_cmp`]).
+///
+/// A server exporting the same bus id twice is
+/// misconfigured: an import request names the device by
+/// bus id alone, so which of the devices gets attached is
+/// up to the server.
+pub fn find_duplicates(devices: &[UsbDevice]) -> Vec<String> {
+    let mut counts = BTreeMap::new();
+    for udev in devices {
+        *counts
+            .entry(buffer_to_string(udev.bus_id()).into_owned())
+            .or_insert(0) += 1;
+    }
+    let mut duplicates: Vec<_> = counts
+        .into_iter()
+        .filter(|&(_, count)| count > 1)
+        .map(|(bus_id, _)| bus_id)
+        .collect();
+    duplicates.sort_by(|a, b| natural_cmp(a, b));
+    duplicates
+}
+
+/// Compares two strings with runs of digits ordered by
+/// their value, so bus ids sort as `1-1`, `1-2`, `1-10`
+/// instead of `1-1`, `1-10`, `1-2`.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, rest_a) = split_digits(a);
+                let (y, rest_b) = split_digits(b);
+                // Compared as digit strings, so arbitrarily
+                // long runs cannot overflow.
+                let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(y));
+                if ordering.is_ne() {
+                    return ordering;
+                }
+                (a, b) = (rest_a, rest_b);
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                (a, b) = (&a[1..], &b[1..]);
+            }
+        }
+    }
+}
+
+/// Splits the leading run of digits off `s`, without
+/// its leading zeros.
+fn split_digits(s: &[u8]) -> (&[u8], &[u8]) {
+    let len = s.iter().take_while(|c| c.is_ascii_digit()).count();
+    let (digits, rest) = s.split_at(len);
+    let zeros = digits.iter().take_while(|&&c| c == b'0').count();
+    (&digits[zeros..], rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::DeviceBytes;
+
+    #[test]
+    fn devid_packs_busnum_above_devnum() {
+        for (busnum, devnum, devid) in [(1, 2, 0x0001_0002), (3, 127, 0x0003_007f)] {
+            let udev = DeviceBytes {
+                busnum,
+                devnum,
+                ..DeviceBytes::default()
+            }
+            .parse();
+            assert_eq!(udev.devid(), devid, "busnum {busnum}, devnum {devnum}");
+        }
+    }
+
+    #[test]
+    fn attach_token_is_the_kernel_speed_number() {
+        let tokens = [
+            (Speed::Unknown, None),
+            (Speed::Low, Some("1")),
+            (Speed::Full, Some("2")),
+            (Speed::High, Some("3")),
+            (Speed::Wireless, Some("4")),
+            (Speed::Super, Some("5")),
+            (Speed::SuperPlus, None),
+        ];
+        for (speed, token) in tokens {
+            assert_eq!(speed.attach_token(), token, "{speed:?}");
+        }
+    }
+
+    #[test]
+    fn natural_cmp_orders_digit_runs_by_value() {
+        let mut bus_ids = ["1-10", "1-2", "1-1", "2-1", "1-1.10", "1-1.2"];
+        bus_ids.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(bus_ids, ["1-1", "1-1.2", "1-1.10", "1-2", "1-10", "2-1"]);
+    }
+
+    #[test]
+    fn natural_cmp_ignores_leading_zeros() {
+        assert_eq!(natural_cmp("1-01", "1-1"), Ordering::Equal);
+        assert_eq!(natural_cmp("1-007", "1-10"), Ordering::Less);
+        assert_eq!(natural_cmp("1-0010", "1-9"), Ordering::Greater);
+    }
+
+    #[test]
+    fn natural_cmp_mixes_letters_and_digits() {
+        assert_eq!(natural_cmp("usb2", "usb10"), Ordering::Less);
+        assert_eq!(natural_cmp("usb10", "usbx"), Ordering::Less);
+        assert_eq!(natural_cmp("a1b2", "a1b10"), Ordering::Less);
+        assert_eq!(natural_cmp("a", "a1"), Ordering::Less);
+        assert_eq!(natural_cmp("b", "a10"), Ordering::Greater);
+    }
+
+    fn sorted_bus_ids(key: SortKey, devices: &[DeviceBytes<'_>]) -> Vec<String> {
+        let mut devices: Vec<_> = devices.iter().map(DeviceBytes::parse).collect();
+        key.sort(&mut devices);
+        devices
+            .iter()
+            .map(|udev| buffer_to_string(udev.bus_id()).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn sort_key_bus_id_is_natural_order() {
+        let devices = [
+            DeviceBytes {
+                bus_id: "1-10",
+                ..DeviceBytes::default()
+            },
+            DeviceBytes {
+                bus_id: "1-2",
+                ..DeviceBytes::default()
+            },
+        ];
+        assert_eq!(sorted_bus_ids(SortKey::BusId, &devices), ["1-2", "1-10"]);
+    }
+
+    #[test]
+    fn sort_key_vid_pid_breaks_ties_by_bus_id() {
+        let devices = [
+            DeviceBytes {
+                bus_id: "1-1",
+                vendor: 0x046d,
+                product: 0xc52b,
+                ..DeviceBytes::default()
+            },
+            DeviceBytes {
+                bus_id: "1-10",
+                vendor: 0x046d,
+                product: 0xc077,
+                ..DeviceBytes::default()
+            },
+            DeviceBytes {
+                bus_id: "1-2",
+                vendor: 0x046d,
+                product: 0xc077,
+                ..DeviceBytes::default()
+            },
+            DeviceBytes {
+                bus_id: "1-3",
+                vendor: 0x0403,
+                product: 0x6001,
+                ..DeviceBytes::default()
+            },
+        ];
+        assert_eq!(
+            sorted_bus_ids(SortKey::VidPid, &devices),
+            ["1-3", "1-2", "1-10", "1-1"]
+        );
+    }
+
+    #[test]
+    fn sort_key_path_is_natural_order() {
+        let devices = [
+            DeviceBytes {
+                path: "/sys/devices/pci0000:00/0000:00:14.0/usb10/10-1",
+                bus_id: "1-1",
+                ..DeviceBytes::default()
+            },
+            DeviceBytes {
+                path: "/sys/devices/pci0000:00/0000:00:14.0/usb2/2-1",
+                bus_id: "1-2",
+                ..DeviceBytes::default()
+            },
+        ];
+        assert_eq!(sorted_bus_ids(SortKey::Path, &devices), ["1-2", "1-1"]);
+    }
+
+    fn with_bus_ids(bus_ids: &[&str]) -> Vec<UsbDevice> {
+        bus_ids
+            .iter()
+            .map(|bus_id| {
+                DeviceBytes {
+                    bus_id,
+                    ..DeviceBytes::default()
+                }
+                .parse()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn find_duplicates_lists_repeated_bus_ids_once() {
+        let devices = with_bus_ids(&["1-10", "1-2", "1-10", "1-1", "1-2", "1-10"]);
+        assert_eq!(find_duplicates(&devices), ["1-2", "1-10"]);
+    }
+
+    #[test]
+    fn find_duplicates_of_distinct_bus_ids_is_empty() {
+        let devices = with_bus_ids(&["1-1", "1-1.1", "2-1"]);
+        assert!(find_duplicates(&devices).is_empty());
+        assert!(find_duplicates(&[]).is_empty());
+    }
+
+    #[test]
+    fn lsusb_lines_parse() {
+        let lines = [
+            (
+                "Bus 002 Device 001: ID 1d6b:0003 Linux Foundation 3.0 root hub",
+                (2, 1, 0x1d6b, 0x0003, "Linux Foundation 3.0 root hub"),
+            ),
+            (
+                "Bus 001 Device 003: ID 0781:5581 SanDisk Corp. Ultra\n",
+                (1, 3, 0x0781, 0x5581, "SanDisk Corp. Ultra"),
+            ),
+            (
+                "Bus 003 Device 012: ID 046d:c52b Logitech, Inc. Unifying Receiver",
+                (3, 12, 0x046d, 0xc52b, "Logitech, Inc. Unifying Receiver"),
+            ),
+        ];
+        for (line, (busnum, devnum, vendor, product, description)) in lines {
+            let expected = LsusbLine {
+                busnum,
+                devnum,
+                vendor,
+                product,
+                description: description.into(),
+            };
+            assert_eq!(line.parse::<LsusbLine>().unwrap(), expected, "{line:?}");
+        }
+    }
+
+    #[test]
+    fn lsusb_line_without_a_description() {
+        // lsusb leaves the names out for ids it does not
+        // know, sometimes keeping the space before them.
+        for line in [
+            "Bus 004 Device 002: ID 2109:0817",
+            "Bus 004 Device 002: ID 2109:0817 ",
+        ] {
+            let parsed: LsusbLine = line.parse().unwrap();
+            assert_eq!((parsed.vendor, parsed.product), (0x2109, 0x0817));
+            assert_eq!(&*parsed.description, "", "{line:?}");
+        }
+    }
+
+    #[test]
+    fn malformed_lsusb_lines_are_rejected() {
+        let lines = [
+            "",
+            "Bus 001 Device 003 ID 0781:5581 SanDisk Corp. Ultra",
+            "Bus 001 Device 003: ID 0781 SanDisk Corp. Ultra",
+            "Bus one Device 003: ID 0781:5581",
+            "Bus 001 Device 003: ID 0781:55811",
+            "Bus 001 Device 003: ID 0781:xyz1",
+            "/:  Bus 01.Port 1: Dev 1, Class=root_hub, Driver=xhci_hcd/12p, 480M",
+        ];
+        for line in lines {
+            let err = line.parse::<LsusbLine>().unwrap_err();
+            assert!(
+                matches!(&err, Error::InvalidLsusbLine(s) if &**s == line),
+                "{line:?}: {err}"
+            );
+        }
+    }
+}
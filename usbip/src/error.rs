@@ -0,0 +1,449 @@
+use std::{error::Error as StdError, fmt, io};
+
+use usbip_core::{net::Status, vhci};
+
+#[cfg(feature = "server")]
+use crate::server;
+use crate::{attach, descriptor, detach, device, net};
+
+/// Any error this crate can return.
+///
+/// The functions in this crate return `Box<dyn Error>`,
+/// since a single call can fail in any of the modules it
+/// goes through. Converting that box with `Error::from`
+/// recovers the concrete error, so callers can match on
+/// one type instead of downcasting to each module's.
+#[derive(Debug)]
+pub enum Error {
+    Attach(attach::Error),
+    Descriptor(descriptor::Error),
+    Detach(detach::Error),
+    Device(device::Error),
+    Validation(device::ValidationError),
+    Net(net::Error),
+    #[cfg(feature = "server")]
+    Server(server::Error),
+    Io(io::Error),
+    /// A PDU could not be encoded or decoded.
+    Wire(bincode::Error),
+    Driver(vhci::Error),
+    /// An error from outside this crate, such as the core
+    /// crate's buffer conversions. Only its message is
+    /// kept, so that `Error` can be sent across threads.
+    Other(Box<dyn StdError + Send + Sync>),
+}
+
+/// A broad class of failure, for choosing what to tell
+/// the user without matching on every error variant.
+///
+/// New error variants are sorted into these classes, so
+/// code matching on them keeps working as errors grow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// The server or proxy could not be reached, or the
+    /// connection broke.
+    NetworkUnreachable,
+    /// An operation did not finish in time.
+    TimedOut,
+    /// The process lacks the privileges it needs.
+    AuthOrPermission,
+    /// The vhci-hcd driver is not loaded or not usable.
+    DriverMissing,
+    /// The other side spoke a version or dialect of the
+    /// protocol this crate does not understand.
+    ProtocolIncompatible,
+    /// The device is in use by another client.
+    DeviceBusy,
+    /// No such device is exported or attached.
+    DeviceNotFound,
+    /// Every suitable vhci port is in use.
+    NoFreePort,
+    /// An argument was malformed or does not apply.
+    BadInput,
+    Other,
+}
+
+impl ErrorKind {
+    /// Returns a short suggestion for fixing this kind of
+    /// failure, suitable for showing to the user.
+    pub const fn hint(self) -> &'static str {
+        match self {
+            ErrorKind::NetworkUnreachable => {
+                "check that the server is running and reachable on the USB/IP port"
+            }
+            ErrorKind::TimedOut => "retry, or allow more time",
+            ErrorKind::AuthOrPermission => "run as root or with CAP_SYS_ADMIN",
+            ErrorKind::DriverMissing => "load the vhci-hcd kernel module (modprobe vhci-hcd)",
+            ErrorKind::ProtocolIncompatible => "the server may run an incompatible USB/IP version",
+            ErrorKind::DeviceBusy => "retry once the other client has detached the device",
+            ErrorKind::DeviceNotFound => "list the exported or attached devices and pick one",
+            ErrorKind::NoFreePort => "detach a device, or load vhci-hcd with more ports",
+            ErrorKind::BadInput => "check the arguments",
+            ErrorKind::Other => "see the error message for details",
+        }
+    }
+}
+
+fn io_kind(err: &io::Error) -> ErrorKind {
+    match err.kind() {
+        io::ErrorKind::PermissionDenied => ErrorKind::AuthOrPermission,
+        // Missing files are almost always sysfs attributes
+        // of a driver that is not loaded.
+        io::ErrorKind::NotFound => ErrorKind::DriverMissing,
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::AddrNotAvailable
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof => ErrorKind::NetworkUnreachable,
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ErrorKind::TimedOut,
+        io::ErrorKind::InvalidInput => ErrorKind::BadInput,
+        _ => ErrorKind::Other,
+    }
+}
+
+impl Error {
+    /// Classifies this error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Attach(err) => match err {
+                attach::Error::ImportFailed(Status::DevBusy) | attach::Error::DeviceBusy(_) => {
+                    ErrorKind::DeviceBusy
+                }
+                attach::Error::ImportFailed(Status::NoDev)
+                | attach::Error::DevidNotExported(_)
+                | attach::Error::NoMatchingDevice(_)
+                | attach::Error::NotRecorded(_) => ErrorKind::DeviceNotFound,
+                attach::Error::ImportFailed(_)
+                | attach::Error::DevlistFailed(_)
+                | attach::Error::NotEnumerated(_)
+                | attach::Error::Cancelled => ErrorKind::Other,
+                attach::Error::TimedOut | attach::Error::DeadlineExceeded => ErrorKind::TimedOut,
+                attach::Error::ConnectionClosed(_) => ErrorKind::NetworkUnreachable,
+                attach::Error::UnsupportedSpeed(_) | attach::Error::AttachRejected(_) => {
+                    ErrorKind::ProtocolIncompatible
+                }
+                attach::Error::NoFreePort(_)
+                | attach::Error::PortUnavailable(_)
+                | attach::Error::PortBusy(_) => ErrorKind::NoFreePort,
+                attach::Error::NoHub(_) | attach::Error::NoController(_) => {
+                    ErrorKind::DriverMissing
+                }
+                attach::Error::SharedNotSupported => ErrorKind::BadInput,
+            },
+            Error::Descriptor(_) | Error::Validation(_) => ErrorKind::ProtocolIncompatible,
+            #[cfg(feature = "server")]
+            Error::Server(_) => ErrorKind::ProtocolIncompatible,
+            Error::Detach(err) => match err {
+                detach::Error::InvalidPort { .. } => ErrorKind::BadInput,
+                detach::Error::PortAlreadyDetached(_) | detach::Error::NotAUsbipDevice => {
+                    ErrorKind::DeviceNotFound
+                }
+                detach::Error::TimedOut(_) => ErrorKind::TimedOut,
+                detach::Error::DetachNotConfirmed(_) => ErrorKind::Other,
+            },
+            Error::Device(_) => ErrorKind::BadInput,
+            Error::Net(err) => match err {
+                net::Error::VersionMismatch { .. }
+                | net::Error::UnsupportedVersion(_)
+                | net::Error::BusIdMismatch(_)
+                | net::Error::FieldTooLong(_) => ErrorKind::ProtocolIncompatible,
+                net::Error::InvalidEndpoint(_) => ErrorKind::BadInput,
+                #[cfg(feature = "socks")]
+                net::Error::ProxyFailed(_) | net::Error::ProxyRejected(_) => {
+                    ErrorKind::NetworkUnreachable
+                }
+            },
+            Error::Io(err) => io_kind(err),
+            Error::Wire(err) => match &**err {
+                bincode::ErrorKind::Io(err) => io_kind(err),
+                _ => ErrorKind::ProtocolIncompatible,
+            },
+            Error::Driver(_) => ErrorKind::DriverMissing,
+            Error::Other(_) => ErrorKind::Other,
+        }
+    }
+
+    /// Returns a suggestion for fixing this error, see
+    /// [`ErrorKind::hint`].
+    pub fn hint(&self) -> &'static str {
+        self.kind().hint()
+    }
+}
+
+/// A `Result` whose error is this crate's [`Error`].
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Attach(err) => err.fmt(f),
+            Error::Descriptor(err) => err.fmt(f),
+            Error::Detach(err) => err.fmt(f),
+            Error::Device(err) => err.fmt(f),
+            Error::Validation(err) => err.fmt(f),
+            Error::Net(err) => err.fmt(f),
+            #[cfg(feature = "server")]
+            Error::Server(err) => err.fmt(f),
+            Error::Io(err) => err.fmt(f),
+            Error::Wire(err) => err.fmt(f),
+            Error::Driver(err) => err.fmt(f),
+            Error::Other(err) => err.fmt(f),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Attach(err) => err.source(),
+            Error::Descriptor(err) => err.source(),
+            Error::Detach(err) => err.source(),
+            Error::Device(err) => err.source(),
+            Error::Validation(err) => err.source(),
+            Error::Net(err) => err.source(),
+            #[cfg(feature = "server")]
+            Error::Server(err) => err.source(),
+            Error::Io(err) => err.source(),
+            Error::Wire(err) => err.source(),
+            Error::Driver(err) => err.source(),
+            Error::Other(err) => err.source(),
+        }
+    }
+}
+
+impl From<Box<dyn StdError>> for Error {
+    fn from(err: Box<dyn StdError>) -> Self {
+        let err = match err.downcast() {
+            Ok(err) => return Error::Attach(*err),
+            Err(err) => err,
+        };
+        let err = match err.downcast() {
+            Ok(err) => return Error::Descriptor(*err),
+            Err(err) => err,
+        };
+        let err = match err.downcast() {
+            Ok(err) => return Error::Detach(*err),
+            Err(err) => err,
+        };
+        let err = match err.downcast() {
+            Ok(err) => return Error::Device(*err),
+            Err(err) => err,
+        };
+        let err = match err.downcast() {
+            Ok(err) => return Error::Validation(*err),
+            Err(err) => err,
+        };
+        let err = match err.downcast() {
+            Ok(err) => return Error::Net(*err),
+            Err(err) => err,
+        };
+        #[cfg(feature = "server")]
+        let err = match err.downcast() {
+            Ok(err) => return Error::Server(*err),
+            Err(err) => err,
+        };
+        let err = match err.downcast() {
+            Ok(err) => return Error::Io(*err),
+            Err(err) => err,
+        };
+        let err = match err.downcast() {
+            Ok(err) => return Error::Wire(*err),
+            Err(err) => err,
+        };
+        match err.downcast() {
+            Ok(err) => Error::Driver(*err),
+            Err(err) => Error::Other(err.to_string().into()),
+        }
+    }
+}
+
+impl From<attach::Error> for Error {
+    fn from(err: attach::Error) -> Self {
+        Error::Attach(err)
+    }
+}
+
+impl From<descriptor::Error> for Error {
+    fn from(err: descriptor::Error) -> Self {
+        Error::Descriptor(err)
+    }
+}
+
+impl From<detach::Error> for Error {
+    fn from(err: detach::Error) -> Self {
+        Error::Detach(err)
+    }
+}
+
+impl From<device::Error> for Error {
+    fn from(err: device::Error) -> Self {
+        Error::Device(err)
+    }
+}
+
+impl From<device::ValidationError> for Error {
+    fn from(err: device::ValidationError) -> Self {
+        Error::Validation(err)
+    }
+}
+
+impl From<net::Error> for Error {
+    fn from(err: net::Error) -> Self {
+        Error::Net(err)
+    }
+}
+
+#[cfg(feature = "server")]
+impl From<server::Error> for Error {
+    fn from(err: server::Error) -> Self {
+        Error::Server(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Self {
+        Error::Wire(err)
+    }
+}
+
+impl From<vhci::Error> for Error {
+    fn from(err: vhci::Error) -> Self {
+        Error::Driver(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Boxes `err` as this crate's functions return it and
+    /// recovers it.
+    fn unbox(err: impl StdError + 'static) -> Error {
+        Error::from(Box::new(err) as Box<dyn StdError>)
+    }
+
+    #[test]
+    fn boxed_errors_come_back_as_their_variant() {
+        // The error, a check for its variant, and its kind.
+        type Case = (Error, fn(&Error) -> bool, ErrorKind);
+        let cases: [Case; 9] = [
+            (
+                unbox(attach::Error::DeviceBusy("1-1".into())),
+                |err| matches!(err, Error::Attach(_)),
+                ErrorKind::DeviceBusy,
+            ),
+            (
+                unbox(descriptor::Error::Malformed { offset: 3 }),
+                |err| matches!(err, Error::Descriptor(_)),
+                ErrorKind::ProtocolIncompatible,
+            ),
+            (
+                unbox(detach::Error::TimedOut(3)),
+                |err| matches!(err, Error::Detach(_)),
+                ErrorKind::TimedOut,
+            ),
+            (
+                unbox(device::Error::InvalidMatch("1d6b".into())),
+                |err| matches!(err, Error::Device(_)),
+                ErrorKind::BadInput,
+            ),
+            (
+                unbox(device::ValidationError::ZeroVendor),
+                |err| matches!(err, Error::Validation(_)),
+                ErrorKind::ProtocolIncompatible,
+            ),
+            (
+                unbox(net::Error::InvalidEndpoint(":3240".into())),
+                |err| matches!(err, Error::Net(_)),
+                ErrorKind::BadInput,
+            ),
+            (
+                unbox(io::Error::from(io::ErrorKind::PermissionDenied)),
+                |err| matches!(err, Error::Io(_)),
+                ErrorKind::AuthOrPermission,
+            ),
+            (
+                unbox(Box::new(bincode::ErrorKind::SizeLimit) as bincode::Error),
+                |err| matches!(err, Error::Wire(_)),
+                ErrorKind::ProtocolIncompatible,
+            ),
+            (
+                unbox(vhci::Error::DriverNotLoaded),
+                |err| matches!(err, Error::Driver(_)),
+                ErrorKind::DriverMissing,
+            ),
+        ];
+        for (err, is_variant, kind) in cases {
+            assert!(is_variant(&err), "{err:?}");
+            assert_eq!(err.kind(), kind, "{err:?}");
+            assert_eq!(err.hint(), kind.hint(), "{err:?}");
+        }
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn boxed_server_errors_come_back_as_their_variant() {
+        let err = unbox(server::Error::UnknownRequest(0x8042));
+        assert!(matches!(err, Error::Server(_)));
+        assert_eq!(err.kind(), ErrorKind::ProtocolIncompatible);
+    }
+
+    #[test]
+    fn wire_errors_are_classified_by_their_io_error() {
+        let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+        let err = unbox(Box::new(bincode::ErrorKind::Io(eof)) as bincode::Error);
+        assert!(matches!(err, Error::Wire(_)));
+        assert_eq!(err.kind(), ErrorKind::NetworkUnreachable);
+    }
+
+    #[test]
+    fn attach_errors_are_classified_by_their_cause() {
+        let cases = [
+            (
+                attach::Error::ImportFailed(Status::NoDev),
+                ErrorKind::DeviceNotFound,
+            ),
+            (
+                attach::Error::ImportFailed(Status::DevBusy),
+                ErrorKind::DeviceBusy,
+            ),
+            (
+                attach::Error::ConnectionClosed(0),
+                ErrorKind::NetworkUnreachable,
+            ),
+            (attach::Error::DeadlineExceeded, ErrorKind::TimedOut),
+            (attach::Error::Cancelled, ErrorKind::Other),
+        ];
+        for (err, kind) in cases {
+            assert_eq!(Error::from(err).kind(), kind);
+        }
+    }
+
+    #[test]
+    fn foreign_errors_keep_their_message() {
+        let err = unbox(fmt::Error);
+        assert!(matches!(err, Error::Other(_)));
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert_eq!(err.to_string(), fmt::Error.to_string());
+        assert_eq!(err.hint(), "see the error message for details");
+    }
+
+    #[test]
+    fn hints_suggest_a_fix() {
+        let err = Error::from(vhci::Error::DriverNotLoaded);
+        assert_eq!(
+            err.hint(),
+            "load the vhci-hcd kernel module (modprobe vhci-hcd)"
+        );
+        let err = Error::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert_eq!(err.hint(), "run as root or with CAP_SYS_ADMIN");
+    }
+}
@@ -0,0 +1,245 @@
+use std::fmt::Write;
+
+use usbip_core::UsbDevice;
+
+use crate::{
+    attach::{AttachmentInfo, PortRecord},
+    buffer_to_string,
+    device::UsbDeviceExt,
+};
+
+/// Lays out `rows` under `headers`, padding every column
+/// but the last to its widest cell.
+fn table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers
+        .iter()
+        .map(|header| header.chars().count())
+        .collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    let headers = headers.iter().map(|header| header.to_string()).collect();
+    for row in std::iter::once(&headers).chain(rows) {
+        let mut line = String::new();
+        for (i, (cell, width)) in row.iter().zip(&widths).enumerate() {
+            if i + 1 == row.len() {
+                line.push_str(cell);
+            } else {
+                let _ = write!(line, "{cell:<width$}  ");
+            }
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// Formats the ports in use among `records` as a table,
+/// in the spirit of `usbip port`, with the remote end
+/// taken from each port's state file where there is one.
+///
+/// Get `records` from [`crate::attach::ports`]. Free ports
+/// are left out, as the C tool does.
+pub fn attachments(records: &[PortRecord]) -> String {
+    let unknown = || "-".to_owned();
+    let rows: Vec<Vec<String>> = records
+        .iter()
+        .filter(|record| !record.is_free())
+        .map(|record| {
+            let remote = record.remote.as_ref();
+            vec![
+                format!("{:02}", record.port),
+                remote.map_or_else(unknown, |info| info.bus_id().to_string()),
+                record.vid_pid.map_or_else(unknown, |(vendor, product)| {
+                    format!("{vendor:04x}:{product:04x}")
+                }),
+                record.speed.to_string(),
+                record.status_str().to_string(),
+                remote.map_or_else(unknown, remote_url),
+            ]
+        })
+        .collect();
+    table(
+        &["PORT", "BUSID", "VID:PID", "SPEED", "STATUS", "REMOTE"],
+        &rows,
+    )
+}
+
+/// Formats where an attachment came from as a URL.
+fn remote_url(info: &AttachmentInfo) -> String {
+    format!(
+        "usbip://{}:{}/{}",
+        info.host(),
+        info.tcp_port(),
+        info.bus_id()
+    )
+}
+
+/// Formats exported devices as a table, in the spirit of
+/// `usbip list`.
+pub fn devices(devices: &[UsbDevice]) -> String {
+    let rows: Vec<Vec<String>> = devices
+        .iter()
+        .map(|udev| {
+            vec![
+                buffer_to_string(udev.bus_id()).into_owned(),
+                format!("{:04x}:{:04x}", udev.id_vendor(), udev.id_product()),
+                udev.speed_kind().to_string(),
+                format!(
+                    "{:02x}/{:02x}/{:02x}",
+                    udev.b_device_class(),
+                    udev.b_device_subclass(),
+                    udev.b_device_protocol()
+                ),
+                buffer_to_string(udev.path()).into_owned(),
+            ]
+        })
+        .collect();
+    table(&["BUSID", "VID:PID", "SPEED", "CLASS", "PATH"], &rows)
+}
+
+/// Formats vhci ports exactly as `usbip port` does, under
+/// the same heading, followed by any free ports.
+pub fn ports(records: &[PortRecord]) -> String {
+    let mut out = String::from("Imported USB devices\n====================\n");
+    let (free, used): (Vec<_>, Vec<_>) = records.iter().partition(|record| record.is_free());
+    for record in used.iter().chain(&free) {
+        let _ = writeln!(out, "{record}");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device::Speed, state, test_support::DeviceBytes, Port};
+    use usbip_core::DeviceStatus;
+
+    const PATH: &str = "/sys/devices/pci0000:00/0000:00:14.0/usb1/1-1";
+
+    fn used(port: u8, bus_id: &str) -> PortRecord {
+        let record = state::Record {
+            host: "10.0.0.2".into(),
+            tcp_port: 3240,
+            bus_id: bus_id.into(),
+            version: Some(0x0111),
+        };
+        PortRecord {
+            port: Port::from_driver(port),
+            status: DeviceStatus::DeviceUsed,
+            speed: Speed::High,
+            devid: 1 << 16 | 2,
+            remote: Some(AttachmentInfo::new(port, record)),
+            local_bus_id: Some("3-1".to_owned()),
+            vid_pid: Some((0x1d6b, 0x0104)),
+        }
+    }
+
+    fn free(port: u8) -> PortRecord {
+        PortRecord {
+            port: Port::from_driver(port),
+            status: DeviceStatus::PortAvailable,
+            speed: Speed::Unknown,
+            devid: 0,
+            remote: None,
+            local_bus_id: None,
+            vid_pid: None,
+        }
+    }
+
+    #[test]
+    fn devices_lines_up_columns() {
+        let listed = [DeviceBytes::default().parse()];
+        assert_eq!(
+            devices(&listed),
+            format!(
+                "BUSID  VID:PID    SPEED                CLASS     PATH\n\
+                 1-1    1d6b:0104  High Speed(480Mbps)  00/00/00  {PATH}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn devices_widens_columns_to_a_long_bus_id() {
+        let path = "/sys/devices/pci0000:00/0000:00:14.0/usb1/1-1/1-1.2/1-1.2.3";
+        let listed = [
+            DeviceBytes::default().parse(),
+            DeviceBytes {
+                path,
+                bus_id: "1-1.2.3",
+                speed: 2,
+                ..DeviceBytes::default()
+            }
+            .parse(),
+        ];
+        assert_eq!(
+            devices(&listed),
+            format!(
+                "BUSID    VID:PID    SPEED                CLASS     PATH\n\
+                 1-1      1d6b:0104  High Speed(480Mbps)  00/00/00  {PATH}\n\
+                 1-1.2.3  1d6b:0104  Full Speed(12Mbps)   00/00/00  {path}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn devices_of_an_empty_list_is_the_header() {
+        assert_eq!(devices(&[]), "BUSID  VID:PID  SPEED  CLASS  PATH\n");
+    }
+
+    #[test]
+    fn attachments_leaves_out_free_ports() {
+        let records = [free(0), used(1, "1-1"), used(2, "1-1.2.3")];
+        assert_eq!(
+            attachments(&records),
+            "PORT  BUSID    VID:PID    SPEED                STATUS       REMOTE\n\
+             01    1-1      1d6b:0104  High Speed(480Mbps)  Port in Use  usbip://10.0.0.2:3240/1-1\n\
+             02    1-1.2.3  1d6b:0104  High Speed(480Mbps)  Port in Use  usbip://10.0.0.2:3240/1-1.2.3\n"
+        );
+    }
+
+    #[test]
+    fn attachments_marks_unknown_fields() {
+        let record = PortRecord {
+            remote: None,
+            vid_pid: None,
+            ..used(0, "1-1")
+        };
+        assert_eq!(
+            attachments(&[record]),
+            "PORT  BUSID  VID:PID  SPEED                STATUS       REMOTE\n\
+             00    -      -        High Speed(480Mbps)  Port in Use  -\n"
+        );
+    }
+
+    #[test]
+    fn attachments_of_no_used_ports_is_the_header() {
+        let header = "PORT  BUSID  VID:PID  SPEED  STATUS  REMOTE\n";
+        assert_eq!(attachments(&[]), header);
+        assert_eq!(attachments(&[free(0), free(1)]), header);
+    }
+
+    #[test]
+    fn ports_matches_the_c_tool() {
+        let records = [free(0), used(1, "1-1")];
+        assert_eq!(
+            ports(&records),
+            "Imported USB devices\n\
+             ====================\n\
+             Port 01: <Port in Use> at High Speed(480Mbps)\n       \
+             unknown vendor : unknown product (1d6b:0104)\n       \
+             3-1 -> usbip://10.0.0.2:3240/1-1\n           \
+             -> remote bus/dev 001/002\n\
+             Port 00: <Port Available>\n"
+        );
+    }
+
+    #[test]
+    fn ports_of_an_empty_list_is_the_heading() {
+        assert_eq!(ports(&[]), "Imported USB devices\n====================\n");
+    }
+}
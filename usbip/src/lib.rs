@@ -1,30 +1,33 @@
+pub mod attach;
+mod buffer;
+pub mod descriptor;
 pub mod detach;
-pub mod attach {
-    use usbip_core::buffer::Buffer;
+pub mod device;
+mod error;
+pub mod format;
+pub mod net;
+mod port;
+pub mod selftest;
+#[cfg(feature = "server")]
+pub mod server;
+mod state;
+pub mod supervisor;
+mod sysfs;
+#[cfg(test)]
+mod test_support;
+
+pub use attach::Error as AttachError;
+pub use buffer::{buffer_to_string, from_cstr_like};
+pub use descriptor::Error as DescriptorError;
+pub use detach::{detach_all_owned, Error as DetachError};
+pub use device::Error as DeviceError;
+pub use error::{Error, ErrorKind, Result};
+pub use net::Error as NetError;
+pub use port::Port;
+pub use selftest::run as selftest;
+#[cfg(feature = "server")]
+pub use server::Error as ServerError;
 
-    use crate::{net, protocol};
-
-    fn query_import_device<S>(mut socket: S, bus_id: &str) -> bincode::Result<u16>
-    where
-        S: net::Send + net::Recv,
-    {
-        let request = net::OpCommon {
-            version: net::VERSION,
-            code: protocol::OP_REQ_IMPORT,
-            status: usbip_core::net::Status::Success,
-        };
-
-        socket.send(&request)?;
-
-        let request = net::OpImportRequest {
-            bus_id: Buffer::try_from(bus_id.as_bytes()).unwrap(),
-        };
-
-        socket.send(&request)?;
-
-        todo!()
-    }
-}
 pub mod protocol {
     // Common header for all the kinds of PDUs.
     pub const OP_REQUEST: u16 = 0x80 << 8;
@@ -49,142 +52,68 @@ pub mod protocol {
     pub const OP_EXPORT: u16 = 0x06;
     pub const OP_REQ_EXPORT: u16 = OP_REQUEST | OP_EXPORT;
     pub const OP_REP_EXPORT: u16 = OP_REPLY | OP_EXPORT;
-}
-
-pub mod net {
-    use bincode::Options;
-    use serde::{de::DeserializeOwned, Deserialize, Serialize};
-    use std::{io, os::fd::AsRawFd};
-    use usbip_core::{buffer::Buffer, UsbDevice, SYSFS_BUS_ID_SIZE};
-
-    pub use error::Error;
-
-    use crate::protocol::OP_UNSPEC;
-
-    mod error {
-        use std::fmt;
-
-        #[derive(Debug, Clone)]
-        pub enum Error {
-            VersionMismatch(u16),
-            BusIdMismatch(Box<str>),
-        }
-
-        impl fmt::Display for Error {
-            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                match self {
-                    Error::VersionMismatch(bad_version) => write!(
-                        f,
-                        "version mismatch! Them: {}, Us: {}",
-                        bad_version,
-                        super::VERSION
-                    ),
-                    Error::BusIdMismatch(bus_id) => write!(f, "received different busid: {bus_id}"),
-                }
-            }
-        }
-
-        impl std::error::Error for Error {}
-    }
-
-    pub const VERSION: u16 = 273;
 
-    #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-    pub struct OpCommon {
-        pub version: u16,
-        pub code: u16,
-        pub status: usbip_core::net::Status,
-    }
-
-    impl OpCommon {
-        pub fn validate(&self, code: u16) -> Result<usbip_core::net::Status, Error> {
-            if self.version != VERSION {
-                Err(Error::VersionMismatch(self.version))
-            } else if !matches!(code, OP_UNSPEC) && code != self.code {
-                Ok(usbip_core::net::Status::Unexpected)
-            } else {
-                Ok(self.status)
-            }
-        }
-    }
-
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct OpImportRequest {
-        pub bus_id: Buffer<SYSFS_BUS_ID_SIZE, i8>,
-    }
+    // The high byte holds OP_REQUEST or OP_REPLY, the low
+    // byte the operation.
+    const OP_KIND_MASK: u16 = 0xff << 8;
+    const OP_MASK: u16 = 0xff;
 
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct OpImportReply {
-        pub udev: UsbDevice,
+    /// Returns `true` if `code` is laid out as a request,
+    /// such as `OP_REQ_IMPORT`.
+    pub const fn is_request(code: u16) -> bool {
+        code & OP_KIND_MASK == OP_REQUEST
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct OpDevlistReply {
-        pub ndev: u32,
+    /// Returns `true` if `code` is laid out as a reply to
+    /// an operation, such as `OP_REP_IMPORT`. `OP_UNSPEC`
+    /// names no operation, so it is not a reply.
+    pub const fn is_reply(code: u16) -> bool {
+        code & OP_KIND_MASK == OP_REPLY && code & OP_MASK != OP_UNSPEC
     }
 
-    fn socket_set_keepalive(socket: &std::net::TcpStream, keepalive: bool) -> io::Result<()> {
-        use libc::{c_int, c_void, socklen_t};
-
-        let val = c_int::from(keepalive);
-        let rc = unsafe {
-            libc::setsockopt(
-                socket.as_raw_fd(),
-                libc::SOL_SOCKET,
-                libc::SO_KEEPALIVE,
-                std::ptr::addr_of!(val).cast::<c_void>(),
-                socklen_t::try_from(std::mem::size_of::<c_int>()).unwrap(),
-            )
-        };
-        if rc < 0 {
-            Err(io::Error::last_os_error())
+    /// Returns the request that `reply_code` answers, such
+    /// as `OP_REQ_DEVLIST` for `OP_REP_DEVLIST`, or `None`
+    /// if `reply_code` is not a reply.
+    pub const fn matching_request(reply_code: u16) -> Option<u16> {
+        if is_reply(reply_code) {
+            Some(OP_REQUEST | (reply_code & OP_MASK))
         } else {
-            Ok(())
+            None
         }
     }
 
-    /// Opens a TCP connection to a remote host.
-    /// It is not required to use this function to initiate
-    /// the connection, as long as these socket options
-    /// (or their equivalents) are set:
-    /// - `NoDelay` is enabled (disables the Nagle algorithm)
-    /// - `KeepAlive` is enabled
-    pub fn connect<A>(host: A) -> io::Result<std::net::TcpStream>
-    where
-        A: std::net::ToSocketAddrs,
-    {
-        let socket = std::net::TcpStream::connect(host)?;
-        socket.set_nodelay(true)?;
-        socket_set_keepalive(&socket, true)?;
-        Ok(socket)
-    }
-
-    fn bincode_options() -> impl bincode::Options {
-        bincode::DefaultOptions::new()
-            .with_no_limit()
-            .with_big_endian()
-            .with_fixint_encoding()
-            .allow_trailing_bytes()
-    }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const PAIRS: [(u16, u16); 3] = [
+            (OP_REQ_IMPORT, OP_REP_IMPORT),
+            (OP_REQ_DEVLIST, OP_REP_DEVLIST),
+            (OP_REQ_EXPORT, OP_REP_EXPORT),
+        ];
+
+        #[test]
+        fn replies_pair_with_their_requests() {
+            for (request, reply) in PAIRS {
+                assert!(is_reply(reply), "{reply:#06x}");
+                assert!(!is_request(reply), "{reply:#06x}");
+                assert_eq!(matching_request(reply), Some(request));
+            }
+        }
 
-    pub trait Send: io::Write {
-        fn send<T>(&mut self, value: &T) -> bincode::Result<()>
-        where
-            T: Serialize + ?Sized,
-        {
-            bincode_options().serialize_into(self, value)
+        #[test]
+        fn requests_are_not_replies() {
+            for (request, _) in PAIRS {
+                assert!(is_request(request), "{request:#06x}");
+                assert!(!is_reply(request), "{request:#06x}");
+                assert_eq!(matching_request(request), None);
+            }
         }
-    }
 
-    pub trait Recv: io::Read {
-        fn recv<T>(&mut self) -> bincode::Result<T>
-        where
-            T: DeserializeOwned,
-        {
-            bincode_options().deserialize_from(self)
+        #[test]
+        fn unspecified_code_is_not_a_reply() {
+            assert!(!is_reply(OP_UNSPEC));
+            assert_eq!(matching_request(OP_UNSPEC), None);
         }
     }
-
-    impl Recv for std::net::TcpStream {}
-    impl Send for std::net::TcpStream {}
 }
@@ -0,0 +1,1864 @@
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    error::Error as StdError,
+    ffi::{CString, OsStr},
+    io,
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs},
+    os::fd::{AsRawFd, RawFd},
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+use usbip_core::{buffer::Buffer, UsbDevice, UsbInterface, SYSFS_BUS_ID_SIZE};
+
+pub use error::Error;
+#[cfg(feature = "socks")]
+pub use socks::connect_via_socks5;
+
+use crate::{
+    buffer::is_nul_terminated,
+    protocol::{OP_REQ_DEVLIST, OP_UNSPEC},
+};
+
+#[cfg(feature = "socks")]
+mod socks;
+#[cfg(feature = "test-util")]
+pub mod testing;
+
+mod error {
+    use std::fmt;
+
+    #[derive(Debug, Clone)]
+    pub enum Error {
+        VersionMismatch {
+            expected: u16,
+            received: u16,
+        },
+        UnsupportedVersion(u16),
+        BusIdMismatch(Box<str>),
+        InvalidEndpoint(Box<str>),
+        FieldTooLong(&'static str),
+        #[cfg(feature = "socks")]
+        ProxyFailed(&'static str),
+        #[cfg(feature = "socks")]
+        ProxyRejected(u8),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::VersionMismatch { expected, received } => {
+                    write!(f, "version mismatch! Them: {}, Us: {}", received, expected)
+                }
+                Error::UnsupportedVersion(version) => {
+                    write!(f, "unsupported protocol version: {version:#06x}")
+                }
+                Error::BusIdMismatch(bus_id) => write!(f, "received different busid: {bus_id}"),
+                Error::InvalidEndpoint(endpoint) => {
+                    write!(f, "invalid host or port: {endpoint}")
+                }
+                Error::FieldTooLong(field) => {
+                    write!(f, "device {field} does not fit in its field")
+                }
+                #[cfg(feature = "socks")]
+                Error::ProxyFailed(reason) => write!(f, "SOCKS5 proxy failed: {reason}"),
+                #[cfg(feature = "socks")]
+                Error::ProxyRejected(code) => {
+                    write!(f, "SOCKS5 proxy could not connect: reply code {code:#04x}")
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+pub const VERSION: u16 = 273;
+
+/// A USB/IP protocol version, encoded as it appears
+/// in the `OpCommon` header (`0x0111` for 1.1.1).
+///
+/// Defaults to [`VERSION`]. Other versions are only
+/// needed to talk to legacy servers that insist on
+/// their own version number in every header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProtocolVersion(u16);
+
+impl ProtocolVersion {
+    pub const CURRENT: Self = Self(VERSION);
+
+    /// Returns `version` as a `ProtocolVersion`.
+    ///
+    /// # Error
+    /// This function returns an error if `version` is not
+    /// a 1.x version. Every 1.x release shares the PDU
+    /// layout this crate parses; anything else does not.
+    pub const fn new(version: u16) -> Result<Self, Error> {
+        if version >> 8 == 0x01 {
+            Ok(Self(version))
+        } else {
+            Err(Error::UnsupportedVersion(version))
+        }
+    }
+
+    pub const fn get(self) -> u16 {
+        self.0
+    }
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        Self::CURRENT
+    }
+}
+
+/// What a server supports, as far as can be told.
+///
+/// USB/IP has no way for a server to advertise features:
+/// `OpCommon` has no reserved bits and there is no
+/// capabilities request. Everything here is inferred from
+/// the version in the server's reply header:
+///
+/// - [`VERSION`]: lists and imports devices.
+/// - Any other 1.x version: lists and imports devices,
+///   but is `legacy`, so requests should carry that version
+///   (see [`ProtocolVersion`]).
+/// - Anything else: nothing this crate can talk to.
+///
+/// Every server speaks TCP only, and the protocol puts no
+/// limit on how many devices one exports, so neither is
+/// reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The version the server replied with.
+    pub version: u16,
+    /// The server answers devlist requests.
+    pub devlist: bool,
+    /// The server answers import requests.
+    pub import: bool,
+    /// The server speaks a 1.x version other than
+    /// [`VERSION`].
+    pub legacy: bool,
+}
+
+impl Capabilities {
+    /// Infers what a server replying with `version`
+    /// supports, see [`Capabilities`].
+    pub fn from_version(version: u16) -> Self {
+        let compatible = ProtocolVersion::new(version).is_ok();
+        Self {
+            version,
+            devlist: compatible,
+            import: compatible,
+            legacy: compatible && version != VERSION,
+        }
+    }
+
+    /// The version to send in requests to this server,
+    /// or `None` if it speaks no version this crate can
+    /// talk to.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        ProtocolVersion::new(self.version).ok()
+    }
+}
+
+/// The TCP port USB/IP servers listen on by default.
+pub const USBIP_PORT: u16 = 3240;
+
+/// The environment variable that overrides [`USBIP_PORT`],
+/// see [`default_port`].
+pub const USBIP_TCP_PORT_VAR: &str = "USBIP_TCP_PORT";
+
+/// Returns the TCP port to connect to when a host is given
+/// without one: the value of the `USBIP_TCP_PORT`
+/// environment variable if it is set, or else
+/// [`USBIP_PORT`].
+///
+/// The variable is read once, the first time this is
+/// called, so every connection in the process uses the
+/// same port. A value that is not a TCP port number
+/// (surrounding whitespace is allowed) is ignored with a
+/// warning.
+pub fn default_port() -> u16 {
+    static PORT: OnceLock<u16> = OnceLock::new();
+    *PORT.get_or_init(|| {
+        let value = std::env::var_os(USBIP_TCP_PORT_VAR);
+        parse_port(value.as_deref().map(OsStr::to_string_lossy).as_deref())
+    })
+}
+
+/// Returns the port `value` of `USBIP_TCP_PORT` asks for,
+/// see [`default_port`].
+fn parse_port(value: Option<&str>) -> u16 {
+    let Some(value) = value else {
+        return USBIP_PORT;
+    };
+    match value.trim().parse() {
+        Ok(port) if port != 0 => port,
+        _ => {
+            log::warn!("ignoring invalid {USBIP_TCP_PORT_VAR}={value:?}, using port {USBIP_PORT}");
+            USBIP_PORT
+        }
+    }
+}
+
+/// A USB/IP server's host and TCP port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    host: Box<str>,
+    port: u16,
+}
+
+impl Endpoint {
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub const fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns the address of a host given as an IPv6
+    /// address with a scope, such as `fe80::1%eth0` or
+    /// `fe80::1%2`, or `None` for any other host.
+    ///
+    /// # Error
+    /// This function returns an error if the scope names
+    /// no local network interface.
+    pub fn scoped_addr(&self) -> io::Result<Option<SocketAddr>> {
+        let Some((addr, scope)) = self.host.split_once('%') else {
+            return Ok(None);
+        };
+        let Ok(addr) = addr.parse::<Ipv6Addr>() else {
+            return Ok(None);
+        };
+        let scope_id = match scope.parse() {
+            Ok(index) => index,
+            Err(_) => interface_index(scope)?,
+        };
+        Ok(Some(SocketAddrV6::new(addr, self.port, 0, scope_id).into()))
+    }
+}
+
+/// Looks up the index of the local network interface
+/// `name`, such as `eth0`.
+fn interface_index(name: &str) -> io::Result<u32> {
+    let unknown = || {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no network interface named {name:?}"),
+        )
+    };
+    let c_name = CString::new(name).map_err(|_| unknown())?;
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => Err(unknown()),
+        index => Ok(index),
+    }
+}
+
+impl std::net::ToSocketAddrs for Endpoint {
+    type Iter = std::vec::IntoIter<std::net::SocketAddr>;
+
+    /// Resolves the endpoint. Scoped IPv6 addresses are
+    /// resolved here rather than by the system resolver,
+    /// which does not handle the scope the same way on
+    /// every platform.
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        match self.scoped_addr()? {
+            Some(addr) => Ok(vec![addr].into_iter()),
+            None => (&*self.host, self.port).to_socket_addrs(),
+        }
+    }
+}
+
+/// Parses a server address given as `host`, `host:port`,
+/// a bare IPv6 address, or `[addr]:port`.
+///
+/// IPv6 addresses may carry a scope after a `%`, as
+/// link-local addresses need: `fe80::1%eth0` or
+/// `[fe80::1%eth0]:3240`. The scope is an interface name
+/// or index, and is kept in the host; it is resolved when
+/// connecting (see [`Endpoint::scoped_addr`]).
+///
+/// Hosts without an explicit port use [`default_port`].
+///
+/// # Error
+/// This function returns an error if the host is empty
+/// or the port is not a valid TCP port number.
+pub fn parse_endpoint(s: &str) -> Result<Endpoint, Error> {
+    let invalid = || Error::InvalidEndpoint(s.into());
+
+    let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+        match rest {
+            "" => (host, None),
+            _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+        }
+    } else {
+        match s.split_once(':') {
+            // More than one colon means a bare IPv6 address.
+            Some((_, rest)) if rest.contains(':') => (s, None),
+            Some((host, port)) => (host, Some(port)),
+            None => (s, None),
+        }
+    };
+
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| invalid())?,
+        None => default_port(),
+    };
+
+    Ok(Endpoint {
+        host: host.into(),
+        port,
+    })
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct OpCommon {
+    pub version: u16,
+    pub code: u16,
+    pub status: usbip_core::net::Status,
+}
+
+impl OpCommon {
+    /// Builds the header of a request for `code`, at the
+    /// current protocol version.
+    pub const fn request(code: u16) -> Self {
+        Self {
+            version: VERSION,
+            code,
+            status: usbip_core::net::Status::Success,
+        }
+    }
+
+    /// Builds the header of a reply to `code` carrying
+    /// `status`, at the current protocol version.
+    pub const fn reply(code: u16, status: usbip_core::net::Status) -> Self {
+        Self {
+            version: VERSION,
+            code,
+            status,
+        }
+    }
+
+    /// Returns this header sent as `version` instead.
+    pub const fn with_version(self, version: ProtocolVersion) -> Self {
+        Self {
+            version: version.get(),
+            ..self
+        }
+    }
+
+    /// Checks that this header carries `version` and a
+    /// reply for `code` (any code if `OP_UNSPEC`),
+    /// returning the status it carries.
+    pub fn validate(
+        &self,
+        code: u16,
+        version: ProtocolVersion,
+    ) -> Result<usbip_core::net::Status, Error> {
+        if self.version != version.get() {
+            Err(Error::VersionMismatch {
+                expected: version.get(),
+                received: self.version,
+            })
+        } else if !matches!(code, OP_UNSPEC) && code != self.code {
+            Ok(usbip_core::net::Status::Unexpected)
+        } else {
+            Ok(self.status)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpImportRequest {
+    pub bus_id: Buffer<SYSFS_BUS_ID_SIZE, i8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpImportReply {
+    pub udev: UsbDevice,
+}
+
+/// Checks that the string fields of a device received
+/// from a server are NUL-terminated.
+///
+/// Some non-standard servers send paths longer than the
+/// fixed-size field. The wire format has no room for
+/// them, and every field after the overlong one is then
+/// misread, so this is reported as an error naming the
+/// field instead of surfacing as garbage further on.
+///
+/// # Error
+/// This function returns `Error::FieldTooLong` if the
+/// path or bus id fills its whole field.
+pub fn check_device(udev: &UsbDevice) -> Result<(), Error> {
+    if !is_nul_terminated(udev.path()) {
+        Err(Error::FieldTooLong("path"))
+    } else if !is_nul_terminated(udev.bus_id()) {
+        Err(Error::FieldTooLong("bus id"))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpDevlistReply {
+    pub ndev: u32,
+}
+
+#[cfg(feature = "socket2")]
+pub(crate) fn socket_set_keepalive(
+    socket: &std::net::TcpStream,
+    keepalive: bool,
+) -> io::Result<()> {
+    socket2::SockRef::from(socket).set_keepalive(keepalive)
+}
+
+#[cfg(not(feature = "socket2"))]
+pub(crate) fn socket_set_keepalive(
+    socket: &std::net::TcpStream,
+    keepalive: bool,
+) -> io::Result<()> {
+    use libc::{c_int, c_void, socklen_t};
+
+    let val = c_int::from(keepalive);
+    let len = socklen_t::try_from(std::mem::size_of::<c_int>())
+        .map_err(|_| io::ErrorKind::InvalidInput)?;
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_KEEPALIVE,
+            std::ptr::addr_of!(val).cast::<c_void>(),
+            len,
+        )
+    };
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Clears the read and write timeouts of the socket `fd`,
+/// like `set_read_timeout(None)` and
+/// `set_write_timeout(None)` on a `TcpStream`, for sockets
+/// only known by their descriptor.
+pub(crate) fn clear_timeouts(fd: RawFd) -> io::Result<()> {
+    use libc::{c_void, socklen_t, timeval};
+
+    let none = timeval {
+        tv_sec: 0,
+        tv_usec: 0,
+    };
+    let len = socklen_t::try_from(std::mem::size_of::<timeval>())
+        .map_err(|_| io::ErrorKind::InvalidInput)?;
+    for option in [libc::SO_RCVTIMEO, libc::SO_SNDTIMEO] {
+        let rc = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                std::ptr::addr_of!(none).cast::<c_void>(),
+                len,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Enables TCP keepalive on `socket` where the platform
+/// supports it.
+///
+/// Keepalive only helps notice dead servers sooner, so a
+/// socket that does not support it (`ENOPROTOOPT` or
+/// `EOPNOTSUPP`) is logged and used without it.
+pub(crate) fn enable_keepalive(socket: &std::net::TcpStream) -> io::Result<()> {
+    enable_keepalive_with(socket, socket_set_keepalive)
+}
+
+/// Does the work of [`enable_keepalive`], turning
+/// keepalive on through `set_keepalive`.
+fn enable_keepalive_with(
+    socket: &std::net::TcpStream,
+    set_keepalive: impl FnOnce(&std::net::TcpStream, bool) -> io::Result<()>,
+) -> io::Result<()> {
+    match set_keepalive(socket, true) {
+        Err(err)
+            if matches!(
+                err.raw_os_error(),
+                Some(libc::ENOPROTOOPT | libc::EOPNOTSUPP)
+            ) =>
+        {
+            log::warn!("TCP keepalive is not supported, continuing without it: {err}");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Opens a TCP connection to a remote host.
+/// It is not required to use this function to initiate
+/// the connection, as long as these socket options
+/// (or their equivalents) are set:
+/// - `NoDelay` is enabled (disables the Nagle algorithm)
+/// - `KeepAlive` is enabled
+pub fn connect<A>(host: A) -> io::Result<std::net::TcpStream>
+where
+    A: std::net::ToSocketAddrs,
+{
+    connect_with(host, &ConnectOptions::default())
+}
+
+/// Options for opening a connection with [`connect_with`].
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// Bounds how long connecting to each address may
+    /// take. `None` waits for as long as the OS does.
+    pub timeout: Option<Duration>,
+    /// Skip enabling TCP keepalive, without making the
+    /// `setsockopt` call at all, for sandboxes that block
+    /// it. Only short-lived connections should set this:
+    /// without keepalive, an attached device whose server
+    /// silently disappears is never noticed.
+    pub no_keepalive: bool,
+    /// Further options to set once connected, after
+    /// `NoDelay` and `KeepAlive`.
+    pub socket_options: Vec<SocketOption>,
+}
+
+/// A socket option that [`connect_with_options`] can set
+/// on a connection before the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocketOption {
+    /// `SO_RCVBUF`, the receive buffer size in bytes.
+    RecvBufferSize(usize),
+    /// `SO_SNDBUF`, the send buffer size in bytes.
+    SendBufferSize(usize),
+    /// `IP_TOS` on IPv4 connections, or `IPV6_TCLASS` on
+    /// IPv6 ones.
+    Tos(u8),
+    /// `SO_BINDTODEVICE`, keeping the connection on the
+    /// network interface with this name. The connection is
+    /// already made when this is set, so it only takes
+    /// effect if that interface can reach the server.
+    /// Usually requires `CAP_NET_RAW`.
+    BindToDevice(Box<str>),
+}
+
+impl SocketOption {
+    const fn name(&self) -> &'static str {
+        match self {
+            SocketOption::RecvBufferSize(_) => "SO_RCVBUF",
+            SocketOption::SendBufferSize(_) => "SO_SNDBUF",
+            SocketOption::Tos(_) => "IP_TOS",
+            SocketOption::BindToDevice(_) => "SO_BINDTODEVICE",
+        }
+    }
+
+    /// Sets this option on `socket`, naming the option in
+    /// the error if the platform refuses it.
+    fn apply(&self, socket: &std::net::TcpStream) -> io::Result<()> {
+        set_socket_option(socket, self).map_err(|err| {
+            io::Error::new(err.kind(), format!("could not set {}: {err}", self.name()))
+        })
+    }
+}
+
+#[cfg(feature = "socket2")]
+fn set_socket_option(socket: &std::net::TcpStream, option: &SocketOption) -> io::Result<()> {
+    let socket2 = socket2::SockRef::from(socket);
+    match option {
+        SocketOption::RecvBufferSize(size) => socket2.set_recv_buffer_size(*size),
+        SocketOption::SendBufferSize(size) => socket2.set_send_buffer_size(*size),
+        SocketOption::Tos(tos) if socket.local_addr()?.is_ipv6() => {
+            socket2.set_tclass_v6(u32::from(*tos))
+        }
+        SocketOption::Tos(tos) => socket2.set_tos(u32::from(*tos)),
+        SocketOption::BindToDevice(name) => socket2.bind_device(Some(name.as_bytes())),
+    }
+}
+
+#[cfg(not(feature = "socket2"))]
+fn set_socket_option(socket: &std::net::TcpStream, option: &SocketOption) -> io::Result<()> {
+    use libc::{c_int, c_void, socklen_t};
+
+    let int = |size: usize| c_int::try_from(size).map_err(|_| io::ErrorKind::InvalidInput);
+    let (level, name, value) = match option {
+        SocketOption::RecvBufferSize(size) => (
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            int(*size)?.to_ne_bytes().to_vec(),
+        ),
+        SocketOption::SendBufferSize(size) => (
+            libc::SOL_SOCKET,
+            libc::SO_SNDBUF,
+            int(*size)?.to_ne_bytes().to_vec(),
+        ),
+        SocketOption::Tos(tos) if socket.local_addr()?.is_ipv6() => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            c_int::from(*tos).to_ne_bytes().to_vec(),
+        ),
+        SocketOption::Tos(tos) => (
+            libc::IPPROTO_IP,
+            libc::IP_TOS,
+            c_int::from(*tos).to_ne_bytes().to_vec(),
+        ),
+        SocketOption::BindToDevice(name) => (
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_bytes().to_vec(),
+        ),
+    };
+
+    let len = socklen_t::try_from(value.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            value.as_ptr().cast::<c_void>(),
+            len,
+        )
+    };
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Opens a TCP connection to a remote host, like
+/// [`connect`], then sets `options` on it.
+///
+/// `NoDelay` and `KeepAlive` are still set first, as by
+/// [`connect`].
+///
+/// # Error
+/// This function returns an error if connecting failed,
+/// or if the platform refused one of `options`, naming
+/// the option.
+pub fn connect_with_options<A>(host: A, options: &[SocketOption]) -> io::Result<std::net::TcpStream>
+where
+    A: std::net::ToSocketAddrs,
+{
+    connect_with(
+        host,
+        &ConnectOptions {
+            socket_options: options.to_vec(),
+            ..ConnectOptions::default()
+        },
+    )
+}
+
+/// Opens a TCP connection to a remote host, like
+/// [`connect`], using `options`.
+///
+/// Every address `host` resolves to is tried in turn,
+/// returning the error from the last one if none of
+/// them could be reached.
+pub fn connect_with<A>(host: A, options: &ConnectOptions) -> io::Result<std::net::TcpStream>
+where
+    A: std::net::ToSocketAddrs,
+{
+    let socket = match options.timeout {
+        Some(timeout) => {
+            let mut last_err = None;
+            let mut socket = None;
+            for addr in host.to_socket_addrs()? {
+                match std::net::TcpStream::connect_timeout(&addr, timeout) {
+                    Ok(connected) => {
+                        socket = Some(connected);
+                        break;
+                    }
+                    Err(err) => last_err = Some(err),
+                }
+            }
+            socket.ok_or_else(|| {
+                last_err.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "host resolved to no addresses")
+                })
+            })?
+        }
+        None => std::net::TcpStream::connect(host)?,
+    };
+    socket.set_nodelay(true)?;
+    if !options.no_keepalive {
+        enable_keepalive(&socket)?;
+    }
+    for option in &options.socket_options {
+        option.apply(&socket)?;
+    }
+    Ok(socket)
+}
+
+/// Opens a connection to a USB/IP peer listening on the
+/// Unix domain socket at `path`, such as a local bridge
+/// daemon or a test server.
+///
+/// PDUs are exchanged exactly as over TCP, so everything
+/// generic over [`Send`] and [`Recv`] works on the
+/// returned stream. There are no TCP options to set. The
+/// vhci driver takes any connected stream socket in
+/// blocking mode, so a device can be attached over this
+/// connection with [`crate::attach::attach_over`].
+///
+/// # Error
+/// This function returns an error if connecting failed.
+pub fn connect_unix<P>(path: P) -> io::Result<std::os::unix::net::UnixStream>
+where
+    P: AsRef<std::path::Path>,
+{
+    std::os::unix::net::UnixStream::connect(path)
+}
+
+/// Asks the server which protocol version it speaks,
+/// before committing to an attach.
+///
+/// This sends a devlist request header and returns the
+/// version the server replies with, without reading the
+/// rest of the reply. Listing devices does not change any
+/// state on the server. The rest of the reply is left
+/// unread, so `socket` should be dropped afterwards rather
+/// than reused.
+///
+/// Compare the result against [`VERSION`], or pass it to
+/// [`ProtocolVersion::new`] to check that this crate can
+/// talk to the server at all.
+///
+/// # Error
+/// This function returns an error if the request could
+/// not be sent or the reply header could not be read.
+pub fn negotiate_version<S>(socket: &mut S) -> bincode::Result<u16>
+where
+    S: Send + Recv,
+{
+    let request = OpCommon::request(OP_REQ_DEVLIST);
+    socket.send(&request)?;
+
+    let reply = socket.recv_header()?;
+    Ok(reply.version)
+}
+
+/// The addresses a server resolves to and the protocol
+/// version it replied with.
+type Probed = (Vec<SocketAddr>, ProtocolVersion);
+
+/// Resolves `host` and asks it which protocol version it
+/// speaks, see [`negotiate_version`].
+fn probe(host: &str, options: &ConnectOptions) -> Result<Probed, Box<dyn StdError>> {
+    let addrs: Vec<SocketAddr> = parse_endpoint(host)?.to_socket_addrs()?.collect();
+    let mut socket = connect_with(&addrs[..], options)?;
+    let version = ProtocolVersion::new(negotiate_version(&mut socket)?)?;
+    Ok((addrs, version))
+}
+
+struct Server {
+    addrs: Vec<SocketAddr>,
+    version: ProtocolVersion,
+    refreshed: Instant,
+}
+
+/// Caches what is learned about each server a long-running
+/// client talks to: the addresses its host name resolves
+/// to, and the protocol version it replies with.
+///
+/// USB/IP opens a fresh connection for every operation, so
+/// the connections themselves cannot be pooled. Caching
+/// the resolution and version probe saves a DNS lookup and
+/// an extra round trip on every attach or listing. Entries
+/// are refreshed once they are older than the registry's
+/// TTL.
+pub struct ServerRegistry {
+    ttl: Duration,
+    options: ConnectOptions,
+    servers: HashMap<Box<str>, Server>,
+}
+
+impl ServerRegistry {
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_options(ttl, ConnectOptions::default())
+    }
+
+    /// Creates a registry that opens every connection
+    /// with `options`.
+    pub fn with_options(ttl: Duration, options: ConnectOptions) -> Self {
+        Self {
+            ttl,
+            options,
+            servers: HashMap::new(),
+        }
+    }
+
+    fn lookup(&mut self, host: &str) -> Result<&Server, Box<dyn StdError>> {
+        self.lookup_with(host, Instant::now(), probe)
+    }
+
+    /// Looks `host` up like [`ServerRegistry::lookup`] at
+    /// `now`, with `probe` standing in for resolving and
+    /// probing the server.
+    fn lookup_with(
+        &mut self,
+        host: &str,
+        now: Instant,
+        probe: impl FnOnce(&str, &ConnectOptions) -> Result<Probed, Box<dyn StdError>>,
+    ) -> Result<&Server, Box<dyn StdError>> {
+        let fresh = self
+            .servers
+            .get(host)
+            .is_some_and(|server| now.saturating_duration_since(server.refreshed) < self.ttl);
+
+        if !fresh {
+            let (addrs, version) = probe(host, &self.options)?;
+            self.servers.insert(
+                host.into(),
+                Server {
+                    addrs,
+                    version,
+                    refreshed: now,
+                },
+            );
+        }
+
+        Ok(&self.servers[host])
+    }
+
+    /// Returns the protocol version `host` replies with,
+    /// probing it if there is no fresh entry for it.
+    ///
+    /// # Errors
+    /// This function fails if `host` could not be parsed,
+    /// resolved or reached, or replied with a version this
+    /// crate cannot parse.
+    pub fn version(&mut self, host: &str) -> Result<ProtocolVersion, Box<dyn StdError>> {
+        self.lookup(host).map(|server| server.version)
+    }
+
+    /// Opens a connection to `host`, returning it together
+    /// with the protocol version to use on it.
+    ///
+    /// # Errors
+    /// See [`ServerRegistry::version`].
+    pub fn connect(
+        &mut self,
+        host: &str,
+    ) -> Result<(std::net::TcpStream, ProtocolVersion), Box<dyn StdError>> {
+        let options = self.options.clone();
+        let server = self.lookup(host)?;
+        let socket = connect_with(&server.addrs[..], &options)?;
+        Ok((socket, server.version))
+    }
+
+    /// Drops what is cached about `host`, so the next use
+    /// resolves and probes it again.
+    pub fn forget(&mut self, host: &str) {
+        self.servers.remove(host);
+    }
+}
+
+fn wire_options() -> impl bincode::Options {
+    bincode::DefaultOptions::new()
+        .with_no_limit()
+        .with_big_endian()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+/// The byte order PDUs are encoded in.
+///
+/// USB/IP is big-endian on the wire, and every standard
+/// server only understands that. `Native` exists only for
+/// custom transports between two processes on the same
+/// host, such as a Unix socket bridge where both ends use
+/// this crate, to skip the byte swaps. It must never be
+/// used on a connection to a USB/IP server: the server
+/// would misread every field.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endian {
+    #[default]
+    Big,
+    Native,
+}
+
+/// The encoding options for PDUs in `endian` byte order,
+/// see [`wire_options_with`].
+#[derive(Debug, Clone, Copy)]
+pub struct WireOptions {
+    endian: Endian,
+}
+
+impl WireOptions {
+    pub fn serialize<T>(self, value: &T) -> bincode::Result<Vec<u8>>
+    where
+        T: Serialize + ?Sized,
+    {
+        match self.endian {
+            Endian::Big => wire_options().serialize(value),
+            Endian::Native => wire_options().with_native_endian().serialize(value),
+        }
+    }
+
+    pub fn serialize_into<W, T>(self, writer: W, value: &T) -> bincode::Result<()>
+    where
+        W: io::Write,
+        T: Serialize + ?Sized,
+    {
+        match self.endian {
+            Endian::Big => wire_options().serialize_into(writer, value),
+            Endian::Native => wire_options()
+                .with_native_endian()
+                .serialize_into(writer, value),
+        }
+    }
+
+    pub fn deserialize_from<R, T>(self, reader: R) -> bincode::Result<T>
+    where
+        R: io::Read,
+        T: DeserializeOwned,
+    {
+        match self.endian {
+            Endian::Big => wire_options().deserialize_from(reader),
+            Endian::Native => wire_options().with_native_endian().deserialize_from(reader),
+        }
+    }
+}
+
+/// Returns the options PDUs are encoded with, in `endian`
+/// byte order. Everything but the byte order matches the
+/// wire format; see [`Endian`] for when anything other
+/// than `Endian::Big` may be used.
+pub fn wire_options_with(endian: Endian) -> WireOptions {
+    WireOptions { endian }
+}
+
+/// Decodes one `T` from the front of `reader`, the
+/// way every PDU on the wire is decoded.
+fn decode<T, R>(reader: R) -> bincode::Result<T>
+where
+    T: DeserializeOwned,
+    R: io::Read,
+{
+    wire_options().deserialize_from(reader)
+}
+
+/// Encodes `value` the way every PDU on the wire is
+/// encoded, for transports that do not implement [`Send`].
+pub fn encode<T>(value: &T) -> bincode::Result<Vec<u8>>
+where
+    T: Serialize + ?Sized,
+{
+    wire_options().serialize(value)
+}
+
+/// Encodes the header of a request for `code` at the
+/// current protocol version, exactly as it is sent.
+pub fn encode_op_common(code: u16) -> Vec<u8> {
+    encode(&OpCommon::request(code)).expect("a header always encodes")
+}
+
+/// Encodes a whole import request for `bus_id`, header
+/// and body, exactly as it is sent.
+///
+/// # Error
+/// This function returns `Error::FieldTooLong` if
+/// `bus_id` does not fit in the request's bus id field.
+pub fn encode_import_request(bus_id: &str) -> Result<Vec<u8>, Error> {
+    let request = OpImportRequest {
+        bus_id: crate::from_cstr_like(bus_id).ok_or(Error::FieldTooLong("bus id"))?,
+    };
+    let mut bytes = encode_op_common(crate::protocol::OP_REQ_IMPORT);
+    bytes.extend(encode(&request).expect("an import request always encodes"));
+    Ok(bytes)
+}
+
+/// Size on the wire of an `OpCommon` header.
+pub const HEADER_SIZE: usize = 8;
+/// Size on the wire of an `OpImportRequest` body.
+pub const IMPORT_REQUEST_SIZE: usize = SYSFS_BUS_ID_SIZE;
+/// Size on the wire of an `OpDevlistReply` body.
+pub const DEVLIST_REPLY_SIZE: usize = 4;
+/// Size on the wire of a `UsbDevice`.
+pub const DEVICE_SIZE: usize = 312;
+/// Size on the wire of a `UsbInterface`.
+pub const INTERFACE_SIZE: usize = 4;
+
+/// Decodes a PDU header from the front of `bytes`.
+///
+/// This and the other `parse_*` functions decode from a
+/// byte slice exactly as [`Recv`] decodes from a socket,
+/// so they can be driven directly by a fuzzer. Malformed
+/// or short input is reported as an error, never a panic.
+/// Any bytes after the decoded value are ignored.
+///
+/// Every PDU but a devlist entry has a fixed size and only
+/// fixed-size fields, the bus id and path included, so
+/// decoding one succeeds without allocating: the decoded
+/// value lives wherever the caller puts it, such as on the
+/// stack of a responder with a fixed receive buffer. Only
+/// the error path allocates, and [`parse_devlist_entry`],
+/// whose interface list depends on the device.
+pub fn parse_header(bytes: &[u8]) -> bincode::Result<OpCommon> {
+    decode(bytes)
+}
+
+/// Decodes the body of an import request from the front
+/// of `bytes`, as a server receives it.
+pub fn parse_import_request(bytes: &[u8]) -> bincode::Result<OpImportRequest> {
+    decode(bytes)
+}
+
+/// Decodes the body of an import reply from the front
+/// of `bytes`.
+///
+/// Callers should still run [`check_device`] on the
+/// decoded device before trusting its string fields.
+pub fn parse_import_reply(bytes: &[u8]) -> bincode::Result<OpImportReply> {
+    decode(bytes)
+}
+
+/// Decodes the body of a devlist reply from the front
+/// of `bytes`.
+pub fn parse_devlist_reply(bytes: &[u8]) -> bincode::Result<OpDevlistReply> {
+    decode(bytes)
+}
+
+/// Decodes a device from the front of `bytes`, without
+/// the interface records that may follow it.
+pub fn parse_device(bytes: &[u8]) -> bincode::Result<UsbDevice> {
+    decode(bytes)
+}
+
+/// Decodes one device of a devlist reply, along with
+/// the interface records that follow it, from the front
+/// of `bytes`.
+///
+/// `bytes` is advanced past the entry, so the devices of
+/// a whole reply can be read by calling this in a loop.
+/// Exactly [`INTERFACE_SIZE`] bytes are taken for each of
+/// the device's `bNumInterfaces` records, padding
+/// included, so the next entry always starts where the
+/// kernel put it.
+pub fn parse_devlist_entry(bytes: &mut &[u8]) -> bincode::Result<(UsbDevice, Vec<UsbInterface>)> {
+    let udev: UsbDevice = decode(&mut *bytes)?;
+    let len = INTERFACE_SIZE * usize::from(udev.b_num_interfaces());
+    if bytes.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let (records, rest) = bytes.split_at(len);
+    *bytes = rest;
+    let interfaces = records
+        .chunks_exact(INTERFACE_SIZE)
+        .map(decode)
+        .collect::<bincode::Result<_>>()?;
+    Ok((udev, interfaces))
+}
+
+pub trait Send: io::Write {
+    /// The byte order PDUs are sent in. Only transports
+    /// that never reach a standard server may override
+    /// this, see [`Endian`].
+    fn send_endian(&self) -> Endian {
+        Endian::Big
+    }
+
+    fn send<T>(&mut self, value: &T) -> bincode::Result<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        wire_options_with(self.send_endian()).serialize_into(self, value)
+    }
+}
+
+pub trait Recv: io::Read {
+    /// The byte order PDUs are received in. Only
+    /// transports that never reach a standard server may
+    /// override this, see [`Endian`].
+    fn recv_endian(&self) -> Endian {
+        Endian::Big
+    }
+
+    fn recv<T>(&mut self) -> bincode::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        wire_options_with(self.recv_endian()).deserialize_from(self)
+    }
+
+    /// Reads only the `OpCommon` header of a PDU.
+    ///
+    /// The body is left unread, so the caller can decide
+    /// from the header's code and status which body (if
+    /// any) follows. This lets one handler dispatch import,
+    /// devlist and error replies on the same connection.
+    fn recv_header(&mut self) -> bincode::Result<OpCommon> {
+        self.recv()
+    }
+}
+
+impl Recv for std::net::TcpStream {}
+impl Send for std::net::TcpStream {}
+
+impl Recv for std::os::unix::net::UnixStream {}
+impl Send for std::os::unix::net::UnixStream {}
+
+/// A transport that can be split into halves read and
+/// written from different threads, as relaying a device's
+/// traffic needs (see [`crate::attach::attach_with_relay`]).
+pub trait Split: Sized {
+    type Reader: io::Read + std::marker::Send + 'static;
+    type Writer: io::Write + std::marker::Send + 'static;
+
+    /// Splits the transport into its reading and writing
+    /// halves.
+    ///
+    /// # Error
+    /// This function returns an error if the transport
+    /// could not be split, such as when duplicating a
+    /// socket fails.
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)>;
+
+    /// Closes the whole transport through its writing
+    /// half, so that a read blocked on the reading half
+    /// returns.
+    ///
+    /// # Error
+    /// This function returns an error if the transport
+    /// could not be closed.
+    fn close(writer: &mut Self::Writer) -> io::Result<()>;
+}
+
+impl Split for std::net::TcpStream {
+    type Reader = Self;
+    type Writer = Self;
+
+    fn split(self) -> io::Result<(Self, Self)> {
+        Ok((self.try_clone()?, self))
+    }
+
+    fn close(writer: &mut Self) -> io::Result<()> {
+        writer.shutdown(std::net::Shutdown::Both)
+    }
+}
+
+impl Split for std::os::unix::net::UnixStream {
+    type Reader = Self;
+    type Writer = Self;
+
+    fn split(self) -> io::Result<(Self, Self)> {
+        Ok((self.try_clone()?, self))
+    }
+
+    fn close(writer: &mut Self) -> io::Result<()> {
+        writer.shutdown(std::net::Shutdown::Both)
+    }
+}
+
+/// Wraps a stream to tee every PDU that crosses it to
+/// `sink`, one line per PDU, for debugging interop with
+/// other implementations:
+///
+/// ```text
+/// > usbip::net::OpCommon (8 bytes): 01 11 80 05 00 00 00 00
+/// < usbip::net::OpCommon (8 bytes): 01 11 00 05 00 00 00 00
+/// ```
+///
+/// `>` marks bytes sent and `<` bytes received, labelled
+/// with the type they were encoded from or decoded into.
+/// Bytes read or written directly through `io::Read` and
+/// `io::Write` are labelled `raw`. Streams that are not
+/// wrapped pay nothing.
+///
+/// A failure to write to `sink` fails the operation, so
+/// a capture never silently misses a PDU.
+#[derive(Debug)]
+pub struct Tap<S, W> {
+    inner: S,
+    sink: W,
+}
+
+impl<S, W> Tap<S, W> {
+    pub const fn new(inner: S, sink: W) -> Self {
+        Self { inner, sink }
+    }
+
+    pub const fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_parts(self) -> (S, W) {
+        (self.inner, self.sink)
+    }
+}
+
+impl<S, W: io::Write> Tap<S, W> {
+    fn record(&mut self, direction: char, label: &str, bytes: &[u8]) -> io::Result<()> {
+        write!(self.sink, "{direction} {label} ({} bytes):", bytes.len())?;
+        for byte in bytes {
+            write!(self.sink, " {byte:02x}")?;
+        }
+        writeln!(self.sink)
+    }
+}
+
+impl<S: io::Read, W: io::Write> io::Read for Tap<S, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.record('<', "raw", &buf[..n])?;
+        Ok(n)
+    }
+}
+
+impl<S: io::Write, W: io::Write> io::Write for Tap<S, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.record('>', "raw", &buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        self.sink.flush()
+    }
+}
+
+impl<S: io::Write, W: io::Write> Send for Tap<S, W> {
+    fn send<T>(&mut self, value: &T) -> bincode::Result<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        let bytes = encode(value)?;
+        self.record('>', std::any::type_name::<T>(), &bytes)?;
+        self.inner.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+impl<S: io::Read, W: io::Write> Recv for Tap<S, W> {
+    fn recv<T>(&mut self) -> bincode::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let mut tee = Tee {
+            inner: &mut self.inner,
+            bytes: Vec::new(),
+        };
+        let result = decode(&mut tee);
+        let bytes = tee.bytes;
+        self.record('<', std::any::type_name::<T>(), &bytes)?;
+        result
+    }
+}
+
+impl<S: AsRawFd, W> AsRawFd for Tap<S, W> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+/// Keeps a copy of every byte read through it.
+struct Tee<R> {
+    inner: R,
+    bytes: Vec<u8>,
+}
+
+impl<R: io::Read> io::Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Wraps a stream to buffer what [`Recv`] reads from it,
+/// so decoding a multi-field PDU such as a devlist entry
+/// costs one `read` instead of one per field.
+///
+/// Only reads are buffered; writes go straight to the
+/// stream.
+///
+/// The buffer may read ahead of the PDU being decoded.
+/// That is harmless while only USB/IP requests and replies
+/// are exchanged, but bytes read ahead after an import
+/// reply would belong to the kernel's data plane, and
+/// would be lost when the socket is handed to the vhci
+/// driver. Unwrap the stream with [`BufferedRecv::into_parts`]
+/// before handing it over, and check that no bytes were
+/// left buffered.
+#[derive(Debug)]
+pub struct BufferedRecv<S> {
+    inner: io::BufReader<S>,
+}
+
+impl<S: io::Read> BufferedRecv<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner: io::BufReader::new(inner),
+        }
+    }
+
+    pub fn with_capacity(capacity: usize, inner: S) -> Self {
+        Self {
+            inner: io::BufReader::with_capacity(capacity, inner),
+        }
+    }
+}
+
+impl<S> BufferedRecv<S> {
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+
+    /// Returns the stream along with the bytes read from
+    /// it that were not consumed yet.
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        let buffered = self.inner.buffer().to_vec();
+        (self.inner.into_inner(), buffered)
+    }
+}
+
+impl<S: io::Read> io::Read for BufferedRecv<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: io::Write> io::Write for BufferedRecv<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.get_mut().flush()
+    }
+}
+
+impl<S: io::Read> Recv for BufferedRecv<S> {}
+impl<S: io::Write> Send for BufferedRecv<S> {}
+
+impl<S: AsRawFd> AsRawFd for BufferedRecv<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.get_ref().as_raw_fd()
+    }
+}
+
+/// Wraps a stream to send and receive PDUs in `endian`
+/// byte order instead of the wire's big-endian order.
+///
+/// This is only for custom transports between two
+/// processes on the same host; see [`Endian`]. Never wrap
+/// a TCP connection to a USB/IP server.
+#[derive(Debug)]
+pub struct WithEndian<S> {
+    inner: S,
+    endian: Endian,
+}
+
+impl<S> WithEndian<S> {
+    pub const fn new(inner: S, endian: Endian) -> Self {
+        Self { inner, endian }
+    }
+
+    pub const fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: io::Read> io::Read for WithEndian<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: io::Write> io::Write for WithEndian<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: io::Write> Send for WithEndian<S> {
+    fn send_endian(&self) -> Endian {
+        self.endian
+    }
+}
+
+impl<S: io::Read> Recv for WithEndian<S> {
+    fn recv_endian(&self) -> Endian {
+        self.endian
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use usbip_core::net::Status;
+
+    use super::*;
+    use crate::{
+        buffer_to_string,
+        test_support::{self, Scripted},
+    };
+
+    #[test]
+    fn op_common_round_trips() {
+        let bytes = encode_op_common(crate::protocol::OP_REQ_DEVLIST);
+        assert_eq!(bytes, [0x01, 0x11, 0x80, 0x05, 0, 0, 0, 0]);
+
+        let header = parse_header(&bytes).unwrap();
+        assert_eq!(header.version, VERSION);
+        assert_eq!(header.code, crate::protocol::OP_REQ_DEVLIST);
+        assert_eq!(header.status, Status::Success);
+    }
+
+    #[test]
+    fn import_request_round_trips() {
+        let bytes = encode_import_request("1-1.2").unwrap();
+        assert_eq!(bytes.len(), HEADER_SIZE + IMPORT_REQUEST_SIZE);
+
+        let header = parse_header(&bytes).unwrap();
+        assert_eq!(header.code, crate::protocol::OP_REQ_IMPORT);
+        let request = parse_import_request(&bytes[HEADER_SIZE..]).unwrap();
+        assert_eq!(buffer_to_string(&request.bus_id), "1-1.2");
+    }
+
+    #[test]
+    fn device_round_trips() {
+        let bytes = test_support::DeviceBytes::default().encode();
+        assert_eq!(bytes.len(), DEVICE_SIZE);
+
+        let reply = parse_import_reply(&bytes).unwrap();
+        assert_eq!(encode(&reply).unwrap(), bytes);
+        assert_eq!(encode(&parse_device(&bytes).unwrap()).unwrap(), bytes);
+    }
+
+    /// Collects the bytes of every line `Tap` wrote for
+    /// `direction`, in order.
+    fn tapped(capture: &str, direction: char) -> Vec<u8> {
+        capture
+            .lines()
+            .filter(|line| line.starts_with(direction))
+            .flat_map(|line| line.split_once("):").unwrap().1.split_whitespace())
+            .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn tap_records_the_bytes_on_the_wire() {
+        let reply = test_support::header(crate::protocol::OP_REP_DEVLIST, 0);
+        let mut tap = Tap::new(Scripted::new(reply.clone()), Vec::new());
+
+        tap.send(&OpCommon::request(crate::protocol::OP_REQ_DEVLIST))
+            .unwrap();
+        tap.recv_header().unwrap();
+
+        let (socket, capture) = tap.into_parts();
+        let capture = String::from_utf8(capture).unwrap();
+        assert!(capture.starts_with("> usbip::net::OpCommon (8 bytes):"));
+        assert_eq!(
+            socket.sent,
+            encode_op_common(crate::protocol::OP_REQ_DEVLIST)
+        );
+        assert_eq!(tapped(&capture, '>'), socket.sent);
+        assert_eq!(tapped(&capture, '<'), reply);
+    }
+
+    #[test]
+    fn devlist_entries_take_every_interface_record() {
+        let first = test_support::DeviceBytes {
+            bus_id: "1-1",
+            num_interfaces: 3,
+            ..test_support::DeviceBytes::default()
+        };
+        let second = test_support::DeviceBytes {
+            bus_id: "1-2",
+            devnum: 3,
+            ..test_support::DeviceBytes::default()
+        };
+        let reply = test_support::devlist_reply(&[first, second]);
+        let mut bytes = &reply[HEADER_SIZE + DEVLIST_REPLY_SIZE..];
+
+        let (udev, interfaces) = parse_devlist_entry(&mut bytes).unwrap();
+        assert_eq!(buffer_to_string(udev.bus_id()), "1-1");
+        assert_eq!(interfaces.len(), 3);
+
+        let (udev, interfaces) = parse_devlist_entry(&mut bytes).unwrap();
+        assert_eq!(buffer_to_string(udev.bus_id()), "1-2");
+        assert_eq!(udev.devnum(), 3);
+        assert_eq!(interfaces.len(), 1);
+        assert!(bytes.is_empty());
+    }
+
+    fn loopback() -> std::net::TcpStream {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn unsupported_keepalive_is_not_fatal() {
+        let socket = loopback();
+        for errno in [libc::ENOPROTOOPT, libc::EOPNOTSUPP] {
+            let result =
+                enable_keepalive_with(&socket, |_, _| Err(io::Error::from_raw_os_error(errno)));
+            assert!(result.is_ok(), "errno {errno}");
+        }
+    }
+
+    #[test]
+    fn other_keepalive_errors_are_fatal() {
+        let socket = loopback();
+        let err = enable_keepalive_with(&socket, |_, _| {
+            Err(io::Error::from_raw_os_error(libc::EBADF))
+        })
+        .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn keepalive_is_enabled_on_tcp() {
+        let socket = loopback();
+        enable_keepalive(&socket).unwrap();
+    }
+
+    #[test]
+    fn overlong_bus_id_is_not_encoded() {
+        let bus_id = "1".repeat(SYSFS_BUS_ID_SIZE);
+        assert!(matches!(
+            encode_import_request(&bus_id),
+            Err(Error::FieldTooLong("bus id"))
+        ));
+    }
+
+    #[test]
+    fn bracketed_scoped_ipv6_keeps_scope_and_port() {
+        let endpoint = parse_endpoint("[fe80::1%2]:3241").unwrap();
+        assert_eq!(endpoint, Endpoint::new("fe80::1%2", 3241));
+
+        let addr = endpoint.scoped_addr().unwrap().unwrap();
+        let SocketAddr::V6(addr) = addr else {
+            panic!("{addr} is not IPv6");
+        };
+        assert_eq!(*addr.ip(), "fe80::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(addr.port(), 3241);
+        assert_eq!(addr.scope_id(), 2);
+
+        let addrs: Vec<_> = std::net::ToSocketAddrs::to_socket_addrs(&endpoint)
+            .unwrap()
+            .collect();
+        assert_eq!(addrs, [SocketAddr::V6(addr)]);
+    }
+
+    #[test]
+    fn scope_may_name_an_interface() {
+        let endpoint = parse_endpoint("[fe80::1%lo]:3240").unwrap();
+        let Some(SocketAddr::V6(addr)) = endpoint.scoped_addr().unwrap() else {
+            panic!("no scoped address for {endpoint:?}");
+        };
+        assert_eq!(addr.scope_id(), interface_index("lo").unwrap());
+
+        let endpoint = parse_endpoint("[fe80::1%no-such-if0]:3240").unwrap();
+        let err = endpoint.scoped_addr().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn bare_scoped_ipv6_uses_the_default_port() {
+        let endpoint = parse_endpoint("fe80::1%2").unwrap();
+        assert_eq!(endpoint, Endpoint::new("fe80::1%2", default_port()));
+    }
+
+    #[test]
+    fn native_wire_options_round_trip_in_native_order() {
+        let options = wire_options_with(Endian::Native);
+        let bytes = options
+            .serialize(&OpCommon::request(crate::protocol::OP_REQ_IMPORT))
+            .unwrap();
+
+        let mut expected = Vec::new();
+        expected.extend(VERSION.to_ne_bytes());
+        expected.extend(crate::protocol::OP_REQ_IMPORT.to_ne_bytes());
+        expected.extend(0u32.to_ne_bytes());
+        assert_eq!(bytes, expected);
+
+        let header: OpCommon = options.deserialize_from(&bytes[..]).unwrap();
+        assert_eq!(header.version, VERSION);
+        assert_eq!(header.code, crate::protocol::OP_REQ_IMPORT);
+        assert_eq!(header.status, Status::Success);
+    }
+
+    #[test]
+    fn with_endian_sends_and_receives_in_its_order() {
+        let mut stream = WithEndian::new(io::Cursor::new(Vec::new()), Endian::Native);
+        assert_eq!(stream.send_endian(), Endian::Native);
+        assert_eq!(stream.recv_endian(), Endian::Native);
+
+        stream.send(&0x0102_0304u32).unwrap();
+        assert_eq!(stream.get_ref().get_ref(), &0x0102_0304u32.to_ne_bytes());
+        stream.inner.set_position(0);
+        assert_eq!(stream.recv::<u32>().unwrap(), 0x0102_0304);
+    }
+
+    /// Probes as a server at `addr` replying with
+    /// `version`, counting the probes in `probed`.
+    fn counting_probe<'a>(
+        probed: &'a std::cell::Cell<u32>,
+        addr: &'a str,
+        version: u16,
+    ) -> impl FnOnce(&str, &ConnectOptions) -> Result<Probed, Box<dyn StdError>> + 'a {
+        move |_, _| {
+            probed.set(probed.get() + 1);
+            Ok((vec![addr.parse()?], ProtocolVersion::new(version)?))
+        }
+    }
+
+    #[test]
+    fn server_registry_probes_again_only_after_the_ttl() {
+        let ttl = Duration::from_secs(30);
+        let mut registry = ServerRegistry::new(ttl);
+        let probed = std::cell::Cell::new(0);
+        let start = Instant::now();
+
+        let server = registry
+            .lookup_with("a", start, counting_probe(&probed, "10.0.0.2:3240", 0x0111))
+            .unwrap();
+        assert_eq!(server.version.get(), 0x0111);
+
+        // A server that changed within the ttl is not noticed.
+        let within = start + ttl - Duration::from_millis(1);
+        let server = registry
+            .lookup_with(
+                "a",
+                within,
+                counting_probe(&probed, "10.0.0.3:3240", 0x0106),
+            )
+            .unwrap();
+        assert_eq!(probed.get(), 1);
+        assert_eq!(server.version.get(), 0x0111);
+        assert_eq!(server.addrs, ["10.0.0.2:3240".parse().unwrap()]);
+
+        let server = registry
+            .lookup_with(
+                "a",
+                start + ttl,
+                counting_probe(&probed, "10.0.0.3:3240", 0x0106),
+            )
+            .unwrap();
+        assert_eq!(probed.get(), 2);
+        assert_eq!(server.version.get(), 0x0106);
+        assert_eq!(server.addrs, ["10.0.0.3:3240".parse().unwrap()]);
+    }
+
+    #[test]
+    fn forgotten_server_is_probed_again() {
+        let mut registry = ServerRegistry::new(Duration::from_secs(30));
+        let probed = std::cell::Cell::new(0);
+        let now = Instant::now();
+        registry
+            .lookup_with("a", now, counting_probe(&probed, "10.0.0.2:3240", 0x0111))
+            .unwrap();
+        registry.forget("a");
+        registry
+            .lookup_with("a", now, counting_probe(&probed, "10.0.0.2:3240", 0x0111))
+            .unwrap();
+        assert_eq!(probed.get(), 2);
+    }
+
+    #[test]
+    fn server_registry_probes_a_real_server() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut request = [0; HEADER_SIZE];
+            io::Read::read_exact(&mut socket, &mut request).unwrap();
+            io::Write::write_all(
+                &mut socket,
+                &test_support::header(crate::protocol::OP_REP_DEVLIST, 0),
+            )
+            .unwrap();
+        });
+
+        let mut registry = ServerRegistry::new(Duration::from_secs(30));
+        assert_eq!(registry.version(&host).unwrap().get(), VERSION);
+        server.join().unwrap();
+        // Cached, so no second connection is accepted.
+        assert_eq!(registry.version(&host).unwrap().get(), VERSION);
+    }
+
+    /// Keeps the warnings logged on each thread, so a test
+    /// can check what it logged itself.
+    struct Warnings;
+
+    static WARNINGS: std::sync::Mutex<Vec<(std::thread::ThreadId, String)>> =
+        std::sync::Mutex::new(Vec::new());
+
+    impl log::Log for Warnings {
+        fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+            metadata.level() <= log::Level::Warn
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            if self.enabled(record.metadata()) {
+                let warning = (std::thread::current().id(), record.args().to_string());
+                WARNINGS.lock().unwrap().push(warning);
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Runs `f`, returning what it returned and the
+    /// warnings it logged.
+    fn warnings_of<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&Warnings).unwrap();
+            log::set_max_level(log::LevelFilter::Warn);
+        });
+        let value = f();
+        let this = std::thread::current().id();
+        let mut warnings = WARNINGS.lock().unwrap();
+        let (mine, others) = warnings.drain(..).partition(|(thread, _)| *thread == this);
+        *warnings = others;
+        (
+            value,
+            mine.into_iter().map(|(_, warning)| warning).collect(),
+        )
+    }
+
+    #[test]
+    fn parse_port_reads_a_valid_port() {
+        for value in ["3241", " 3241\n", "65535"] {
+            let (port, warnings) = warnings_of(|| parse_port(Some(value)));
+            assert_eq!(port, value.trim().parse::<u16>().unwrap(), "{value:?}");
+            assert!(warnings.is_empty(), "{value:?}: {warnings:?}");
+        }
+    }
+
+    #[test]
+    fn parse_port_warns_and_falls_back_on_an_invalid_port() {
+        for value in ["", "usbip", "0", "65536", "-1", "3240 3241"] {
+            let (port, warnings) = warnings_of(|| parse_port(Some(value)));
+            assert_eq!(port, USBIP_PORT, "{value:?}");
+            assert_eq!(
+                warnings,
+                [format!(
+                    "ignoring invalid {USBIP_TCP_PORT_VAR}={value:?}, using port {USBIP_PORT}"
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn parse_port_defaults_without_a_value() {
+        let (port, warnings) = warnings_of(|| parse_port(None));
+        assert_eq!(port, USBIP_PORT);
+        assert!(warnings.is_empty(), "{warnings:?}");
+    }
+
+    /// Reads back an `int` socket option of `socket`.
+    fn getsockopt(socket: &std::net::TcpStream, level: libc::c_int, name: libc::c_int) -> i32 {
+        let mut value: libc::c_int = 0;
+        let mut len = libc::socklen_t::try_from(std::mem::size_of_val(&value)).unwrap();
+        let rc = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                std::ptr::addr_of_mut!(value).cast(),
+                &mut len,
+            )
+        };
+        assert_eq!(rc, 0, "{}", io::Error::last_os_error());
+        value
+    }
+
+    #[test]
+    fn socket_options_are_set_on_the_connection() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = [
+            SocketOption::RecvBufferSize(32 * 1024),
+            SocketOption::SendBufferSize(48 * 1024),
+            SocketOption::Tos(0x10),
+        ];
+        let socket = connect_with_options(listener.local_addr().unwrap(), &options).unwrap();
+
+        // Linux doubles buffer sizes to leave room for its
+        // own bookkeeping, and reports the doubled size.
+        let rcvbuf = getsockopt(&socket, libc::SOL_SOCKET, libc::SO_RCVBUF);
+        assert_eq!(rcvbuf, 2 * 32 * 1024);
+        let sndbuf = getsockopt(&socket, libc::SOL_SOCKET, libc::SO_SNDBUF);
+        assert_eq!(sndbuf, 2 * 48 * 1024);
+        assert_eq!(getsockopt(&socket, libc::IPPROTO_IP, libc::IP_TOS), 0x10);
+    }
+
+    #[test]
+    fn refused_socket_options_are_named_in_the_error() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        // Refused without CAP_NET_RAW, or for lack of such
+        // an interface with it.
+        let options = [SocketOption::BindToDevice("usbip-none0".into())];
+        let err = connect_with_options(listener.local_addr().unwrap(), &options).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("could not set SO_BINDTODEVICE: "),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn into_parts_returns_the_bytes_read_ahead() {
+        let mut reply = test_support::header(crate::protocol::OP_REP_IMPORT, 0);
+        reply.extend([0xde, 0xad, 0xbe, 0xef]);
+        let mut stream = BufferedRecv::new(Scripted::new(reply));
+
+        let header = stream.recv_header().unwrap();
+        assert_eq!(header.code, crate::protocol::OP_REP_IMPORT);
+
+        let (inner, buffered) = stream.into_parts();
+        assert_eq!(buffered, [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(inner.unread(), 0);
+    }
+
+    #[test]
+    fn into_parts_returns_nothing_before_reading() {
+        let reply = test_support::header(crate::protocol::OP_REP_IMPORT, 0);
+        let (inner, buffered) = BufferedRecv::new(Scripted::new(reply)).into_parts();
+        assert!(buffered.is_empty());
+        assert_eq!(inner.unread(), HEADER_SIZE);
+    }
+
+    #[test]
+    fn into_parts_returns_only_what_the_buffer_holds() {
+        let mut reply = test_support::header(crate::protocol::OP_REP_IMPORT, 0);
+        reply.extend([1, 2, 3, 4, 5, 6]);
+        let mut stream = BufferedRecv::with_capacity(HEADER_SIZE + 2, Scripted::new(reply));
+
+        stream.recv_header().unwrap();
+        let (inner, buffered) = stream.into_parts();
+        assert_eq!(buffered, [1, 2]);
+        assert_eq!(inner.unread(), 4);
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv6Addr, SocketAddr, TcpStream},
+};
+
+use super::{connect, parse_endpoint, Error};
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE: u8 = 0xff;
+const USER_PASS_VERSION: u8 = 0x01;
+const CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+fn failed(err: Error) -> io::Error {
+    io::Error::other(err)
+}
+
+/// Opens a connection to `target` through the SOCKS5
+/// proxy at `proxy`, optionally authenticating with a
+/// user name and password.
+///
+/// The connection to the proxy gets the same socket
+/// options as [`connect`]. Once the proxy has connected
+/// to `target`, the returned stream carries the USB/IP
+/// exchange unchanged. Host names in `target` are
+/// resolved by the proxy, not locally.
+///
+/// # Errors
+/// This function can fail for these reasons below:
+/// - `target` could not be parsed, or is an IPv6 address
+///   with a scope such as `fe80::1%eth0`: the scope names
+///   an interface of this host, which the proxy cannot
+///   use
+/// - The proxy could not be reached
+/// - The proxy refused every offered authentication
+///   method, or the credentials
+/// - The proxy could not connect to `target`
+pub fn connect_via_socks5(
+    proxy: SocketAddr,
+    target: &str,
+    auth: Option<(String, String)>,
+) -> io::Result<TcpStream> {
+    let target = parse_endpoint(target).map_err(failed)?;
+    let scoped = target
+        .host()
+        .split_once('%')
+        .is_some_and(|(addr, _)| addr.parse::<Ipv6Addr>().is_ok());
+    if scoped {
+        return Err(failed(Error::InvalidEndpoint(target.host().into())));
+    }
+    let mut socket = connect(proxy)?;
+
+    let method = if auth.is_some() { USER_PASS } else { NO_AUTH };
+    socket.write_all(&[VERSION, 1, method])?;
+    let mut choice = [0; 2];
+    socket.read_exact(&mut choice)?;
+    match choice {
+        [VERSION, NO_ACCEPTABLE] => return Err(failed(Error::ProxyFailed("no acceptable auth"))),
+        [VERSION, chosen] if chosen == method => {}
+        _ => return Err(failed(Error::ProxyFailed("invalid method reply"))),
+    }
+
+    if let Some((user, pass)) = auth {
+        let field =
+            |s: &str| u8::try_from(s.len()).map_err(|_| Error::ProxyFailed("credential too long"));
+        let mut request = vec![USER_PASS_VERSION, field(&user).map_err(failed)?];
+        request.extend_from_slice(user.as_bytes());
+        request.push(field(&pass).map_err(failed)?);
+        request.extend_from_slice(pass.as_bytes());
+        socket.write_all(&request)?;
+
+        let mut status = [0; 2];
+        socket.read_exact(&mut status)?;
+        if status[1] != 0 {
+            return Err(failed(Error::ProxyFailed("authentication failed")));
+        }
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    match target.host().parse::<IpAddr>() {
+        Ok(IpAddr::V4(addr)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&addr.octets());
+        }
+        Ok(IpAddr::V6(addr)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&addr.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(target.host().len())
+                .map_err(|_| failed(Error::ProxyFailed("host name too long")))?;
+            request.extend_from_slice(&[ATYP_DOMAIN, len]);
+            request.extend_from_slice(target.host().as_bytes());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    socket.write_all(&request)?;
+
+    let mut reply = [0; 4];
+    socket.read_exact(&mut reply)?;
+    if reply[0] != VERSION {
+        return Err(failed(Error::ProxyFailed("invalid connect reply")));
+    }
+    if reply[1] != 0 {
+        return Err(failed(Error::ProxyRejected(reply[1])));
+    }
+
+    // The reply ends with the address the proxy bound,
+    // which is of no use here but must be consumed.
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            socket.read_exact(&mut len)?;
+            usize::from(len[0])
+        }
+        _ => return Err(failed(Error::ProxyFailed("invalid bound address"))),
+    };
+    let mut bound = vec![0; addr_len + 2];
+    socket.read_exact(&mut bound)?;
+
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    /// What a fake proxy does once a client connects.
+    struct Script {
+        /// The method to choose, which must be the one the
+        /// client offers.
+        method: u8,
+        /// The reply code to the connect request.
+        reply: u8,
+        /// The bound address after the reply code, starting
+        /// with its address type.
+        bound: Vec<u8>,
+    }
+
+    fn read(socket: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        socket.read_exact(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Runs a SOCKS5 proxy for one client, returning its
+    /// address and everything the client sent after the
+    /// method selection. After a connect reply, the proxy
+    /// sends `hello` as if from the target.
+    fn proxy(script: Script) -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            assert_eq!(read(&mut socket, 3), [VERSION, 1, script.method]);
+            socket.write_all(&[VERSION, script.method]).unwrap();
+
+            let mut sent = Vec::new();
+            if script.method == USER_PASS {
+                sent.extend(read(&mut socket, 1));
+                // The user name, then the password.
+                for _ in 0..2 {
+                    let len = read(&mut socket, 1);
+                    sent.extend(&len);
+                    sent.extend(read(&mut socket, usize::from(len[0])));
+                }
+                socket.write_all(&[USER_PASS_VERSION, 0]).unwrap();
+            }
+
+            let request = read(&mut socket, 4);
+            sent.extend(&request);
+            let addr_len = match request[3] {
+                ATYP_IPV4 => 4,
+                ATYP_IPV6 => 16,
+                _ => {
+                    let len = read(&mut socket, 1);
+                    sent.extend(&len);
+                    usize::from(len[0])
+                }
+            };
+            sent.extend(read(&mut socket, addr_len + 2));
+
+            let mut reply = vec![VERSION, script.reply, 0];
+            reply.extend(&script.bound);
+            reply.extend(b"hello");
+            socket.write_all(&reply).unwrap();
+            sent
+        });
+        (addr, proxy)
+    }
+
+    fn bound_ipv4() -> Vec<u8> {
+        vec![ATYP_IPV4, 10, 0, 0, 1, 0x1f, 0x90]
+    }
+
+    fn read_hello(mut socket: TcpStream) -> [u8; 5] {
+        let mut hello = [0; 5];
+        socket.read_exact(&mut hello).unwrap();
+        hello
+    }
+
+    fn proxy_error(err: &io::Error) -> &Error {
+        err.get_ref().unwrap().downcast_ref().unwrap()
+    }
+
+    #[test]
+    fn connects_to_an_ipv4_target_without_auth() {
+        let (addr, proxy) = proxy(Script {
+            method: NO_AUTH,
+            reply: 0,
+            bound: bound_ipv4(),
+        });
+        let socket = connect_via_socks5(addr, "10.0.0.2:3240", None).unwrap();
+        assert_eq!(&read_hello(socket), b"hello");
+        assert_eq!(
+            proxy.join().unwrap(),
+            [VERSION, CONNECT, 0, ATYP_IPV4, 10, 0, 0, 2, 0x0c, 0xa8]
+        );
+    }
+
+    #[test]
+    fn authenticates_and_sends_host_names_to_the_proxy() {
+        let mut bound = vec![ATYP_DOMAIN, 9];
+        bound.extend(b"proxy.lan");
+        bound.extend(1080u16.to_be_bytes());
+        let (addr, proxy) = proxy(Script {
+            method: USER_PASS,
+            reply: 0,
+            bound,
+        });
+        let auth = Some(("user".to_owned(), "secret".to_owned()));
+        let socket = connect_via_socks5(addr, "usbip.lan", auth).unwrap();
+        // The whole bound address was consumed.
+        assert_eq!(&read_hello(socket), b"hello");
+
+        let mut expected = vec![USER_PASS_VERSION, 4];
+        expected.extend(b"user");
+        expected.push(6);
+        expected.extend(b"secret");
+        expected.extend([VERSION, CONNECT, 0, ATYP_DOMAIN, 9]);
+        expected.extend(b"usbip.lan");
+        expected.extend(3240u16.to_be_bytes());
+        assert_eq!(proxy.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn rejected_connect_reports_the_reply_code() {
+        let (addr, proxy) = proxy(Script {
+            method: NO_AUTH,
+            reply: 0x05,
+            bound: bound_ipv4(),
+        });
+        let err = connect_via_socks5(addr, "10.0.0.2", None).unwrap_err();
+        proxy.join().unwrap();
+        assert!(matches!(proxy_error(&err), Error::ProxyRejected(0x05)));
+    }
+
+    #[test]
+    fn sends_unscoped_ipv6_targets_as_addresses() {
+        let (addr, proxy) = proxy(Script {
+            method: NO_AUTH,
+            reply: 0,
+            bound: bound_ipv4(),
+        });
+        connect_via_socks5(addr, "[fe80::1]:3240", None).unwrap();
+        let sent = proxy.join().unwrap();
+        assert_eq!(sent[..4], [VERSION, CONNECT, 0, ATYP_IPV6]);
+        assert_eq!(sent[4..20], "fe80::1".parse::<Ipv6Addr>().unwrap().octets());
+    }
+
+    #[test]
+    fn rejects_scoped_ipv6_targets_before_connecting() {
+        // Nothing listens here; the proxy must not be tried.
+        let proxy = SocketAddr::from(([127, 0, 0, 1], 9));
+        for target in ["fe80::1%eth0", "[fe80::1%2]:3240"] {
+            let err = connect_via_socks5(proxy, target, None).unwrap_err();
+            assert!(
+                matches!(proxy_error(&err), Error::InvalidEndpoint(_)),
+                "{target}: {err}"
+            );
+        }
+    }
+}
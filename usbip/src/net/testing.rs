@@ -0,0 +1,386 @@
+//! Recording real sessions and replaying them, to turn
+//! interop with a real server into a regression test.
+//!
+//! A session file has one line per burst of bytes, with
+//! the time since the session started in microseconds,
+//! `>` for bytes the client sent or `<` for bytes it
+//! received, and the bytes in hex:
+//!
+//! ```text
+//! 0 > 01 11 80 05 00 00 00 00
+//! 1843 < 01 11 00 05 00 00 00 00 00 00 00 01
+//! ```
+//!
+//! Lines that are empty or start with `#` are ignored, so
+//! recorded files can be annotated by hand.
+
+use std::{
+    collections::VecDeque,
+    fs, io,
+    os::fd::{AsRawFd, RawFd},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use super::{Recv, Send};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    const fn marker(self) -> char {
+        match self {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Event {
+    at: Duration,
+    direction: Direction,
+    bytes: Vec<u8>,
+}
+
+/// Wraps `stream` to record every byte that crosses it,
+/// see [`Recorder`].
+pub fn record<S>(stream: S) -> Recorder<S> {
+    Recorder {
+        inner: stream,
+        start: Instant::now(),
+        events: Vec::new(),
+    }
+}
+
+/// A stream that keeps every byte sent and received
+/// through it, with timing, until it is saved.
+///
+/// Bytes sent or received back to back in the same
+/// direction are kept as one burst.
+#[derive(Debug)]
+pub struct Recorder<S> {
+    inner: S,
+    start: Instant,
+    events: Vec<Event>,
+}
+
+impl<S> Recorder<S> {
+    pub const fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn push(&mut self, direction: Direction, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        match self.events.last_mut() {
+            Some(last) if last.direction == direction => last.bytes.extend_from_slice(bytes),
+            _ => self.events.push(Event {
+                at: self.start.elapsed(),
+                direction,
+                bytes: bytes.to_vec(),
+            }),
+        }
+    }
+
+    /// Writes the session recorded so far to `path`, in
+    /// the format [`replay`] reads.
+    ///
+    /// # Errors
+    /// This function returns an error if `path` could not
+    /// be written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut contents = String::new();
+        for event in &self.events {
+            contents.push_str(&format!(
+                "{} {}",
+                event.at.as_micros(),
+                event.direction.marker()
+            ));
+            for byte in &event.bytes {
+                contents.push_str(&format!(" {byte:02x}"));
+            }
+            contents.push('\n');
+        }
+        fs::write(path, contents)
+    }
+}
+
+impl<S: io::Read> io::Read for Recorder<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.push(Direction::Received, &buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<S: io::Write> io::Write for Recorder<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.push(Direction::Sent, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: io::Write> Send for Recorder<S> {}
+impl<S: io::Read> Recv for Recorder<S> {}
+
+impl<S: AsRawFd> AsRawFd for Recorder<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+/// Opens a session saved by [`Recorder::save`] for
+/// replaying, see [`Replay`].
+///
+/// # Errors
+/// This function returns an error if `path` could not be
+/// read, or with `io::ErrorKind::InvalidData` if a line
+/// is malformed.
+pub fn replay<P: AsRef<Path>>(path: P) -> io::Result<Replay> {
+    let contents = fs::read_to_string(path)?;
+    let mut events = VecDeque::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let event = parse_line(line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed session line {}: {line}", number + 1),
+            )
+        })?;
+        events.push_back(event);
+    }
+    Ok(Replay { events })
+}
+
+fn parse_line(line: &str) -> Option<Event> {
+    let mut fields = line.split_ascii_whitespace();
+    let at = Duration::from_micros(fields.next()?.parse().ok()?);
+    let direction = match fields.next()? {
+        ">" => Direction::Sent,
+        "<" => Direction::Received,
+        _ => return None,
+    };
+    let bytes = fields
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect::<Option<_>>()?;
+    Some(Event {
+        at,
+        direction,
+        bytes,
+    })
+}
+
+/// A stream that plays the server's side of a recorded
+/// session.
+///
+/// Reads return the bytes the server sent, and writes are
+/// checked against the bytes the client sent when the
+/// session was recorded. Reads and writes may be split
+/// differently than when recording; only the bytes and
+/// their order must match. Timing is not reproduced.
+///
+/// A write that differs from the recording, a write while
+/// the recording expects the client to read, or a read
+/// while it expects the client to write fails with
+/// `io::ErrorKind::InvalidData`. Once the session is over,
+/// reads return end of file, as if the server closed the
+/// connection. Call [`Replay::finish`] at the end of a
+/// test to check that the client went through the whole
+/// session.
+#[derive(Debug)]
+pub struct Replay {
+    events: VecDeque<Event>,
+}
+
+fn mismatch(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Replay {
+    /// Checks that the whole session was replayed.
+    ///
+    /// # Errors
+    /// This function returns an error with
+    /// `io::ErrorKind::InvalidData` if any recorded bytes
+    /// were never read or written.
+    pub fn finish(self) -> io::Result<()> {
+        match self.events.front() {
+            None => Ok(()),
+            Some(event) => Err(mismatch(format!(
+                "session ended early, {} bytes {} at {:?} were left",
+                event.bytes.len(),
+                event.direction.marker(),
+                event.at
+            ))),
+        }
+    }
+
+    /// Takes up to `len` bytes off the front of the next
+    /// event, which must go in `direction`.
+    fn take(&mut self, direction: Direction, len: usize) -> io::Result<Vec<u8>> {
+        let Some(event) = self.events.front_mut() else {
+            return Ok(Vec::new());
+        };
+        if event.direction != direction {
+            return Err(mismatch(format!(
+                "expected the client to {} at {:?}",
+                match event.direction {
+                    Direction::Sent => "write",
+                    Direction::Received => "read",
+                },
+                event.at
+            )));
+        }
+        let n = len.min(event.bytes.len());
+        let bytes = event.bytes.drain(..n).collect();
+        if event.bytes.is_empty() {
+            self.events.pop_front();
+        }
+        Ok(bytes)
+    }
+}
+
+impl io::Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.take(Direction::Received, buf.len())?;
+        buf[..bytes.len()].copy_from_slice(&bytes);
+        Ok(bytes.len())
+    }
+}
+
+impl io::Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let at = self.events.front().map(|event| event.at);
+        let expected = self.take(Direction::Sent, buf.len())?;
+        if expected.is_empty() {
+            return Err(mismatch("client wrote after the session ended".into()));
+        }
+        let n = expected.len();
+        if buf[..n] != expected[..] {
+            return Err(mismatch(format!(
+                "client wrote {:02x?}, but {:02x?} was recorded at {:?}",
+                &buf[..n],
+                expected,
+                at.unwrap_or_default()
+            )));
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Send for Replay {}
+impl Recv for Replay {}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{net, protocol, test_support::Scripted};
+
+    /// Returns a path in the temp directory unique to this
+    /// process and `name`.
+    fn session_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("usbip-session-{}-{name}", std::process::id()))
+    }
+
+    /// Records a version probe against a server replying
+    /// with a devlist header, saving it to `path`.
+    fn record_probe(path: &Path) {
+        let reply = crate::test_support::header(protocol::OP_REP_DEVLIST, 0);
+        let mut stream = record(Scripted::new(reply));
+        assert_eq!(net::negotiate_version(&mut stream).unwrap(), net::VERSION);
+        stream.save(path).unwrap();
+    }
+
+    #[test]
+    fn recorded_session_replays() {
+        let path = session_path("replays");
+        record_probe(&path);
+        let contents = fs::read_to_string(&path).unwrap();
+        let mut replay = replay(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 2, "{contents}");
+        assert!(
+            lines[0].ends_with(" > 01 11 80 05 00 00 00 00"),
+            "{contents}"
+        );
+        assert!(
+            lines[1].ends_with(" < 01 11 00 05 00 00 00 00"),
+            "{contents}"
+        );
+
+        assert_eq!(net::negotiate_version(&mut replay).unwrap(), net::VERSION);
+        replay.finish().unwrap();
+    }
+
+    #[test]
+    fn replay_rejects_different_client_bytes() {
+        let path = session_path("differs");
+        record_probe(&path);
+        let mut replay = replay(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let err = replay
+            .send(&net::OpCommon::request(protocol::OP_REQ_IMPORT))
+            .unwrap_err();
+        assert!(
+            matches!(&*err, bincode::ErrorKind::Io(err) if err.kind() == io::ErrorKind::InvalidData),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn replay_reports_an_unfinished_session() {
+        let path = session_path("unfinished");
+        record_probe(&path);
+        let mut replay = replay(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        replay
+            .send(&net::OpCommon::request(protocol::OP_REQ_DEVLIST))
+            .unwrap();
+        let err = replay.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn replay_skips_comments_and_rejects_malformed_lines() {
+        let path = session_path("annotated");
+        fs::write(&path, "# probe\n\n0 > 01 11\n5 < 01\n").unwrap();
+        let replay = replay(&path).unwrap();
+        assert_eq!(replay.events.len(), 2);
+
+        fs::write(&path, "0 > 01\n5 ? 01\n").unwrap();
+        let err = super::replay(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("line 2"), "{err}");
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+
+use crate::{attach, detach};
+
+/// A vhci port number, checked against the number of
+/// ports the driver has when it was made.
+///
+/// Ports are plain `u8`s on the wire and in sysfs, which
+/// makes them easy to mix up with statuses, counts and
+/// indices. Taking a `Port` instead moves the range check
+/// to the one place the number is made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Port(u8);
+
+impl Port {
+    /// Checks `port` against `num_ports`, the total number
+    /// of vhci ports, without reading it from the driver.
+    ///
+    /// # Error
+    /// This function returns `detach::Error::InvalidPort`
+    /// if `port` is not below `num_ports`.
+    pub const fn new(port: u8, num_ports: usize) -> Result<Self, detach::Error> {
+        if (port as usize) < num_ports {
+            Ok(Self(port))
+        } else {
+            Err(detach::Error::InvalidPort {
+                requested: port,
+                num_ports,
+            })
+        }
+    }
+
+    /// Wraps a port number the driver reported itself, in
+    /// its status or through a successful attach, which is
+    /// in range by construction.
+    pub(crate) const fn from_driver(port: u8) -> Self {
+        Self(port)
+    }
+
+    /// Returns the port number, as written to sysfs.
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+}
+
+impl TryFrom<u8> for Port {
+    type Error = crate::Error;
+
+    /// Checks `port` against the number of ports the vhci
+    /// driver has, see [`attach::max_ports`].
+    fn try_from(port: u8) -> Result<Self, Self::Error> {
+        Ok(Self::new(port, attach::max_ports()?)?)
+    }
+}
+
+impl From<Port> for u8 {
+    fn from(port: Port) -> Self {
+        port.0
+    }
+}
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
@@ -0,0 +1,198 @@
+//! Checks that the environment can attach devices, for
+//! diagnosing a broken setup without attaching anything.
+
+use std::{
+    error::Error as StdError,
+    fmt,
+    io::{Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use usbip_core::vhci;
+
+use crate::{attach, buffer_to_string, net, state};
+
+type CheckFn = fn() -> Result<(), Box<dyn StdError>>;
+
+/// The outcome of one check.
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub result: Result<(), Box<dyn StdError>>,
+}
+
+/// The outcome of every check run by [`run`].
+#[derive(Debug)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// Returns `true` if every check passed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.result.is_ok())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.result {
+                Ok(()) => writeln!(f, "ok   {}", check.name)?,
+                Err(err) => writeln!(f, "FAIL {}: {err}", check.name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs every check, in order, even if an earlier one
+/// fails.
+pub fn run() -> Report {
+    let checks: [(&'static str, CheckFn); 3] = [
+        ("vhci driver", check_driver),
+        ("state directory", check_state_dir),
+        ("loopback handshake", check_handshake),
+    ];
+
+    Report {
+        checks: checks
+            .into_iter()
+            .map(|(name, check)| Check {
+                name,
+                result: check(),
+            })
+            .collect(),
+    }
+}
+
+/// Checks that the vhci-hcd driver is loaded and can be
+/// opened.
+///
+/// # Error
+/// This function returns an error if the driver could not
+/// be opened, usually because the `vhci-hcd` module is
+/// not loaded.
+pub fn check_driver() -> Result<(), Box<dyn StdError>> {
+    vhci::Driver::try_open()?;
+    Ok(())
+}
+
+/// Checks that state files can be written to
+/// `usbip_core::vhci::STATE_PATH`.
+///
+/// # Error
+/// This function returns an error if the directory could
+/// not be created or written to, usually because of
+/// missing permissions.
+pub fn check_state_dir() -> Result<(), Box<dyn StdError>> {
+    state::probe()?;
+    Ok(())
+}
+
+/// Bus id of the device the scripted server lists.
+const SCRIPTED_BUS_ID: &str = "1-1";
+
+/// Builds the exact bytes a server replies to a devlist
+/// request with, listing one device with one interface.
+///
+/// The reply is spelled out field by field instead of
+/// encoded by this crate, so that the check catches this
+/// crate misreading the wire format.
+fn scripted_devlist_reply() -> Vec<u8> {
+    fn padded(s: &str, len: usize) -> Vec<u8> {
+        let mut field = s.as_bytes().to_vec();
+        field.resize(len, 0);
+        field
+    }
+
+    let mut reply = Vec::new();
+    // OpCommon: version, code, status
+    reply.extend(net::VERSION.to_be_bytes());
+    reply.extend(crate::protocol::OP_REP_DEVLIST.to_be_bytes());
+    reply.extend(0u32.to_be_bytes());
+    // OpDevlistReply: ndev
+    reply.extend(1u32.to_be_bytes());
+    // UsbDevice
+    reply.extend(padded("/sys/devices/selftest/usb1/1-1", 256));
+    reply.extend(padded(SCRIPTED_BUS_ID, 32));
+    reply.extend(1u32.to_be_bytes()); // busnum
+    reply.extend(2u32.to_be_bytes()); // devnum
+    reply.extend(3u32.to_be_bytes()); // speed
+    reply.extend(0x1d6bu16.to_be_bytes()); // idVendor
+    reply.extend(0x0104u16.to_be_bytes()); // idProduct
+    reply.extend(0x0100u16.to_be_bytes()); // bcdDevice
+
+    // bDeviceClass, bDeviceSubClass, bDeviceProtocol,
+    // bConfigurationValue, bNumConfigurations, bNumInterfaces
+    reply.extend([0, 0, 0, 1, 1, 1]);
+    // UsbInterface: class, subclass, protocol, padding
+    reply.extend([0xff, 0, 0, 0]);
+    reply
+}
+
+/// How long the scripted server waits for the check to
+/// connect before giving up.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Accepts one connection on `listener`, giving up after
+/// [`ACCEPT_TIMEOUT`] so that the scripted server's thread
+/// ends even if the check never managed to connect.
+fn accept_within_timeout(listener: &TcpListener) -> std::io::Result<TcpStream> {
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + ACCEPT_TIMEOUT;
+    loop {
+        match listener.accept() {
+            Ok((conn, _)) => {
+                conn.set_nonblocking(false)?;
+                return Ok(conn);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(std::io::ErrorKind::TimedOut.into());
+                }
+                thread::sleep(attach::POLL_INTERVAL);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Checks that a devlist exchange with a scripted server
+/// on the loopback interface parses as expected.
+///
+/// This exercises the socket setup and PDU parsing
+/// without needing a real server.
+///
+/// # Error
+/// This function returns an error if the loopback
+/// connection failed, or the scripted reply was parsed
+/// differently than it was written.
+pub fn check_handshake() -> Result<(), Box<dyn StdError>> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let addr = listener.local_addr()?;
+
+    let server = thread::spawn(move || -> std::io::Result<()> {
+        let mut conn = accept_within_timeout(&listener)?;
+        let mut request = [0; 8];
+        conn.read_exact(&mut request)?;
+        conn.write_all(&scripted_devlist_reply())
+    });
+
+    let socket = net::connect(addr)?;
+    let devices =
+        attach::devlist(socket, net::ProtocolVersion::default())?.collect::<Result<Vec<_>, _>>()?;
+    server.join().map_err(|_| "scripted server panicked")??;
+
+    match &devices[..] {
+        [udev]
+            if buffer_to_string(udev.bus_id()) == SCRIPTED_BUS_ID
+                && (udev.id_vendor(), udev.id_product()) == (0x1d6b, 0x0104) =>
+        {
+            Ok(())
+        }
+        _ => Err(format!("scripted devlist was parsed as {devices:?}").into()),
+    }
+}
@@ -0,0 +1,319 @@
+//! The export side of USB/IP: accepting connections from
+//! clients and answering their requests.
+//!
+//! Which devices are exported, and what happens to an
+//! imported device's connection, is up to a [`Handler`].
+//! The same PDU types and [`net::Send`]/[`net::Recv`]
+//! traits as the client side are used, in reverse.
+
+use std::{
+    error::Error as StdError,
+    io,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use usbip_core::{net::Status, UsbDevice, UsbInterface};
+
+use crate::{
+    buffer_to_string,
+    net::{self, Recv, Send},
+    protocol,
+};
+
+pub use error::Error;
+
+mod error {
+    use std::fmt;
+
+    #[derive(Debug, Clone, Copy)]
+    pub enum Error {
+        UnknownRequest(u16),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::UnknownRequest(code) => {
+                    write!(f, "client sent an unknown request: {code:#06x}")
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+/// Answers the requests of clients.
+///
+/// Failures are reported as the `Status` sent back to
+/// the client, which then gets no reply body.
+pub trait Handler {
+    /// Lists the exported devices, each with the
+    /// interfaces of its active configuration.
+    fn devlist(&self) -> Result<Vec<(UsbDevice, Vec<UsbInterface>)>, Status>;
+
+    /// Looks up the exported device `bus_id` for a client
+    /// that wants to import it.
+    fn import(&self, bus_id: &str) -> Result<UsbDevice, Status>;
+
+    /// Takes over the connection of a client that just
+    /// imported `udev`. From here on the connection
+    /// carries USB traffic, for example once it is handed
+    /// to the `usbip-host` driver.
+    fn imported(&self, udev: &UsbDevice, socket: TcpStream);
+}
+
+/// A connection accepted from a client.
+#[derive(Debug)]
+pub struct Connection {
+    socket: TcpStream,
+    peer: SocketAddr,
+}
+
+impl Connection {
+    pub const fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Reads the client's request and answers it with
+    /// `handler`.
+    ///
+    /// Replies carry the version of the request, so that a
+    /// client speaking a legacy version (see
+    /// [`net::ProtocolVersion`]) gets replies it accepts.
+    ///
+    /// # Errors
+    /// This function fails if the request could not be
+    /// read or was not a devlist or import request, or if
+    /// the reply could not be sent.
+    pub fn serve(mut self, handler: &dyn Handler) -> Result<(), Box<dyn StdError>> {
+        let request = self.socket.recv_header()?;
+        let version = net::ProtocolVersion::new(request.version)?;
+
+        match request.code {
+            protocol::OP_REQ_DEVLIST => {
+                let devices = match handler.devlist() {
+                    Ok(devices) => devices,
+                    Err(status) => return self.reject(protocol::OP_REP_DEVLIST, status, version),
+                };
+                self.socket.send(
+                    &net::OpCommon::reply(protocol::OP_REP_DEVLIST, Status::Success)
+                        .with_version(version),
+                )?;
+                self.socket.send(&net::OpDevlistReply {
+                    ndev: u32::try_from(devices.len())?,
+                })?;
+                for (udev, interfaces) in &devices {
+                    self.socket.send(udev)?;
+                    for interface in interfaces {
+                        self.socket.send(interface)?;
+                    }
+                }
+                Ok(())
+            }
+            protocol::OP_REQ_IMPORT => {
+                let request: net::OpImportRequest = self.socket.recv()?;
+                let bus_id = buffer_to_string(&request.bus_id);
+                let udev = match handler.import(&bus_id) {
+                    Ok(udev) => udev,
+                    Err(status) => return self.reject(protocol::OP_REP_IMPORT, status, version),
+                };
+                self.socket.send(
+                    &net::OpCommon::reply(protocol::OP_REP_IMPORT, Status::Success)
+                        .with_version(version),
+                )?;
+                self.socket
+                    .send(&net::OpImportReply { udev: udev.clone() })?;
+                handler.imported(&udev, self.socket);
+                Ok(())
+            }
+            code => {
+                self.reject(protocol::OP_UNSPEC, Status::Unexpected, version)?;
+                Err(Error::UnknownRequest(code).into())
+            }
+        }
+    }
+
+    fn reject(
+        mut self,
+        code: u16,
+        status: Status,
+        version: net::ProtocolVersion,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.socket
+            .send(&net::OpCommon::reply(code, status).with_version(version))?;
+        Ok(())
+    }
+}
+
+/// Listens for clients on `addr`, yielding each
+/// connection as it is accepted.
+///
+/// Accepted connections get the same socket options as
+/// [`net::connect`]. Serve each one with
+/// [`Connection::serve`], on its own thread if requests
+/// should be answered concurrently.
+///
+/// # Error
+/// This function returns an error if `addr` could not be
+/// bound. Errors accepting a connection are yielded by
+/// the iterator instead.
+pub fn listen<A>(addr: A) -> io::Result<impl Iterator<Item = io::Result<Connection>>>
+where
+    A: ToSocketAddrs,
+{
+    let listener = TcpListener::bind(addr)?;
+    Ok(std::iter::repeat_with(move || {
+        let (socket, peer) = listener.accept()?;
+        socket.set_nodelay(true)?;
+        net::enable_keepalive(&socket)?;
+        Ok(Connection { socket, peer })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread};
+
+    use super::*;
+    use crate::{
+        attach::{self, AttachOptions},
+        test_support::DeviceBytes,
+    };
+
+    /// Exports one device, `1-1`, and reports the bus id
+    /// of every device imported.
+    struct Stub {
+        devlist: Result<(), Status>,
+        imported: mpsc::Sender<String>,
+    }
+
+    impl Handler for Stub {
+        fn devlist(&self) -> Result<Vec<(UsbDevice, Vec<UsbInterface>)>, Status> {
+            self.devlist?;
+            let interface = net::wire_options_with(net::Endian::Big)
+                .deserialize_from(&[0xff, 0, 0, 0][..])
+                .unwrap();
+            Ok(vec![(DeviceBytes::default().parse(), vec![interface])])
+        }
+
+        fn import(&self, bus_id: &str) -> Result<UsbDevice, Status> {
+            match bus_id {
+                "1-1" => Ok(DeviceBytes::default().parse()),
+                _ => Err(Status::NoDev),
+            }
+        }
+
+        fn imported(&self, udev: &UsbDevice, _socket: TcpStream) {
+            let bus_id = buffer_to_string(udev.bus_id()).into_owned();
+            self.imported.send(bus_id).unwrap();
+        }
+    }
+
+    /// Serves one connection with a [`Stub`] on a loopback
+    /// socket, returning the client's end, the outcome of
+    /// serving it and the devices imported. The stub's
+    /// devlist fails with the status in `devlist`, if any.
+    fn serve_one(
+        devlist: Result<(), Status>,
+    ) -> (
+        TcpStream,
+        thread::JoinHandle<Result<(), String>>,
+        mpsc::Receiver<String>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (imported, imports) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let (socket, peer) = listener.accept().unwrap();
+            let handler = Stub { devlist, imported };
+            Connection { socket, peer }
+                .serve(&handler)
+                .map_err(|err| err.to_string())
+        });
+        (client, server, imports)
+    }
+
+    #[test]
+    fn devlist_round_trips_through_the_client() {
+        let (client, server, _) = serve_one(Ok(()));
+        let devices: Vec<_> = attach::devlist(client, net::ProtocolVersion::default())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        server.join().unwrap().unwrap();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(buffer_to_string(devices[0].bus_id()), "1-1");
+        assert_eq!(devices[0].id_vendor(), 0x1d6b);
+    }
+
+    #[test]
+    fn failed_devlist_is_rejected_with_its_status() {
+        let (client, server, _) = serve_one(Err(Status::Failed));
+        let err = attach::devlist(client, net::ProtocolVersion::default())
+            .err()
+            .unwrap();
+        server.join().unwrap().unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(attach::Error::DevlistFailed(Status::Failed))
+        ));
+    }
+
+    #[test]
+    fn import_round_trips_through_the_client() {
+        let (mut client, server, imports) = serve_one(Ok(()));
+        let (udev, version) =
+            attach::request_import(&mut client, "1-1", &AttachOptions::default()).unwrap();
+        server.join().unwrap().unwrap();
+
+        assert_eq!(buffer_to_string(udev.bus_id()), "1-1");
+        assert_eq!(version, net::VERSION);
+        assert_eq!(imports.recv().unwrap(), "1-1");
+    }
+
+    #[test]
+    fn import_of_an_unknown_device_is_rejected() {
+        let (mut client, server, imports) = serve_one(Ok(()));
+        let err =
+            attach::request_import(&mut client, "9-9", &AttachOptions::default()).unwrap_err();
+        server.join().unwrap().unwrap();
+
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(attach::Error::ImportFailed(Status::NoDev))
+        ));
+        assert!(imports.try_recv().is_err(), "nothing was imported");
+    }
+
+    #[test]
+    fn unknown_requests_get_an_unspecified_reply() {
+        let (mut client, server, _) = serve_one(Ok(()));
+        client.send(&net::OpCommon::request(0x8042)).unwrap();
+        let reply = client.recv_header().unwrap();
+
+        assert_eq!(reply.code, protocol::OP_UNSPEC);
+        assert_eq!(reply.status, Status::Unexpected);
+        assert_eq!(reply.version, net::VERSION);
+        assert_eq!(
+            server.join().unwrap().unwrap_err(),
+            Error::UnknownRequest(0x8042).to_string()
+        );
+    }
+
+    #[test]
+    fn replies_echo_a_legacy_version() {
+        let legacy = net::ProtocolVersion::new(0x0106).unwrap();
+        let (mut client, server, _) = serve_one(Ok(()));
+        client
+            .send(&net::OpCommon::request(protocol::OP_REQ_DEVLIST).with_version(legacy))
+            .unwrap();
+        let reply = client.recv_header().unwrap();
+        server.join().unwrap().unwrap();
+
+        assert_eq!(reply.version, 0x0106);
+        assert_eq!(reply.code, protocol::OP_REP_DEVLIST);
+        assert_eq!(reply.status, Status::Success);
+    }
+}
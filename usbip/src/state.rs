@@ -0,0 +1,193 @@
+use std::{
+    fs::{self, DirBuilder},
+    io,
+    os::unix::fs::DirBuilderExt,
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+use usbip_core::vhci;
+
+use crate::sysfs;
+
+/// How the connection behind a port is written to its
+/// state file.
+///
+/// State files are read back in either format, whichever
+/// wrote them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StateFormat {
+    /// The layout written by the C `usbip` tool
+    /// (`host port busid`), so either tool can list or
+    /// detach the other's attachments. The protocol
+    /// version the server replied with is appended as a
+    /// fourth field, which the C tool ignores.
+    #[default]
+    C,
+    /// A JSON object with named fields, for tools that
+    /// would rather not parse the C layout. The C tool
+    /// cannot read these.
+    Json,
+}
+
+/// Connection details recorded for an attached port.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Record {
+    pub(crate) host: Box<str>,
+    pub(crate) tcp_port: u16,
+    pub(crate) bus_id: Box<str>,
+    /// `None` for ports attached by tools that do not
+    /// record the version.
+    pub(crate) version: Option<u16>,
+}
+
+impl Record {
+    /// Returns the contents of a state file holding this
+    /// record in `format`.
+    fn to_contents(&self, format: StateFormat) -> io::Result<String> {
+        let mut contents = match format {
+            StateFormat::C => {
+                let mut contents = format!("{} {} {}", self.host, self.tcp_port, self.bus_id);
+                if let Some(version) = self.version {
+                    contents.push_str(&format!(" {version}"));
+                }
+                contents
+            }
+            StateFormat::Json => serde_json::to_string(self)?,
+        };
+        contents.push('\n');
+        Ok(contents)
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        if s.trim_start().starts_with('{') {
+            return serde_json::from_str(s).ok();
+        }
+
+        let mut fields = s.split_whitespace();
+        let host = fields.next()?.into();
+        let tcp_port = fields.next()?.parse().ok()?;
+        let bus_id = fields.next()?.into();
+        let version = fields.next().and_then(|version| version.parse().ok());
+
+        Some(Record {
+            host,
+            tcp_port,
+            bus_id,
+            version,
+        })
+    }
+}
+
+/// Returns the path of the state file for `port`.
+pub(crate) fn path(port: u8) -> PathBuf {
+    PathBuf::from(format!("{}/port{}", vhci::STATE_PATH, port))
+}
+
+fn create_dir() -> io::Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(vhci::STATE_PATH)
+}
+
+/// Records `record` as the connection behind `port` in
+/// `format`, creating the state directory if needed.
+pub(crate) fn write(port: u8, record: &Record, format: StateFormat) -> io::Result<()> {
+    create_dir()?;
+    fs::write(path(port), record.to_contents(format)?)
+}
+
+/// Removes the state file for `port`, leaving the state
+/// directory in place. Nothing recorded is not an error.
+pub(crate) fn remove(port: u8) -> io::Result<()> {
+    match sysfs::retry_interrupted(|| fs::remove_file(path(port))) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Reads the connection recorded for `port`, returning
+/// `None` if nothing was recorded.
+///
+/// # Error
+/// This function returns an error if the state file
+/// could not be read or is malformed.
+pub(crate) fn read(port: u8) -> io::Result<Option<Record>> {
+    let contents = match fs::read_to_string(path(port)) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    Record::parse(&contents).map(Some).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("malformed state file for port {port}"),
+        )
+    })
+}
+
+/// Lists the ports that have a state file, in no
+/// particular order. A missing state directory means
+/// nothing is recorded.
+pub(crate) fn recorded_ports() -> io::Result<Vec<u8>> {
+    let entries = match fs::read_dir(vhci::STATE_PATH) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut ports = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name();
+        if let Some(port) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("port"))
+            .and_then(|port| port.parse().ok())
+        {
+            ports.push(port);
+        }
+    }
+    Ok(ports)
+}
+
+/// Checks that state files can be written, by creating
+/// the state directory if needed and writing and removing
+/// a probe file in it.
+pub(crate) fn probe() -> io::Result<()> {
+    create_dir()?;
+    let probe = PathBuf::from(format!("{}/.probe", vhci::STATE_PATH));
+    fs::write(&probe, "")?;
+    fs::remove_file(probe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(version: Option<u16>) -> Record {
+        Record {
+            host: "10.0.0.2".into(),
+            tcp_port: 3240,
+            bus_id: "1-1.2".into(),
+            version,
+        }
+    }
+
+    #[test]
+    fn records_round_trip_in_every_format() {
+        for format in [StateFormat::C, StateFormat::Json] {
+            for record in [record(Some(0x0111)), record(None)] {
+                let contents = record.to_contents(format).unwrap();
+                assert_eq!(Record::parse(&contents), Some(record), "{format:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn c_format_matches_the_c_tool() {
+        let contents = record(None).to_contents(StateFormat::C).unwrap();
+        assert_eq!(contents, "10.0.0.2 3240 1-1.2\n");
+    }
+}
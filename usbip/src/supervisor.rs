@@ -0,0 +1,162 @@
+//! Keeps a set of devices attached, re-attaching any
+//! that drop.
+
+use std::{
+    error::Error as StdError,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    attach::{self, AttachedDevice, AttachmentInfo},
+    net,
+};
+
+/// A change the supervisor saw or made while reconciling.
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// A desired device was found detached.
+    Dropped { host: &'a str, bus_id: &'a str },
+    /// A desired device was attached.
+    Attached(&'a AttachedDevice),
+    /// Attaching a desired device failed. It is retried
+    /// on the next pass.
+    AttachFailed {
+        host: &'a str,
+        bus_id: &'a str,
+        error: &'a dyn StdError,
+    },
+}
+
+struct Desired {
+    host: String,
+    bus_id: String,
+    endpoint: net::Endpoint,
+    attached: bool,
+}
+
+impl Desired {
+    fn is(&self, info: &AttachmentInfo) -> bool {
+        info.is_to(&self.endpoint, &self.bus_id)
+    }
+}
+
+/// Reconciles the vhci state against a desired set of
+/// `(host, bus_id)` attachments, re-attaching any that
+/// dropped.
+///
+/// The kernel owns the connections of attached devices,
+/// so liveness is judged from the kernel's view: a device
+/// whose connection broke has its port freed, and is
+/// attached again on the next pass.
+pub struct Supervisor {
+    desired: Vec<Desired>,
+    interval: Duration,
+    on_change: Box<dyn FnMut(&Event<'_>)>,
+}
+
+impl Supervisor {
+    /// Creates a supervisor for `desired`, reconciling
+    /// every `interval` once running.
+    ///
+    /// # Error
+    /// This function returns an error if one of the hosts
+    /// could not be parsed.
+    pub fn new(desired: &[(String, String)], interval: Duration) -> Result<Self, net::Error> {
+        let desired = desired
+            .iter()
+            .map(|(host, bus_id)| {
+                Ok(Desired {
+                    host: host.clone(),
+                    bus_id: bus_id.clone(),
+                    endpoint: net::parse_endpoint(host)?,
+                    attached: false,
+                })
+            })
+            .collect::<Result<_, net::Error>>()?;
+
+        Ok(Self {
+            desired,
+            interval,
+            on_change: Box::new(|_| {}),
+        })
+    }
+
+    /// Calls `f` for every change seen or made while
+    /// reconciling.
+    pub fn on_change(mut self, f: impl FnMut(&Event<'_>) + 'static) -> Self {
+        self.on_change = Box::new(f);
+        self
+    }
+
+    /// Runs one reconciliation pass, attaching every
+    /// desired device that is not attached.
+    ///
+    /// Failing to attach one device does not stop the
+    /// others; it is reported through the callback.
+    ///
+    /// # Errors
+    /// This function fails if the current attachments
+    /// could not be listed (see [`attach::attachments`]).
+    pub fn reconcile(&mut self) -> Result<(), Box<dyn StdError>> {
+        let attachments = attach::attachments()?;
+
+        for desired in &mut self.desired {
+            let present = attachments.iter().any(|info| desired.is(info));
+            if present {
+                desired.attached = true;
+                continue;
+            }
+
+            if desired.attached {
+                desired.attached = false;
+                (self.on_change)(&Event::Dropped {
+                    host: &desired.host,
+                    bus_id: &desired.bus_id,
+                });
+            }
+
+            match attach::attach_device(&desired.host, &desired.bus_id) {
+                Ok(device) => {
+                    desired.attached = true;
+                    (self.on_change)(&Event::Attached(&device));
+                }
+                Err(error) => (self.on_change)(&Event::AttachFailed {
+                    host: &desired.host,
+                    bus_id: &desired.bus_id,
+                    error: &*error,
+                }),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reconciles every interval until `stop` is set.
+    ///
+    /// # Errors
+    /// This function stops at the first failed pass, see
+    /// [`Supervisor::reconcile`].
+    pub fn run(&mut self, stop: &AtomicBool) -> Result<(), Box<dyn StdError>> {
+        while !stop.load(Ordering::Relaxed) {
+            let next = Instant::now() + self.interval;
+            self.reconcile()?;
+
+            // Sleep in short steps so `stop` is noticed
+            // without waiting out a long interval.
+            while !stop.load(Ordering::Relaxed) {
+                let now = Instant::now();
+                if now >= next {
+                    break;
+                }
+                thread::sleep((next - now).min(STOP_POLL));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How often `Supervisor::run` checks for a stop request
+/// while waiting for the next pass.
+const STOP_POLL: Duration = Duration::from_millis(100);
@@ -0,0 +1,258 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::fd::RawFd,
+    path::{Path, PathBuf},
+};
+
+/// sysfs directory of the primary vhci-hcd controller,
+/// which carries the attributes for every controller.
+pub(crate) const VHCI_PATH: &str = "/sys/devices/platform/vhci_hcd.0";
+
+/// Returns `true` if the vhci-hcd controller is present
+/// in sysfs.
+pub(crate) fn vhci_present() -> bool {
+    Path::new(VHCI_PATH).is_dir()
+}
+
+fn attr(name: &str) -> PathBuf {
+    PathBuf::from(format!("{VHCI_PATH}/{name}"))
+}
+
+/// How many times an interrupted call is retried before
+/// the interruption is reported as an error.
+const MAX_INTERRUPTED_RETRIES: usize = 8;
+
+/// Runs `f`, retrying it a bounded number of times while
+/// it is interrupted by a signal (`EINTR`).
+pub(crate) fn retry_interrupted<T>(mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut retries = 0;
+    loop {
+        match f() {
+            Err(err)
+                if err.kind() == io::ErrorKind::Interrupted
+                    && retries < MAX_INTERRUPTED_RETRIES =>
+            {
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Writes `value` to the sysfs attribute at `path` in a
+/// single `write`, retrying if interrupted.
+///
+/// sysfs stores take the whole value at once, so a short
+/// write means the value was not taken.
+fn write_path(path: &Path, value: &str) -> io::Result<()> {
+    retry_interrupted(|| {
+        let mut attr = OpenOptions::new().write(true).open(path)?;
+        if attr.write(value.as_bytes())? == value.len() {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::WriteZero.into())
+        }
+    })
+}
+
+/// Writes `value` to the vhci attribute `name`, see
+/// [`write_path`].
+fn write_attr(name: &str, value: &str) -> io::Result<()> {
+    write_path(&attr(name), value)
+}
+
+/// Asks the vhci driver to detach the device on `port`.
+pub(crate) fn detach(port: u8) -> io::Result<()> {
+    write_attr("detach", &port.to_string())
+}
+
+/// Writes the attach request to the vhci driver, handing
+/// `sockfd` to the kernel for the data plane.
+pub(crate) fn attach(port: u8, sockfd: RawFd, devid: u32, speed: &str) -> io::Result<()> {
+    let attr = OpenOptions::new().write(true).open(attr("attach"))?;
+    write_attach(attr, port, sockfd, devid, speed)
+}
+
+/// Writes the attach request to `attr`, the opened
+/// `attach` attribute. The request must go out in one
+/// write, as the driver parses each write on its own.
+pub(crate) fn write_attach(
+    mut attr: impl Write,
+    port: u8,
+    sockfd: RawFd,
+    devid: u32,
+    speed: &str,
+) -> io::Result<()> {
+    attr.write_all(format!("{port} {sockfd} {devid} {speed}").as_bytes())
+}
+
+/// sysfs directory listing every USB device known to the
+/// local kernel, by bus id.
+const USB_DEVICES_PATH: &str = "/sys/bus/usb/devices";
+
+/// One port's line in a vhci status attribute.
+#[derive(Debug, Clone)]
+pub(crate) struct PortStatus {
+    /// The controller whose status attribute lists the
+    /// port, as in `vhci_hcd.N`.
+    pub(crate) controller: usize,
+    pub(crate) port: u8,
+    /// The usbip speed the device was attached with.
+    pub(crate) speed: u32,
+    /// The remote devid the device was attached with.
+    pub(crate) devid: u32,
+    /// The local bus id the kernel gave the device, or
+    /// `None` if no device has been enumerated on the port.
+    pub(crate) local_bus_id: Option<String>,
+}
+
+/// Reads the status line of every port.
+///
+/// Each controller lists its ports in its own status
+/// attribute (`status`, `status.1`, ...), one line per
+/// port after a header, ending with the local bus id.
+pub(crate) fn port_statuses() -> io::Result<Vec<PortStatus>> {
+    let mut statuses = Vec::new();
+    let mut controller = 0;
+    loop {
+        let name = match controller {
+            0 => "status".to_owned(),
+            n => format!("status.{n}"),
+        };
+        let status = match fs::read_to_string(attr(&name)) {
+            Ok(status) => status,
+            Err(err) if err.kind() == io::ErrorKind::NotFound && controller > 0 => {
+                return Ok(statuses)
+            }
+            Err(err) => return Err(err),
+        };
+
+        for line in status.lines().skip(1) {
+            // hub port sta spd dev sockfd local_busid
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [_, port, _, speed, devid, _, bus_id] = fields[..] else {
+                continue;
+            };
+            let Ok(port) = port.parse() else {
+                continue;
+            };
+            statuses.push(PortStatus {
+                controller,
+                port,
+                speed: speed.parse().unwrap_or(0),
+                devid: u32::from_str_radix(devid, 16).unwrap_or(0),
+                local_bus_id: (bus_id != "0-0").then(|| bus_id.to_owned()),
+            });
+        }
+        controller += 1;
+    }
+}
+
+/// Reads the status line of `port`, returning `None` if
+/// no controller lists it.
+pub(crate) fn port_status(port: u8) -> io::Result<Option<PortStatus>> {
+    Ok(port_statuses()?
+        .into_iter()
+        .find(|status| status.port == port))
+}
+
+/// Looks up the local bus id the kernel gave the device
+/// attached to `port`, returning `None` if no device has
+/// been enumerated on it.
+pub(crate) fn local_bus_id(port: u8) -> io::Result<Option<String>> {
+    Ok(port_status(port)?.and_then(|status| status.local_bus_id))
+}
+
+/// Resolves the sysfs directory of the local USB device
+/// `bus_id` through its link in the USB bus directory.
+pub(crate) fn usb_device_path(bus_id: &str) -> io::Result<PathBuf> {
+    fs::canonicalize(format!("{USB_DEVICES_PATH}/{bus_id}"))
+}
+
+/// Finds the vhci port whose local device is at `path`
+/// or contains it, such as one of its interfaces.
+pub(crate) fn port_for_path(path: &Path) -> io::Result<Option<u8>> {
+    let path = fs::canonicalize(path)?;
+    for status in port_statuses()? {
+        let Some(bus_id) = &status.local_bus_id else {
+            continue;
+        };
+        if path.starts_with(usb_device_path(bus_id)?) {
+            return Ok(Some(status.port));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the attribute `name` of the local USB device
+/// `bus_id`, returning `None` if the device does not
+/// have it.
+pub(crate) fn usb_device_attr(bus_id: &str, name: &str) -> io::Result<Option<String>> {
+    match fs::read_to_string(format!("{USB_DEVICES_PATH}/{bus_id}/{name}")) {
+        Ok(value) => Ok(Some(value.trim_end().to_owned())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Reads the binary attribute `name` of the local USB
+/// device `bus_id`, returning `None` if the device does
+/// not have it.
+pub(crate) fn usb_device_bytes(bus_id: &str, name: &str) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(format!("{USB_DEVICES_PATH}/{bus_id}/{name}")) {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Finds the bus id of the local USB device with the
+/// given bus and device numbers, as `lsusb` prints them.
+pub(crate) fn find_usb_device(busnum: u32, devnum: u32) -> io::Result<Option<String>> {
+    for entry in fs::read_dir(USB_DEVICES_PATH)? {
+        let bus_id = entry?.file_name().to_string_lossy().into_owned();
+        // Interfaces (`1-1:1.0`) and root hubs (`usb1`)
+        // are listed alongside the devices.
+        if bus_id.contains(':') || bus_id.starts_with("usb") {
+            continue;
+        }
+
+        let number = |name| -> io::Result<Option<u32>> {
+            Ok(usb_device_attr(&bus_id, name)?.and_then(|value| value.parse().ok()))
+        };
+        if number("busnum")? == Some(busnum) && number("devnum")? == Some(devnum) {
+            return Ok(Some(bus_id));
+        }
+    }
+    Ok(None)
+}
+
+/// Lists the interfaces of the local USB device `bus_id`,
+/// such as `3-1:1.0`.
+pub(crate) fn usb_interfaces(bus_id: &str) -> io::Result<Vec<String>> {
+    let prefix = format!("{bus_id}:");
+    let mut interfaces = Vec::new();
+    for entry in fs::read_dir(USB_DEVICES_PATH)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with(&prefix) {
+            interfaces.push(name);
+        }
+    }
+    Ok(interfaces)
+}
+
+/// Sets the `driver_override` of the local USB interface
+/// `interface` and has the kernel bind it again: unbinding
+/// its current driver, then probing for one that matches
+/// the override. `"\n"` clears the override.
+pub(crate) fn rebind_interface(interface: &str, driver_override: &str) -> io::Result<()> {
+    let dir = PathBuf::from(format!("{USB_DEVICES_PATH}/{interface}"));
+    write_path(&dir.join("driver_override"), driver_override)?;
+
+    let unbind = dir.join("driver/unbind");
+    if unbind.exists() {
+        write_path(&unbind, interface)?;
+    }
+    write_path(Path::new("/sys/bus/usb/drivers_probe"), interface)
+}
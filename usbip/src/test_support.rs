@@ -0,0 +1,143 @@
+//! Fixtures shared by the unit tests.
+
+use std::io;
+
+use usbip_core::UsbDevice;
+
+use crate::net;
+
+/// Returns the bytes of an `OpCommon` reply header.
+pub(crate) fn header(code: u16, status: u32) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend(net::VERSION.to_be_bytes());
+    bytes.extend(code.to_be_bytes());
+    bytes.extend(status.to_be_bytes());
+    bytes
+}
+
+/// Returns the bytes of a successful devlist reply
+/// listing `devices`, each followed by its interface
+/// records.
+pub(crate) fn devlist_reply(devices: &[DeviceBytes<'_>]) -> Vec<u8> {
+    let mut bytes = header(crate::protocol::OP_REP_DEVLIST, 0);
+    bytes.extend(u32::try_from(devices.len()).unwrap().to_be_bytes());
+    for device in devices {
+        bytes.extend(device.encode());
+        for _ in 0..device.num_interfaces {
+            // class, subclass, protocol, padding
+            bytes.extend([0xff, 0, 0, 0]);
+        }
+    }
+    bytes
+}
+
+/// An in-memory connection to a scripted server, which
+/// replies with fixed bytes whatever it is sent and then
+/// closes.
+///
+/// Everything written to it is kept in `sent`.
+#[derive(Debug)]
+pub(crate) struct Scripted {
+    reply: io::Cursor<Vec<u8>>,
+    pub(crate) sent: Vec<u8>,
+}
+
+impl Scripted {
+    pub(crate) fn new(reply: Vec<u8>) -> Self {
+        Self {
+            reply: io::Cursor::new(reply),
+            sent: Vec::new(),
+        }
+    }
+
+    /// Returns how many bytes of the reply were not read.
+    pub(crate) fn unread(&self) -> usize {
+        let read = usize::try_from(self.reply.position()).unwrap();
+        self.reply.get_ref().len() - read
+    }
+}
+
+impl io::Read for Scripted {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reply.read(buf)
+    }
+}
+
+impl io::Write for Scripted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl net::Send for Scripted {}
+impl net::Recv for Scripted {}
+
+/// The fields of a device record that tests vary, spelled
+/// out as the bytes a server would send.
+///
+/// The bytes are laid out by hand instead of encoded by
+/// this crate, so tests catch this crate misreading the
+/// wire format.
+#[derive(Debug, Clone)]
+pub(crate) struct DeviceBytes<'a> {
+    pub(crate) path: &'a str,
+    pub(crate) bus_id: &'a str,
+    pub(crate) busnum: u32,
+    pub(crate) devnum: u32,
+    pub(crate) speed: u32,
+    pub(crate) vendor: u16,
+    pub(crate) product: u16,
+    pub(crate) num_interfaces: u8,
+}
+
+impl Default for DeviceBytes<'_> {
+    fn default() -> Self {
+        Self {
+            path: "/sys/devices/pci0000:00/0000:00:14.0/usb1/1-1",
+            bus_id: "1-1",
+            busnum: 1,
+            devnum: 2,
+            speed: 3,
+            vendor: 0x1d6b,
+            product: 0x0104,
+            num_interfaces: 1,
+        }
+    }
+}
+
+impl DeviceBytes<'_> {
+    /// Returns the device record alone, without the
+    /// interface records a devlist reply puts after it.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        fn padded(s: &str, len: usize) -> Vec<u8> {
+            let mut field = s.as_bytes().to_vec();
+            field.resize(len, 0);
+            field
+        }
+
+        let mut bytes = Vec::new();
+        bytes.extend(padded(self.path, 256));
+        bytes.extend(padded(self.bus_id, 32));
+        bytes.extend(self.busnum.to_be_bytes());
+        bytes.extend(self.devnum.to_be_bytes());
+        bytes.extend(self.speed.to_be_bytes());
+        bytes.extend(self.vendor.to_be_bytes());
+        bytes.extend(self.product.to_be_bytes());
+        bytes.extend(0x0100u16.to_be_bytes()); // bcdDevice
+
+        // bDeviceClass, bDeviceSubClass, bDeviceProtocol,
+        // bConfigurationValue, bNumConfigurations
+        bytes.extend([0, 0, 0, 1, 1]);
+        bytes.push(self.num_interfaces);
+        bytes
+    }
+
+    /// Decodes the record, as a client would receive it.
+    pub(crate) fn parse(&self) -> UsbDevice {
+        net::parse_device(&self.encode()).unwrap()
+    }
+}